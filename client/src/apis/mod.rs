@@ -1,15 +1,21 @@
 pub mod types;
 
 use crate::components::chatbot::{ChatMessage, Sender};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use js_sys::{Reflect, Uint8Array};
 use lazy_static::lazy_static;
 use reqwasm::http::Request;
 use std::env::var;
 use types::{
-    ChatRequest, ChatResponse, CreateAttachmentRequest, CreateAttachmentResponse,
-    CreateNoteRequest, GetNoteResponse, ListCategoriesResponse, ListNotesResponse, Note,
-    SearchRequest, SearchResponse,
+    AttachmentInfo, ChatHistoryMessage, ChatRequest, ChatRole, ChatToken, Citation,
+    CreateAttachmentRequest, CreateAttachmentResponse, CreateNoteRequest, ErrorResponse,
+    GetNoteResponse, ListCategoriesResponse, ListNotesResponse, Note, SearchMode, SearchRequest,
+    SearchResponse, SuggestCategoryRequest, SuggestCategoryResponse, SummaryRequest,
+    SummaryResponse,
 };
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::ReadableStreamDefaultReader;
 
 lazy_static! {
     pub static ref SERVER_BASE_URL: String =
@@ -20,7 +26,9 @@ pub static API_SUBPATH: &str = "api/";
 pub static CATEGORIES_API: &str = "categories/";
 pub static NOTES_API: &str = "notes/";
 pub static SEARCH_API: &str = "search/";
+pub const SEARCH_PAGE_SIZE: usize = 20;
 pub static CHAT_API: &str = "chat/";
+pub static STREAM_API: &str = "stream/";
 pub static ATTACHMENTS_API: &str = "attachments/";
 
 pub async fn categories() -> Result<ListCategoriesResponse> {
@@ -35,6 +43,24 @@ pub async fn categories() -> Result<ListCategoriesResponse> {
     Ok(categories)
 }
 
+/// Asks the server to suggest categories for a draft note, best first.
+pub async fn suggest_categories(title: String, body: String) -> Result<SuggestCategoryResponse> {
+    let url = format!("{}{API_SUBPATH}{CATEGORIES_API}suggest", *SERVER_BASE_URL);
+    let suggestions = Request::post(&url)
+        .credentials(web_sys::RequestCredentials::Include)
+        .body(serde_json::to_string(&SuggestCategoryRequest {
+            title,
+            body,
+        })?)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?
+        .json::<SuggestCategoryResponse>()
+        .await?;
+
+    Ok(suggestions)
+}
+
 pub async fn notes(category_filter: Option<String>) -> Result<ListNotesResponse> {
     let mut url = format!("{}{API_SUBPATH}{NOTES_API}", *SERVER_BASE_URL);
     if let Some(cat_filter) = category_filter {
@@ -87,6 +113,40 @@ pub async fn rekey_note(note_id: usize) -> Result<()> {
     Ok(())
 }
 
+/// Has the chat model summarize the note and saves the summary with it.
+pub async fn summarize_note(note_id: usize) -> Result<SummaryResponse> {
+    let url = format!(
+        "{}{API_SUBPATH}{NOTES_API}{note_id}/summary",
+        *SERVER_BASE_URL
+    );
+    let summary = Request::post(&url)
+        .credentials(web_sys::RequestCredentials::Include)
+        .body(serde_json::to_string(&SummaryRequest { store: true })?)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?
+        .json::<SummaryResponse>()
+        .await?;
+
+    Ok(summary)
+}
+
+/// Notes similar to the given one, most similar first.
+pub async fn related_notes(note_id: usize) -> Result<SearchResponse> {
+    let url = format!(
+        "{}{API_SUBPATH}{NOTES_API}{note_id}/related",
+        *SERVER_BASE_URL
+    );
+    let notes = Request::get(&url)
+        .credentials(web_sys::RequestCredentials::Include)
+        .send()
+        .await?
+        .json::<SearchResponse>()
+        .await?;
+
+    Ok(notes)
+}
+
 pub async fn note(note_id: usize) -> Result<GetNoteResponse> {
     let url = format!("{}{API_SUBPATH}{NOTES_API}{note_id}", *SERVER_BASE_URL);
     let note = Request::get(&url)
@@ -124,13 +184,18 @@ pub async fn update_note(
     Ok(note)
 }
 
-pub async fn search(query: String) -> Result<SearchResponse> {
+/// Gets the page of search results starting at `from`.
+pub async fn search(query: String, from: usize) -> Result<SearchResponse> {
     let url = format!("{}{API_SUBPATH}{NOTES_API}{SEARCH_API}", *SERVER_BASE_URL);
     let notes = Request::post(&url)
         .credentials(web_sys::RequestCredentials::Include)
         .body(serde_json::to_string(&SearchRequest {
             title: Some(query.clone()),
-            body: Some(query),
+            body: Some(query.clone()),
+            attachment_text: Some(query),
+            mode: SearchMode::Hybrid,
+            from,
+            size: SEARCH_PAGE_SIZE,
         })?)
         .header("Content-Type", "application/json")
         .send()
@@ -141,23 +206,46 @@ pub async fn search(query: String) -> Result<SearchResponse> {
     Ok(notes)
 }
 
-pub async fn create_attachment(filename: String) -> Result<CreateAttachmentResponse> {
+pub async fn create_attachment(filename: String, size: usize) -> Result<CreateAttachmentResponse> {
     let url = format!("{}{API_SUBPATH}{ATTACHMENTS_API}", *SERVER_BASE_URL);
-    Ok(Request::post(&url)
+    let response = Request::post(&url)
         .credentials(web_sys::RequestCredentials::Include)
         .body(serde_json::to_string(&CreateAttachmentRequest {
             filename,
+            size,
         })?)
         .header("Content-Type", "application/json")
         .send()
-        .await?
-        .json::<CreateAttachmentResponse>()
-        .await?)
+        .await?;
+    if !response.ok() {
+        return Err(anyhow!(response.json::<ErrorResponse>().await?.error));
+    }
+    Ok(response.json::<CreateAttachmentResponse>().await?)
 }
 
-pub async fn write_to_url(url: String, data: Vec<u8>) -> Result<()> {
-    Request::put(&url)
-        .header("Content-Type", "application/octet-stream")
+pub async fn confirm_attachment(attachment_id: usize) -> Result<AttachmentInfo> {
+    let url = format!(
+        "{}{API_SUBPATH}{ATTACHMENTS_API}{attachment_id}/confirm",
+        *SERVER_BASE_URL
+    );
+    let response = Request::post(&url)
+        .credentials(web_sys::RequestCredentials::Include)
+        .send()
+        .await?;
+    if !response.ok() {
+        return Err(anyhow!(response.json::<ErrorResponse>().await?.error));
+    }
+    Ok(response.json::<AttachmentInfo>().await?)
+}
+
+pub async fn write_to_url(url: String, data: Vec<u8>, content_type: String) -> Result<()> {
+    let mut request = Request::put(&url);
+    // when the server encrypts attachments itself, uploads go to the server and need the organization cookie
+    if url.starts_with(SERVER_BASE_URL.as_str()) {
+        request = request.credentials(web_sys::RequestCredentials::Include);
+    }
+    request
+        .header("Content-Type", &content_type)
         .body(<&[u8] as Into<Box<[u8]>>>::into(&data[..]))
         .send()
         .await?;
@@ -165,33 +253,106 @@ pub async fn write_to_url(url: String, data: Vec<u8>) -> Result<()> {
     Ok(())
 }
 
-pub async fn query_chatbot(mut chat_messages: Vec<ChatMessage>) -> Result<ChatResponse> {
-    let url = format!("{}{API_SUBPATH}{CHAT_API}", *SERVER_BASE_URL);
+fn chat_request(mut chat_messages: Vec<ChatMessage>) -> ChatRequest {
     let question = chat_messages.remove(chat_messages.len() - 1);
-    let notes = Request::post(&url)
+    let mut referenced_note_ids = vec![];
+    for note_id in chat_messages
+        .iter()
+        .flat_map(|message| message.citations.iter().map(|citation| citation.note_id))
+    {
+        if !referenced_note_ids.contains(&note_id) {
+            referenced_note_ids.push(note_id);
+        }
+    }
+    // the greeting isn't part of the conversation as far as the model is concerned
+    let history = chat_messages
+        .into_iter()
+        .skip_while(|message| message.sender == Sender::Bot)
+        .map(
+            |ChatMessage {
+                 sender, message, ..
+             }| ChatHistoryMessage {
+                role: match sender {
+                    Sender::Bot => ChatRole::Assistant,
+                    Sender::Human => ChatRole::User,
+                },
+                content: message,
+            },
+        )
+        .collect();
+    ChatRequest {
+        question: question.message,
+        history,
+        referenced_note_ids,
+    }
+}
+
+/// Splits a Server-Sent Event into its event name and data.
+fn parse_sse_event(event: &str) -> (String, String) {
+    let mut name = String::new();
+    let mut data = vec![];
+    for line in event.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            name = value.trim().to_string();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    (name, data.join("\n"))
+}
+
+fn js_error(e: JsValue) -> anyhow::Error {
+    anyhow!("{e:?}")
+}
+
+/// Asks the chatbot the last message in `chat_messages`, calling `on_token` with each piece of the answer as it
+/// arrives. Resolves to the notes the answer cites once it's complete.
+pub async fn stream_chatbot(
+    chat_messages: Vec<ChatMessage>,
+    mut on_token: impl FnMut(String),
+) -> Result<Vec<Citation>> {
+    let url = format!("{}{API_SUBPATH}{CHAT_API}{STREAM_API}", *SERVER_BASE_URL);
+    let response = Request::post(&url)
         .credentials(web_sys::RequestCredentials::Include)
-        .body(serde_json::to_string(&ChatRequest {
-            question: format!("[INST]{}[/INST]", question.message),
-            context: chat_messages
-                .iter()
-                .map(
-                    |ChatMessage {
-                         sender, message, ..
-                     }| {
-                        let (b_statement, e_statement) = match sender {
-                            Sender::Bot => ("", ""),
-                            Sender::Human => ("[INST]", "[/INST]"),
-                        };
-                        format!("{b_statement}{message}{e_statement}")
-                    },
-                )
-                .fold(String::new(), |x, y| format!("{x}\n{y}")),
-        })?)
+        .body(serde_json::to_string(&chat_request(chat_messages))?)
         .header("Content-Type", "application/json")
         .send()
-        .await?
-        .json::<ChatResponse>()
         .await?;
+    if !response.ok() {
+        return Err(anyhow!("Chat failed with status {}", response.status()));
+    }
+    let reader = response
+        .body()
+        .ok_or_else(|| anyhow!("Chat response had no body"))?
+        .get_reader()
+        .unchecked_into::<ReadableStreamDefaultReader>();
 
-    Ok(notes)
+    let mut buffer = vec![];
+    loop {
+        let chunk = JsFuture::from(reader.read()).await.map_err(js_error)?;
+        if Reflect::get(&chunk, &"done".into())
+            .map_err(js_error)?
+            .is_truthy()
+        {
+            return Err(anyhow!(
+                "Chat response ended before the answer was complete"
+            ));
+        }
+        let value = Reflect::get(&chunk, &"value".into()).map_err(js_error)?;
+        buffer.extend(Uint8Array::new(&value).to_vec());
+
+        // events are separated by a blank line, anything after the last one is still arriving
+        while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+            let event = buffer.drain(..end + 2).collect::<Vec<_>>();
+            match parse_sse_event(&String::from_utf8_lossy(&event)) {
+                (name, data) if name == "token" => {
+                    on_token(serde_json::from_str::<ChatToken>(&data)?.content)
+                }
+                (name, data) if name == "citations" => return Ok(serde_json::from_str(&data)?),
+                (name, data) if name == "error" => return Err(anyhow!(data)),
+                // keep-alive comments
+                _ => {}
+            }
+        }
+    }
 }
@@ -5,6 +5,34 @@ pub struct ListCategoriesResponse {
     pub result: Vec<String>,
 }
 
+#[derive(Serialize)]
+pub struct SuggestCategoryRequest {
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SuggestCategoryResponse {
+    pub result: Vec<CategorySuggestion>,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct CategorySuggestion {
+    pub category: String,
+    pub confidence: f32,
+    pub existing: bool,
+}
+
+#[derive(Serialize)]
+pub struct SummaryRequest {
+    pub store: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SummaryResponse {
+    pub summary: String,
+}
+
 #[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct AttachmentInfo {
     pub id: usize,
@@ -18,6 +46,7 @@ pub struct Note {
     pub category: Option<String>,
     pub title: String,
     pub body: String,
+    pub summary: Option<String>,
     pub created: String,
     pub updated: String,
     pub attachments: Vec<AttachmentInfo>,
@@ -41,6 +70,7 @@ pub struct CreateNoteRequest {
 #[derive(Serialize, Clone)]
 pub struct CreateAttachmentRequest {
     pub filename: String,
+    pub size: usize,
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,29 +78,77 @@ pub struct CreateAttachmentResponse {
     pub id: usize,
     pub note_id: Option<usize>,
     pub filename: String,
+    pub content_type: String,
     pub presigned_put_url: String,
     pub url: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
 #[derive(Serialize)]
 pub struct SearchRequest {
     pub title: Option<String>,
     pub body: Option<String>,
+    pub attachment_text: Option<String>,
+    pub mode: SearchMode,
+    pub from: usize,
+    pub size: usize,
+}
+
+/// The search box always runs a hybrid search so notes that match the meaning of the query show up even if they
+/// don't contain any of its words.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    Hybrid,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct SearchResponse {
-    pub result: Vec<Note>,
+    pub result: Vec<ScoredNote>,
+    pub total: usize,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct ScoredNote {
+    #[serde(flatten)]
+    pub note: Note,
+    pub score: f32,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    User,
+    Assistant,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChatHistoryMessage {
+    pub role: ChatRole,
+    pub content: String,
 }
 
 #[derive(Serialize, Debug)]
 pub struct ChatRequest {
     pub question: String,
-    pub context: String,
+    pub history: Vec<ChatHistoryMessage>,
+    pub referenced_note_ids: Vec<usize>,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct ChatResponse {
-    pub response: String,
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct Citation {
+    pub source: usize,
     pub note_id: usize,
+    pub title: String,
+    pub snippet: String,
+    pub score: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatToken {
+    pub content: String,
 }
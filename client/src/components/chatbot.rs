@@ -1,5 +1,5 @@
 use crate::{
-    apis,
+    apis::{self, types::Citation},
     pages::index::{CurrentNote, CurrentOrg},
 };
 use std::fmt::Display;
@@ -21,11 +21,11 @@ impl Display for Sender {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ChatMessage {
     pub sender: Sender,
     pub message: String,
-    pub referenced_note_id: Option<usize>,
+    pub citations: Vec<Citation>,
 }
 
 #[component]
@@ -42,7 +42,7 @@ pub fn Chatbot() -> View {
         message: "I'm here to help you get more out of your notes, what would you like to know?"
             .to_string(),
         sender: Sender::Bot,
-        referenced_note_id: None,
+        citations: vec![],
     }]);
 
     let submit_question = move || {
@@ -51,20 +51,40 @@ pub fn Chatbot() -> View {
             vs.push(ChatMessage {
                 sender: Sender::Human,
                 message: query,
-                referenced_note_id: None,
+                citations: vec![],
             })
         });
         current_query.set(String::new());
         loading.set(true);
         spawn_local_scoped(async move {
-            match apis::query_chatbot(chat_messages.get_clone()).await {
-                Ok(response_message) => chat_messages.update(|vs| {
-                    vs.push(ChatMessage {
-                        sender: Sender::Bot,
-                        message: response_message.response,
-                        referenced_note_id: Some(response_message.note_id),
-                    })
+            // the answer's message is added once the first piece of it arrives, until then the loading
+            // indicator is shown
+            let result = apis::stream_chatbot(chat_messages.get_clone(), |token| {
+                if loading.get() {
+                    loading.set(false);
+                    chat_messages.update(|vs| {
+                        vs.push(ChatMessage {
+                            sender: Sender::Bot,
+                            message: token,
+                            citations: vec![],
+                        })
+                    });
+                } else {
+                    chat_messages.update(|vs| {
+                        if let Some(answer) = vs.last_mut() {
+                            answer.message.push_str(&token);
+                        }
+                    });
+                }
+            })
+            .await;
+            match result {
+                Ok(citations) if !loading.get() => chat_messages.update(|vs| {
+                    if let Some(answer) = vs.last_mut() {
+                        answer.citations = citations;
+                    }
                 }),
+                Ok(_) => {}
                 Err(e) => {
                     console_error!("{e}");
                 }
@@ -81,7 +101,7 @@ pub fn Chatbot() -> View {
                 "I'm here to help you get more out of your notes, what would you like to know?"
                     .to_string(),
             sender: Sender::Bot,
-            referenced_note_id: None,
+            citations: vec![],
         }]);
         current_query.set(String::new());
         visible_chatbot.set(false);
@@ -105,26 +125,41 @@ pub fn Chatbot() -> View {
                         ul(class="flex flex-col w-full") {
                             Indexed(
                                 list=chat_messages,
-                                view=move |ChatMessage { sender, message, referenced_note_id }| {
+                                view=move |ChatMessage { sender, message, citations }| {
                                     let direction = match sender {
                                         Sender::Human => "self-end bg-blue-500",
                                         Sender::Bot => "self-start bg-gray-500"
                                     };
+                                    let sources = if citations.is_empty() {
+                                        view!()
+                                    } else {
+                                        let links = citations.into_iter().map(|Citation { source, note_id, title, snippet, score }| view! {
+                                            li {
+                                                a(
+                                                    on:click=move |_| current_note.set(CurrentNote(Some(note_id))),
+                                                    title=snippet,
+                                                    class="underline cursor-pointer"
+                                                ) {
+                                                    (format!("[{source}] {title}"))
+                                                }
+                                                (match score {
+                                                    Some(score) => view! {
+                                                        span(class="ml-1 font-extralight") { (format!("{score:.2}")) }
+                                                    },
+                                                    None => view! {},
+                                                })
+                                            }
+                                        }).collect::<Vec<_>>();
+                                        view! {
+                                            ol(class="mt-1 text-xs") {
+                                                (links)
+                                            }
+                                        }
+                                    };
                                     view! {
                                         li(class=format!("{direction} mb-1 rounded-lg p-2")) {
-                                            (if let Some(note_id) = referenced_note_id {
-                                                view! {
-                                                    a(
-                                                        on:click=move |_| current_note.set(CurrentNote(Some(note_id))),
-                                                        class="underline"
-                                                    ) {
-                                                        "Based on this note."
-                                                    }
-                                                    " "
-                                                }
-                                            } else { view!() })
-
                                             (message)
+                                            (sources)
                                         }
                                     }
                                 }
@@ -5,5 +5,6 @@ pub mod note;
 pub mod note_categories;
 pub mod note_list;
 pub mod org_avatar;
+pub mod related_notes;
 pub mod search_notes;
 pub mod sidebar;
@@ -1,12 +1,17 @@
 use crate::{
     apis::{
-        self, create_attachment, create_note, rekey_note, types::AttachmentInfo, update_note,
-        write_to_url,
+        self, confirm_attachment, create_attachment, create_note, rekey_note, suggest_categories,
+        summarize_note,
+        types::{AttachmentInfo, CategorySuggestion},
+        update_note, write_to_url,
+    },
+    components::{
+        atoms::{attachment_icon::AttachmentIcon, download_icon::DownloadIcon},
+        related_notes::RelatedNotes,
     },
-    components::atoms::{attachment_icon::AttachmentIcon, download_icon::DownloadIcon},
     pages::index::{CurrentCategory, CurrentNote},
 };
-use sycamore::{futures::spawn_local_scoped, prelude::*};
+use sycamore::{futures::spawn_local_scoped, prelude::*, web::console_error};
 use web_sys::window;
 
 #[component]
@@ -16,10 +21,13 @@ pub fn Note() -> View {
     let category = create_signal(String::new());
     let title = create_signal(String::new());
     let body = create_signal(String::new());
+    let summary = create_signal(None::<String>);
     let id = create_signal("Note ID".to_string());
     let attachments = create_signal(Vec::<AttachmentInfo>::new());
+    let suggestions = create_signal(Vec::<CategorySuggestion>::new());
     create_effect(move || {
         let current_note = current_note_id.get();
+        suggestions.set(vec![]);
         spawn_local_scoped(async move {
             if let Some(note_id) = current_note.0 {
                 match apis::note(note_id).await {
@@ -28,6 +36,7 @@ pub fn Note() -> View {
                             category.set(note.category.unwrap_or_else(|| String::new()));
                             title.set(note.title);
                             body.set(note.body);
+                            summary.set(note.summary);
                             id.set(format!("Note ID {}", note.id.to_string()));
                             attachments.set(note.attachments);
                         }
@@ -38,6 +47,7 @@ pub fn Note() -> View {
                 category.set(String::new());
                 title.set(String::new());
                 body.set(String::new());
+                summary.set(None);
                 id.set("Note ID".to_string());
                 attachments.set(vec![])
             }
@@ -70,6 +80,8 @@ pub fn Note() -> View {
 
             match operation {
                 Ok(note) => {
+                    // saving clears the summary, since it may no longer match
+                    summary.set(note.summary);
                     current_note_id.set(CurrentNote(Some(note.id)));
                     // touch the category, ideally this would only be if it was one that didn't already exist
                     current_category.set(current_category.get_clone());
@@ -80,6 +92,34 @@ pub fn Note() -> View {
         })
     };
 
+    let suggest_category = move |_| {
+        spawn_local_scoped(async move {
+            match suggest_categories(title.get_clone(), body.get_clone()).await {
+                Ok(resp) => suggestions.set(resp.result),
+                Err(e) => console_error!("Failed to suggest categories: {:?}", e),
+            }
+        })
+    };
+    let show_suggest = create_memo(move || {
+        category.with(String::is_empty)
+            && !(title.with(String::is_empty) && body.with(String::is_empty))
+    });
+
+    let summarize_note_handler = move |_| {
+        spawn_local_scoped(async move {
+            if let Some(note_id) = current_note_id.get().0 {
+                match summarize_note(note_id).await {
+                    Ok(resp) => {
+                        summary.set(Some(resp.summary));
+                        // refresh the note list so its preview shows the summary
+                        current_category.set(current_category.get_clone());
+                    }
+                    Err(e) => console_error!("Failed to summarize note: {:?}", e),
+                }
+            };
+        })
+    };
+
     let rekey_note_handler = move |_| {
         spawn_local_scoped(async move {
             if let Some(note_to_update) = current_note_id.get().0 {
@@ -100,21 +140,22 @@ pub fn Note() -> View {
             }
             .await;
 
-            let maybe_attachment_response = create_attachment(filename).await;
+            let maybe_attachment_response = create_attachment(filename, file_bytes.len()).await;
             match maybe_attachment_response {
                 Ok(attachment_response) => {
-                    write_to_url(attachment_response.presigned_put_url, file_bytes)
-                        .await
-                        .unwrap();
-                    attachments.update(|v| {
-                        v.push(AttachmentInfo {
-                            filename: attachment_response.filename,
-                            id: attachment_response.id,
-                            url: attachment_response.url,
-                        })
-                    });
+                    write_to_url(
+                        attachment_response.presigned_put_url,
+                        file_bytes,
+                        attachment_response.content_type,
+                    )
+                    .await
+                    .unwrap();
+                    match confirm_attachment(attachment_response.id).await {
+                        Ok(attachment) => attachments.update(|v| v.push(attachment)),
+                        Err(e) => console_error!("{e}"),
+                    }
                 }
-                Err(_) => {}
+                Err(e) => console_error!("{e}"),
             }
             {}
         })
@@ -123,6 +164,12 @@ pub fn Note() -> View {
     view! {
         div(class="border-l drop-shadow-md flex flex-col") {
             input(bind:value=title, r#type="text", placeholder="Title", autocomplete="off", class="w-full h-12 pl-4 outline-none border-b text-lg")
+            (match summary.get_clone() {
+                Some(summary) => view! {
+                    p(class="pl-4 pr-4 py-2 border-b text-sm italic text-gray-600") { (summary) }
+                },
+                None => view! {},
+            })
             textarea(bind:value=body, placeholder="Note", class="w-full grow pl-4 pt-2 outline-none text-sm")
             div(class="bg-white") {
                 button(on:click=new_attachment, class="ml-1"){ AttachmentIcon(class="w-6".to_string()) }
@@ -131,20 +178,48 @@ pub fn Note() -> View {
             }
             div(class="flex flex-row bg-white") {
                 input(bind:value=category, r#type="text", placeholder="Category", class="w-full h-12 pl-4 outline-none text-sm border-t")
+                (if show_suggest.get() {
+                    view! {
+                        button(on:click=suggest_category, class="text-nowrap text-sm border-t border-l text-gray-400 pl-4 pr-4") { "Suggest" }
+                    }
+                } else {
+                    view! {}
+                })
                 div(class="flex text-nowrap text-sm border-t border-l text-gray-400 pl-4 pr-4 items-center") {
                     (id.get_clone())
                 }
             }
+            (if show_suggest.get() && !suggestions.with(Vec::is_empty) {
+                let chips = suggestions
+                    .get_clone()
+                    .into_iter()
+                    .map(|suggestion| {
+                        let label = if suggestion.existing {
+                            format!("{} ({:.0}%)", suggestion.category, suggestion.confidence * 100.0)
+                        } else {
+                            format!("{} - new ({:.0}%)", suggestion.category, suggestion.confidence * 100.0)
+                        };
+                        view! {
+                            button(on:click=move |_| category.set(suggestion.category.clone()), class="ml-2 mb-2 px-2 py-1 rounded-sm bg-gray-200 text-xs") { (label) }
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                view! { div(class="bg-white") { (chips) } }
+            } else {
+                view! {}
+            })
             div(class="bg-white") {
                 (if current_note_id.get().0.is_none() {
                   view!{button(on:click=save_note, class="w-full border-2 bg-red-600 text-white h-12 self-end"){ "Save" }}
                 } else {
                     view!{
-                        button(on:click=save_note, class="w-5/6 border-2 bg-red-600 text-white h-12 self-end"){ "Save" }
+                        button(on:click=save_note, class="w-4/6 border-2 bg-red-600 text-white h-12 self-end"){ "Save" }
+                        button(on:click=summarize_note_handler, class="w-1/6 border-2 bg-red-600 text-white h-12 self-end"){ "Summarize" }
                         button(on:click=rekey_note_handler, class="w-1/6 border-2 bg-red-600 text-white h-12 self-end"){ "Rekey" }
                     }
                 })
             }
+            RelatedNotes()
         }
     }
 }
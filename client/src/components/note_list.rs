@@ -33,18 +33,50 @@ pub fn NoteList() -> View {
 
     let notes = create_memo(move || notes_resp.get_clone().result);
     let current_category_name = create_memo(move || current_category.get_clone().0);
-    let current_search_results = create_memo(move || search_results.get_clone().0);
+    let showing_search = create_memo(move || {
+        current_category_name.get_clone().as_deref() == Some(SEARCH_RESULT_CATEGORY)
+    });
+    // search results carry a relevance score to show next to each note
     let notes_to_render = create_memo(move || {
-        let mut render = notes.get_clone();
-        match current_category_name.get_clone() {
-            Some(cn) if &cn == SEARCH_RESULT_CATEGORY => {
-                render = current_search_results.get_clone();
-            }
-            _ => {}
+        if showing_search.get() {
+            search_results
+                .get_clone()
+                .notes
+                .into_iter()
+                .map(|scored| (scored.note, Some(scored.score)))
+                .collect::<Vec<_>>()
+        } else {
+            notes
+                .get_clone()
+                .into_iter()
+                .map(|note| (note, None))
+                .collect()
         }
-        render
     });
     let notes_count = create_memo(move || notes_to_render.get_clone().len());
+    let notes_total = create_memo(move || {
+        if showing_search.get() {
+            search_results.get_clone().total
+        } else {
+            notes_count.get()
+        }
+    });
+    let has_more = create_memo(move || notes_count.get() < notes_total.get());
+    let load_more = move |_| {
+        let current = search_results.get_clone();
+        spawn_local_scoped(async move {
+            match apis::search(current.query.clone(), current.notes.len()).await {
+                Ok(resp) => search_results.set(SearchResults {
+                    notes: current.notes.into_iter().chain(resp.result).collect(),
+                    total: resp.total,
+                    ..current
+                }),
+                Err(e) => {
+                    console_error!("Failed to get more search results: {:?}", e)
+                }
+            };
+        })
+    };
 
     view! {
         ul(class="w-full h-screen overflow-y-auto bg-gray-200") {
@@ -54,12 +86,18 @@ pub fn NoteList() -> View {
                     div(on:click=move |_| current_category.set(CurrentCategory::default()), class="w-4") {
                         Icon(path="M6 18 18 6M6 6l12 12".to_string())
                     }
-                    p(class="font-extralight text-gray-400 text-sm mt-auto mr-auto w-full") { (notes_count.get()) " notes"  }
+                    p(class="font-extralight text-gray-400 text-sm mt-auto mr-auto w-full") {
+                        (if has_more.get() {
+                            format!("{} of {} notes", notes_count.get(), notes_total.get())
+                        } else {
+                            format!("{} notes", notes_count.get())
+                        })
+                    }
                 }
             }
             Indexed(
                 list=notes_to_render,
-                view=move |n| {
+                view=move |(n, score)| {
                     let set_note = move |_| current_note.set(CurrentNote(Some(n.id)));
                     let bg_color = create_memo(move || match current_note.get_clone().0 {
                         Some(cn) if cn == n.id => "bg-gray-300",
@@ -71,13 +109,30 @@ pub fn NoteList() -> View {
                                 on:click=set_note,
                                 class=format!("flex flex-col p-4 w-full h-24 border-t border-gray-400 {}", bg_color)
                             ) {
-                                h4(class="font-bold text-sm") { (n.title.clone()) }
-                                p(class="line-clamp-2 text-sm") { (n.body.clone()) }
+                                div(class="flex flex-row justify-between") {
+                                    h4(class="font-bold text-sm") { (n.title.clone()) }
+                                    (match score {
+                                        Some(score) => view! {
+                                            span(class="font-extralight text-gray-500 text-xs") { (format!("{score:.2}")) }
+                                        },
+                                        None => view! {},
+                                    })
+                                }
+                                p(class="line-clamp-2 text-sm") { (n.summary.clone().unwrap_or_else(|| n.body.clone())) }
                             }
                         }
                     }
                 }
             )
+            (if showing_search.get() && has_more.get() {
+                view! {
+                    li {
+                        button(on:click=load_more, class="w-full p-4 text-sm border-t border-gray-400") { "Load more" }
+                    }
+                }
+            } else {
+                view! {}
+            })
         }
     }
 }
@@ -0,0 +1,56 @@
+use crate::{
+    apis::{self, types::ScoredNote},
+    pages::index::CurrentNote,
+};
+use sycamore::{futures::spawn_local_scoped, prelude::*, web::console_error};
+
+#[component]
+pub fn RelatedNotes() -> View {
+    let current_note = use_context::<Signal<CurrentNote>>();
+    let related = create_signal(Vec::<ScoredNote>::new());
+    create_effect(move || {
+        let note = current_note.get();
+        spawn_local_scoped(async move {
+            match note.0 {
+                Some(note_id) => match apis::related_notes(note_id).await {
+                    Ok(resp) => related.set(resp.result),
+                    Err(e) => {
+                        related.set(vec![]);
+                        console_error!("Failed to get related notes: {:?}", e)
+                    }
+                },
+                None => related.set(vec![]),
+            }
+        })
+    });
+    let has_related = create_memo(move || !related.with(Vec::is_empty));
+
+    view! {
+        (if has_related.get() {
+            view! {
+                div(class="bg-white border-t") {
+                    h4(class="font-bold text-sm pl-4 pt-2") { "Related" }
+                    ul(class="max-h-40 overflow-y-auto") {
+                        Indexed(
+                            list=related,
+                            view=move |scored| {
+                                let note_id = scored.note.id;
+                                view! {
+                                    li(
+                                        on:click=move |_| current_note.set(CurrentNote(Some(note_id))),
+                                        class="flex flex-row justify-between pl-4 pr-4 py-1 text-sm cursor-pointer hover:bg-gray-100"
+                                    ) {
+                                        span(class="truncate") { (scored.note.title.clone()) }
+                                        span(class="font-extralight text-gray-500 text-xs") { (format!("{:.2}", scored.score)) }
+                                    }
+                                }
+                            }
+                        )
+                    }
+                }
+            }
+        } else {
+            view! {}
+        })
+    }
+}
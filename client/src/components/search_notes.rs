@@ -17,9 +17,13 @@ pub fn SearchNotes() -> View {
         let query = search.get_clone();
         spawn_local_scoped(async move {
             if query != String::new() {
-                match apis::search(query).await {
+                match apis::search(query.clone(), 0).await {
                     Ok(resp) => {
-                        search_results.set(SearchResults(resp.result));
+                        search_results.set(SearchResults {
+                            query,
+                            notes: resp.result,
+                            total: resp.total,
+                        });
                         // BIG HACK ALERT
                         current_category
                             .set(CurrentCategory(Some(SEARCH_RESULT_CATEGORY.to_string())));
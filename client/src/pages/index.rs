@@ -1,5 +1,5 @@
 use crate::{
-    apis::types::Note,
+    apis::types::ScoredNote,
     components::{chatbot::Chatbot, note::Note, note_list::NoteList, sidebar::Sidebar},
 };
 use sycamore::prelude::*;
@@ -10,8 +10,13 @@ pub struct CurrentOrg(pub String);
 pub struct CurrentCategory(pub Option<String>);
 #[derive(Clone, Copy, PartialEq, Eq, Default)]
 pub struct CurrentNote(pub Option<usize>);
-#[derive(Clone, PartialEq, Eq, Default)]
-pub struct SearchResults(pub Vec<Note>);
+/// The pages of results loaded so far for the last search.
+#[derive(Clone, PartialEq, Default)]
+pub struct SearchResults {
+    pub query: String,
+    pub notes: Vec<ScoredNote>,
+    pub total: usize,
+}
 
 #[component]
 pub fn Index() -> View {
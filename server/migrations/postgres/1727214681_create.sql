@@ -0,0 +1,24 @@
+-- timestamps are stored as text in the same format as SQLite's `current_timestamp`, which the search index expects
+CREATE TABLE organization (
+  id BIGSERIAL PRIMARY KEY,
+  login TEXT NOT NULL,
+  name TEXT NOT NULL,
+  created TEXT DEFAULT to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'),
+  updated TEXT DEFAULT to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')
+);
+CREATE TABLE note (
+  id BIGSERIAL PRIMARY KEY,
+  org_id BIGINT NOT NULL REFERENCES organization(id),
+  category TEXT,
+  title TEXT NOT NULL,
+  body TEXT NOT NULL,
+  edek TEXT NOT NULL,
+  created TEXT DEFAULT to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'),
+  updated TEXT DEFAULT to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')
+);
+CREATE TABLE attachment (
+  id BIGSERIAL PRIMARY KEY,
+  note_id BIGINT REFERENCES note(id),
+  filename TEXT NOT NULL,
+  created TEXT DEFAULT to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')
+);
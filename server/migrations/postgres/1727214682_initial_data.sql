@@ -0,0 +1,2 @@
+INSERT INTO organization (login, name) VALUES ('notes-demo-1', 'IronCore Labs');
+INSERT INTO organization (login, name) VALUES ('notes-demo-2', 'iguanatech');
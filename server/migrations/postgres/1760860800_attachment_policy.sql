@@ -0,0 +1,14 @@
+CREATE TABLE attachment_policy (
+  org_id BIGINT PRIMARY KEY REFERENCES organization(id),
+  max_size_bytes BIGINT NOT NULL,
+  allowed_mime_types TEXT NOT NULL,
+  max_attachments_per_note BIGINT NOT NULL,
+  created TEXT DEFAULT to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'),
+  updated TEXT DEFAULT to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')
+);
+ALTER TABLE attachment ADD COLUMN org_id BIGINT REFERENCES organization(id);
+ALTER TABLE attachment ADD COLUMN content_type TEXT;
+ALTER TABLE attachment ADD COLUMN size BIGINT;
+ALTER TABLE attachment ADD COLUMN confirmed BOOLEAN NOT NULL DEFAULT FALSE;
+INSERT INTO attachment_policy (org_id, max_size_bytes, allowed_mime_types, max_attachments_per_note)
+  SELECT id, 10485760, 'image/jpeg,image/png,image/gif,text/plain,text/markdown,text/csv,application/pdf', 5 FROM organization;
@@ -0,0 +1,2 @@
+-- keyed hash of the content the note's search document was last embedded from
+ALTER TABLE note ADD COLUMN content_fingerprint TEXT;
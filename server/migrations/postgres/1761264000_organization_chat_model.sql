@@ -0,0 +1,5 @@
+-- per organization chat model settings, NULL falls back to the server's CHAT_* settings
+ALTER TABLE organization ADD COLUMN chat_provider TEXT;
+ALTER TABLE organization ADD COLUMN chat_model TEXT;
+ALTER TABLE organization ADD COLUMN chat_temperature REAL;
+ALTER TABLE organization ADD COLUMN chat_max_tokens BIGINT;
@@ -0,0 +1,11 @@
+-- every saved version of an organization's chat prompt template, the highest version is the one in use
+CREATE TABLE prompt_template (
+  id BIGSERIAL PRIMARY KEY,
+  org_id BIGINT NOT NULL REFERENCES organization(id),
+  version BIGINT NOT NULL,
+  system TEXT NOT NULL,
+  note_format TEXT NOT NULL,
+  answer_style TEXT NOT NULL,
+  created TEXT DEFAULT to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'),
+  UNIQUE(org_id, version)
+);
@@ -0,0 +1,17 @@
+CREATE TABLE attachment_policy (
+  org_id INTEGER PRIMARY KEY,
+  max_size_bytes INTEGER NOT NULL,
+  allowed_mime_types TEXT NOT NULL,
+  max_attachments_per_note INTEGER NOT NULL,
+  created DATETIME DEFAULT current_timestamp,
+  updated DATETIME DEFAULT current_timestamp,
+  FOREIGN KEY(org_id) REFERENCES organization(id)
+);
+ALTER TABLE attachment ADD COLUMN org_id INTEGER REFERENCES organization(id);
+ALTER TABLE attachment ADD COLUMN content_type TEXT;
+ALTER TABLE attachment ADD COLUMN size INTEGER;
+ALTER TABLE attachment ADD COLUMN confirmed BOOLEAN NOT NULL DEFAULT FALSE;
+-- attachments uploaded before policies existed are grandfathered in
+UPDATE attachment SET org_id = (SELECT note.org_id FROM note WHERE note.id = attachment.note_id), confirmed = TRUE;
+INSERT INTO attachment_policy (org_id, max_size_bytes, allowed_mime_types, max_attachments_per_note)
+  SELECT id, 10485760, 'image/jpeg,image/png,image/gif,text/plain,text/markdown,text/csv,application/pdf', 5 FROM organization;
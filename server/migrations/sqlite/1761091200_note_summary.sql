@@ -0,0 +1,2 @@
+-- standard encrypted with the note's existing EDEK, cleared whenever the note is edited
+ALTER TABLE note ADD COLUMN summary TEXT;
@@ -0,0 +1,12 @@
+-- every saved version of an organization's chat prompt template, the highest version is the one in use
+CREATE TABLE prompt_template (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  org_id INTEGER NOT NULL,
+  version INTEGER NOT NULL,
+  system TEXT NOT NULL,
+  note_format TEXT NOT NULL,
+  answer_style TEXT NOT NULL,
+  created DATETIME DEFAULT current_timestamp,
+  FOREIGN KEY(org_id) REFERENCES organization(id),
+  UNIQUE(org_id, version)
+);
@@ -0,0 +1,6 @@
+-- each organization is looked up by its login, so no two can share one. Databases from before this may already have
+-- organizations that do: the first one created keeps the login and the others get their ID appended, to be renamed
+-- by hand if need be.
+UPDATE organization SET login = login || '-' || id
+WHERE EXISTS (SELECT 1 FROM organization AS earlier WHERE earlier.login = organization.login AND earlier.id < organization.id);
+CREATE UNIQUE INDEX organization_login ON organization (login);
@@ -1,9 +1,12 @@
 
 function write_attachment {
-  resp=$(curl -X POST -H "Content-Type: application/json" --cookie organization=$2 -d '{"filename": "'$1'"}' http://localhost:7654/api/attachments)
+  size=$(wc -c < $1)
+  resp=$(curl -X POST -H "Content-Type: application/json" --cookie organization=$2 -d '{"filename": "'$1'", "size": '$size'}' http://localhost:7654/api/attachments)
   attach_id=$(echo $resp | jq -r ".id")
   url=$(echo $resp | jq -r ".presigned_put_url")
-  curl --upload-file $1 ${url}
+  content_type=$(echo $resp | jq -r ".content_type")
+  curl -H "Content-Type: ${content_type}" --upload-file $1 ${url}
+  curl -X POST --cookie organization=$2 http://localhost:7654/api/attachments/${attach_id}/confirm
   echo "Attachment $1 is ID ${attach_id}"
 }
 
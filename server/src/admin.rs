@@ -0,0 +1,197 @@
+use crate::{
+    attachments::{self, attachment_key, CreateAttachmentRequest},
+    db::{self, get_organization, EncryptedString},
+    db_pool::DbPool,
+    note_service,
+    notes::{self, CreateNoteRequest},
+    AppState, CurrentOrganization,
+};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use tracing::warn;
+
+/// The organization with this login.
+async fn organization(db: &DbPool, login: &str) -> Result<CurrentOrganization> {
+    get_organization(db, login)
+        .await?
+        .map(CurrentOrganization)
+        .ok_or_else(|| anyhow!("There's no organization with the login `{login}`."))
+}
+
+/// Returns the new organization's ID. It gets the default attachment policy.
+pub async fn create_organization(db: &DbPool, login: &str, name: &str) -> Result<u32> {
+    if get_organization(db, login).await?.is_some() {
+        return Err(anyhow!(
+            "There's already an organization with the login `{login}`."
+        ));
+    }
+    Ok(db::create_organization(db, login, name).await?.id)
+}
+
+/// Overrides the server's chat model settings for an organization. Each of `settings` is `provider=`, `model=`,
+/// `temperature=`, or `max_tokens=` followed by its value. Any setting left out goes back to the server's.
+pub async fn set_chat_model(state: &AppState, login: &str, settings: &[&str]) -> Result<()> {
+    let mut org = organization(&state.db, login).await?.0;
+    org.chat_provider = None;
+    org.chat_model = None;
+    org.chat_temperature = None;
+    org.chat_max_tokens = None;
+    for setting in settings {
+        let (name, value) = setting
+            .split_once('=')
+            .ok_or_else(|| anyhow!("`{setting}` isn't `<setting>=<value>`."))?;
+        match name {
+            "provider" => org.chat_provider = Some(value.to_string()),
+            "model" => org.chat_model = Some(value.to_string()),
+            "temperature" => {
+                let temperature = value
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|temperature| (0.0..=2.0).contains(temperature))
+                    .ok_or_else(|| anyhow!("The temperature has to be a number from 0 to 2."))?;
+                org.chat_temperature = Some(temperature);
+            }
+            "max_tokens" => {
+                let max_tokens = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|max_tokens| *max_tokens > 0)
+                    .ok_or_else(|| anyhow!("`max_tokens` has to be a whole number above 0."))?;
+                org.chat_max_tokens = Some(i64::from(max_tokens));
+            }
+            other => return Err(anyhow!("There's no chat model setting `{other}`.")),
+        }
+    }
+    // the same check the organization's next chat would fail, such as for an unknown provider
+    state.chat_models.for_org(&org)?;
+    db::set_organization_chat_model(
+        &state.db,
+        org.id,
+        org.chat_provider.as_deref(),
+        org.chat_model.as_deref(),
+        org.chat_temperature,
+        org.chat_max_tokens
+            .and_then(|max_tokens| u32::try_from(max_tokens).ok()),
+    )
+    .await
+}
+
+/// Creates and indexes a note for each line of `jsonl`, which holds the same JSON as `POST /api/notes` like the
+/// `notes_data_demo_*` files. Attachment IDs that are keys of `attachment_ids` are replaced with their values, so
+/// notes written against other IDs (like the demo files') can point at the attachments they were uploaded as.
+/// Stops at the first note that fails, leaving the ones before it imported. Returns the IDs of the new notes.
+pub async fn import_notes(
+    state: &AppState,
+    login: &str,
+    jsonl: &str,
+    attachment_ids: &HashMap<u32, u32>,
+) -> Result<Vec<u32>> {
+    let org = organization(&state.db, login).await?;
+    let mut ids = vec![];
+    for (number, line) in (1..).zip(jsonl.lines()) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut input = serde_json::from_str::<CreateNoteRequest>(line)
+            .with_context(|| format!("Line {number} isn't a note."))?;
+        for id in &mut input.attachments {
+            *id = attachment_ids.get(id).copied().unwrap_or(*id);
+        }
+        let note = notes::create_note(state, &org, input)
+            .await
+            .with_context(|| format!("Couldn't import the note on line {number}."))?;
+        ids.push(note.id);
+    }
+    Ok(ids)
+}
+
+/// Stores and confirms an attachment the same way an upload from the browser is, so it's held to the
+/// organization's attachment policy. Returns its ID.
+pub async fn upload_attachment(
+    state: &AppState,
+    login: &str,
+    filename: &str,
+    bytes: Vec<u8>,
+) -> Result<u32> {
+    let org = organization(&state.db, login).await?;
+    let request = CreateAttachmentRequest {
+        filename: filename.to_string(),
+        size: bytes.len() as i64,
+        content_type: None,
+    };
+    let created = attachments::create_attachment(
+        state.attachment_repo.clone(),
+        request,
+        &org,
+        state.aws_sdk.clone(),
+    )
+    .await?;
+    state
+        .aws_sdk
+        .put(
+            &attachment_key(&org, created.id, &created.filename),
+            bytes,
+            &created.content_type,
+            HashMap::new(),
+        )
+        .await?;
+    let confirmed = attachments::confirm_attachment(
+        state.attachment_repo.clone(),
+        created.id,
+        &org,
+        state.aws_sdk.clone(),
+    )
+    .await?;
+    Ok(confirmed.id)
+}
+
+/// Embeds and indexes each of the organization's notes again, such as after the search index was recreated for a
+/// new embedding provider. Notes that can't be decrypted are skipped. Returns how many were indexed.
+pub async fn reindex(state: &AppState, login: &str) -> Result<usize> {
+    let org = organization(&state.db, login).await?;
+    let notes = note_service::list_notes(
+        state.note_repo.clone(),
+        state.attachment_repo.clone(),
+        org.clone(),
+        state.sdk.clone(),
+        None,
+        state.aws_sdk.clone(),
+    )
+    .await?;
+    for note in &notes {
+        let input = CreateNoteRequest {
+            title: note.title.clone(),
+            body: note.body.clone(),
+            category: note.category.clone(),
+            attachments: note
+                .attachments
+                .iter()
+                .map(|attachment| attachment.id)
+                .collect(),
+        };
+        notes::index_note(state, &org, note.id, input, &note.attachments)
+            .await
+            .with_context(|| format!("Couldn't index note {}.", note.id))?;
+    }
+    Ok(notes.len())
+}
+
+/// Re-encrypts the EDEK of each of the organization's notes to its current key. Notes that fail are logged.
+/// Returns how many were rekeyed.
+pub async fn rekey(state: &AppState, login: &str) -> Result<usize> {
+    let org = organization(&state.db, login).await?;
+    let edeks = state
+        .note_repo
+        .list(org.0.id, None)
+        .await?
+        .into_iter()
+        .map(|row| (row.id, EncryptedString(row.edek)))
+        .collect::<Vec<_>>();
+    let count = edeks.len();
+    let failures =
+        note_service::rekey_notes(state.note_repo.clone(), edeks, &org, state.sdk.clone()).await?;
+    for (id, failure) in &failures {
+        warn!("Couldn't rekey note {}: {}", id, failure);
+    }
+    Ok(count - failures.len())
+}
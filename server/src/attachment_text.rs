@@ -0,0 +1,98 @@
+use crate::{
+    attachments::{attachment_key, AttachmentInfo},
+    object_store::ObjectStore,
+    CurrentOrganization,
+};
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use itertools::Itertools;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Attachments bigger than this aren't downloaded to extract text from, since PDFs especially are slow to parse.
+const MAX_EXTRACT_BYTES: i64 = 2 * 1024 * 1024;
+/// Each attachment's text is cut off after this many characters, so a few big attachments can't make saving a note
+/// embed hundreds of passages.
+const MAX_TEXT_CHARS: usize = 20_000;
+
+/// Pulls the attachments back from the object store, which decrypts them, and extracts any text we know how to read.
+/// Attachments that are too big, or that can't be downloaded or parsed, are skipped so they don't block saving the
+/// note. Returns `None` if none of the attachments had any text.
+pub async fn extract_attachments_text(
+    aws_sdk: Arc<dyn ObjectStore>,
+    org: &CurrentOrganization,
+    attachments: &[AttachmentInfo],
+) -> Option<String> {
+    let texts = join_all(attachments.iter().map(|attachment| {
+        let aws_sdk = aws_sdk.clone();
+        async move {
+            match fetch_attachment_text(aws_sdk, org, attachment).await {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!(
+                        "Failed to extract text from attachment `{}`: {:?}",
+                        attachment.filename, e
+                    );
+                    None
+                }
+            }
+        }
+    }))
+    .await
+    .into_iter()
+    .flatten()
+    .filter(|text| !text.trim().is_empty())
+    .collect_vec();
+
+    (!texts.is_empty()).then(|| texts.join("\n\n"))
+}
+
+async fn fetch_attachment_text(
+    aws_sdk: Arc<dyn ObjectStore>,
+    org: &CurrentOrganization,
+    attachment: &AttachmentInfo,
+) -> Result<Option<String>> {
+    if text_kind(&attachment.filename).is_none() {
+        return Ok(None);
+    }
+    let key = attachment_key(org, attachment.id, &attachment.filename);
+    let missing = || anyhow!("There's no object at `{key}`.");
+    let size = aws_sdk.head(&key).await?.ok_or_else(missing)?.size;
+    if size > MAX_EXTRACT_BYTES {
+        return Err(anyhow!(
+            "It's {size} bytes, more than the {MAX_EXTRACT_BYTES} text is extracted from."
+        ));
+    }
+    let bytes = aws_sdk.get(&key).await?.ok_or_else(missing)?;
+    let mut text = extract_text(&attachment.filename, &bytes)?;
+    if let Some(text) = &mut text {
+        if let Some((end, _)) = text.char_indices().nth(MAX_TEXT_CHARS) {
+            text.truncate(end);
+        }
+    }
+    Ok(text)
+}
+
+enum TextKind {
+    Plain,
+    Pdf,
+}
+
+fn text_kind(filename: &str) -> Option<TextKind> {
+    let extension = filename.rsplit_once('.')?.1.to_lowercase();
+    match extension.as_str() {
+        "txt" | "md" | "csv" => Some(TextKind::Plain),
+        "pdf" => Some(TextKind::Pdf),
+        _ => None,
+    }
+}
+
+/// Extracts the text of a supported attachment type. Unsupported types produce `None`.
+pub fn extract_text(filename: &str, bytes: &[u8]) -> Result<Option<String>> {
+    let text = match text_kind(filename) {
+        Some(TextKind::Plain) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        Some(TextKind::Pdf) => Some(pdf_extract::extract_text_from_mem(bytes)?),
+        None => None,
+    };
+    Ok(text)
+}
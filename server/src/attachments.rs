@@ -1,15 +1,28 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use anyhow::Result;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use futures::future::join_all;
 use serde::{Deserialize, Serialize};
-use tracing::error;
+use serde_json::json;
+use std::{collections::HashMap, fmt::Display, sync::Arc};
 
 use crate::{
-    db::{self},
-    AppState, CurrentOrganization,
+    db::AttachmentTable, error::handle_err, object_store::ObjectStore,
+    repository::AttachmentRepository, AppState, CurrentOrganization,
 };
 
 #[derive(Debug, Deserialize)]
 pub struct CreateAttachmentRequest {
     pub filename: String,
+    /// Size of the file in bytes. The presigned upload will only accept exactly this many bytes.
+    pub size: i64,
+    /// Defaults to a type guessed from the filename's extension.
+    pub content_type: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -17,6 +30,8 @@ pub struct CreateAttachmentResponse {
     pub id: u32,
     pub note_id: Option<u32>,
     pub filename: String,
+    /// The `Content-Type` header that must be sent with the upload to `presigned_put_url`.
+    pub content_type: String,
     pub presigned_put_url: String,
     pub url: String,
 }
@@ -28,17 +43,455 @@ pub struct AttachmentInfo {
     pub url: String,
 }
 
+/// Limits an organization places on attachment uploads.
+#[derive(Debug, Clone)]
+pub struct AttachmentPolicy {
+    pub max_size_bytes: i64,
+    pub allowed_mime_types: Vec<String>,
+    pub max_attachments_per_note: u32,
+}
+
+impl Default for AttachmentPolicy {
+    fn default() -> Self {
+        AttachmentPolicy {
+            max_size_bytes: 10 * 1024 * 1024,
+            allowed_mime_types: [
+                "image/jpeg",
+                "image/png",
+                "image/gif",
+                "text/plain",
+                "text/markdown",
+                "text/csv",
+                "application/pdf",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            max_attachments_per_note: 5,
+        }
+    }
+}
+
+impl AttachmentPolicy {
+    pub fn check_upload(&self, size: i64, content_type: &str) -> Result<(), PolicyViolation> {
+        if size < 0 {
+            Err(PolicyViolation::NegativeSize(size))
+        } else if size > self.max_size_bytes {
+            Err(PolicyViolation::TooLarge {
+                size,
+                max_size: self.max_size_bytes,
+            })
+        } else if !self
+            .allowed_mime_types
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(content_type))
+        {
+            Err(PolicyViolation::DisallowedType(content_type.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check_note_attachment_count(&self, count: usize) -> Result<(), PolicyViolation> {
+        if count > self.max_attachments_per_note as usize {
+            Err(PolicyViolation::TooManyAttachments {
+                count,
+                max_count: self.max_attachments_per_note,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A request that breaks the organization's attachment policy. These are reported back to the caller
+/// as a 4xx with a message rather than as an internal error.
+#[derive(Debug)]
+pub enum PolicyViolation {
+    TooLarge { size: i64, max_size: i64 },
+    DisallowedType(String),
+    TooManyAttachments { count: usize, max_count: u32 },
+    UnknownAttachment(u32),
+    Unconfirmed(u32),
+    AlreadyUploaded(u32),
+    UploadMismatch(u32),
+    NotUploaded(u32),
+    NegativeSize(i64),
+}
+
+impl PolicyViolation {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PolicyViolation::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            PolicyViolation::DisallowedType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            PolicyViolation::TooManyAttachments { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            PolicyViolation::UnknownAttachment(_) => StatusCode::NOT_FOUND,
+            PolicyViolation::Unconfirmed(_) => StatusCode::CONFLICT,
+            PolicyViolation::AlreadyUploaded(_) => StatusCode::CONFLICT,
+            PolicyViolation::UploadMismatch(_) => StatusCode::BAD_REQUEST,
+            PolicyViolation::NotUploaded(_) => StatusCode::CONFLICT,
+            PolicyViolation::NegativeSize(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyViolation::TooLarge { size, max_size } => write!(
+                f,
+                "Attachment is {size} bytes, the maximum allowed is {max_size} bytes."
+            ),
+            PolicyViolation::DisallowedType(content_type) => {
+                write!(f, "Attachments of type `{content_type}` are not allowed.")
+            }
+            PolicyViolation::TooManyAttachments { count, max_count } => write!(
+                f,
+                "A note can have at most {max_count} attachments, {count} were given."
+            ),
+            PolicyViolation::UnknownAttachment(id) => {
+                write!(f, "Attachment with ID {id} was not found.")
+            }
+            PolicyViolation::Unconfirmed(id) => write!(
+                f,
+                "Attachment with ID {id} hasn't been confirmed since it was uploaded."
+            ),
+            PolicyViolation::AlreadyUploaded(id) => {
+                write!(f, "Attachment with ID {id} has already been uploaded.")
+            }
+            PolicyViolation::UploadMismatch(id) => write!(
+                f,
+                "The upload doesn't have the size and type attachment {id} was created with."
+            ),
+            PolicyViolation::NotUploaded(id) => {
+                write!(f, "Attachment with ID {id} hasn't been uploaded yet.")
+            }
+            PolicyViolation::NegativeSize(size) => {
+                write!(f, "Attachment size can't be negative, {size} was given.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+impl IntoResponse for PolicyViolation {
+    fn into_response(self) -> Response {
+        (
+            self.status_code(),
+            Json(json!({ "error": self.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+/// Best guess at the MIME type of an attachment based on its extension.
+pub fn content_type_for(filename: &str) -> &'static str {
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("csv") => "text/csv",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Object key the attachment is stored under in the attachment bucket.
+pub fn attachment_key(org: &CurrentOrganization, attachment_id: u32, filename: &str) -> String {
+    format!("{}/{}-{}", org.0.login, attachment_id, filename)
+}
+
+async fn create_attachment_info(
+    aws_sdk: Arc<dyn ObjectStore>,
+    org: &CurrentOrganization,
+    attachment: AttachmentTable,
+) -> Result<AttachmentInfo> {
+    let content_type = attachment
+        .content_type
+        .unwrap_or_else(|| content_type_for(&attachment.filename).to_string());
+    let url = aws_sdk
+        .presign_get(
+            &attachment_key(org, attachment.id, &attachment.filename),
+            &content_type,
+        )
+        .await?;
+
+    Ok(AttachmentInfo {
+        id: attachment.id,
+        filename: attachment.filename,
+        url,
+    })
+}
+
+pub async fn create_attachment_infos(
+    aws_sdk: Arc<dyn ObjectStore>,
+    org: &CurrentOrganization,
+    attachments: Vec<AttachmentTable>,
+) -> Result<Vec<AttachmentInfo>> {
+    join_all(
+        attachments
+            .into_iter()
+            .map(|attachment| create_attachment_info(aws_sdk.clone(), org, attachment)),
+    )
+    .await
+    .into_iter()
+    .collect()
+}
+
+pub async fn create_attachment(
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    attachment: CreateAttachmentRequest,
+    org: &CurrentOrganization,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> Result<CreateAttachmentResponse> {
+    let content_type = attachment
+        .content_type
+        .unwrap_or_else(|| content_type_for(&attachment.filename).to_string());
+    attachment_repo
+        .get_policy(org.0.id)
+        .await?
+        .check_upload(attachment.size, &content_type)?;
+
+    let new_attachment = attachment_repo
+        .create(
+            org.0.id,
+            attachment.filename,
+            &content_type,
+            attachment.size,
+        )
+        .await?;
+
+    // content length and type are part of the signature, so the upload has to match what was checked above
+    let presigned_put_url = aws_sdk
+        .presign_put(
+            &attachment_key(org, new_attachment.id, &new_attachment.filename),
+            attachment.size,
+            &content_type,
+        )
+        .await?;
+    let note_id = new_attachment.note_id.map(u32::try_from).transpose()?;
+    let info = create_attachment_info(aws_sdk, org, new_attachment).await?;
+
+    Ok(CreateAttachmentResponse {
+        filename: info.filename,
+        id: info.id,
+        note_id,
+        content_type,
+        presigned_put_url,
+        url: info.url,
+    })
+}
+
+/// Checks the uploaded object against the organization's policy. If it doesn't pass, the object and its
+/// attachment record are removed.
+pub async fn confirm_attachment(
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    id: u32,
+    org: &CurrentOrganization,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> Result<AttachmentInfo> {
+    let attachment = attachment_repo
+        .get(id, org.0.id)
+        .await?
+        .ok_or(PolicyViolation::UnknownAttachment(id))?;
+    let key = attachment_key(org, attachment.id, &attachment.filename);
+
+    let uploaded = aws_sdk
+        .head(&key)
+        .await?
+        .ok_or(PolicyViolation::NotUploaded(id))?;
+    let size = uploaded.size;
+    let content_type = uploaded
+        .content_type
+        .or(attachment.content_type.clone())
+        .unwrap_or_else(|| content_type_for(&attachment.filename).to_string());
+
+    if let Err(violation) = attachment_repo
+        .get_policy(org.0.id)
+        .await?
+        .check_upload(size, &content_type)
+    {
+        aws_sdk.delete(&key).await?;
+        attachment_repo.delete(id).await?;
+        Err(violation)?
+    }
+
+    attachment_repo.confirm(id, size).await?;
+
+    create_attachment_info(aws_sdk, org, attachment).await
+}
+
 pub async fn create(
-    State(AppState { db, aws_sdk, .. }): State<AppState>,
+    State(AppState {
+        attachment_repo,
+        aws_sdk,
+        ..
+    }): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
     Json(input): Json<CreateAttachmentRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let result = db::create_attachment(&db, input, &org, aws_sdk)
+) -> Result<impl IntoResponse, Response> {
+    let result = create_attachment(attachment_repo, input, &org, aws_sdk)
+        .await
+        .map_err(handle_err)?;
+
+    Ok(Json(result))
+}
+
+/// Called once the upload to the presigned URL has finished. Checks what actually landed in the bucket against
+/// the policy again, since the uploader could have ignored the presigned conditions.
+pub async fn confirm(
+    Path(id): Path<u32>,
+    State(AppState {
+        attachment_repo,
+        aws_sdk,
+        ..
+    }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+) -> Result<impl IntoResponse, Response> {
+    let result = confirm_attachment(attachment_repo, id, &org, aws_sdk)
         .await
-        .map_err(|e| {
-            error!("{:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(handle_err)?;
 
     Ok(Json(result))
 }
+
+/// Objects are stored under their organization's login, and can only be read or written by that organization.
+fn owns_object(org: &CurrentOrganization, key: &str) -> bool {
+    key.split_once('/')
+        .is_some_and(|(owner, _)| owner == org.0.login)
+}
+
+/// The ID of the attachment stored under `key`, if it's an attachment's key at all.
+fn attachment_id(key: &str) -> Option<u32> {
+    let (_, name) = key.split_once('/')?;
+    let (id, _) = name.split_once('-')?;
+    id.parse().ok()
+}
+
+/// Where the browser uploads attachments to when they're encrypted by the server, in place of a presigned URL.
+/// With no presigned signature to enforce them, the upload is held here to the size and type the attachment was
+/// created with, which were checked against the organization's policy, and can only happen before it's confirmed.
+pub async fn put_object(
+    Path(key): Path<String>,
+    State(AppState {
+        attachment_repo,
+        aws_sdk,
+        ..
+    }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, Response> {
+    if !owns_object(&org, &key) {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    let Some(id) = attachment_id(&key) else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+    let attachment = attachment_repo
+        .get(id, org.0.id)
+        .await
+        .map_err(handle_err)?
+        .filter(|attachment| attachment_key(&org, attachment.id, &attachment.filename) == key)
+        .ok_or_else(|| PolicyViolation::UnknownAttachment(id).into_response())?;
+    if attachment.confirmed {
+        return Err(PolicyViolation::AlreadyUploaded(id).into_response());
+    }
+    let content_type = attachment
+        .content_type
+        .unwrap_or_else(|| content_type_for(&attachment.filename).to_string());
+    let size = attachment
+        .size
+        .and_then(|size| usize::try_from(size).ok())
+        .unwrap_or_default();
+    let mismatch = || PolicyViolation::UploadMismatch(id).into_response();
+    if headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        != Some(content_type.as_str())
+    {
+        return Err(mismatch());
+    }
+    let bytes = to_bytes(body, size).await.map_err(|_| mismatch())?;
+    if bytes.len() != size {
+        return Err(mismatch());
+    }
+    aws_sdk
+        .put(&key, bytes.to_vec(), &content_type, HashMap::new())
+        .await
+        .map_err(handle_err)?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Decrypts an attachment that was encrypted by the server and sends it back. The type is decided from the
+/// filename rather than what was uploaded, and browsers are told not to second guess it.
+pub async fn get_object(
+    Path(key): Path<String>,
+    State(AppState { aws_sdk, .. }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+) -> Result<impl IntoResponse, Response> {
+    if !owns_object(&org, &key) {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    let bytes = aws_sdk
+        .get(&key)
+        .await
+        .map_err(handle_err)?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type_for(&key)),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        bytes,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::OrganizationTable;
+
+    fn org(login: &str) -> CurrentOrganization {
+        CurrentOrganization(OrganizationTable {
+            id: 1,
+            login: login.to_string(),
+            name: "Acme".to_string(),
+            created: "2024-01-01 00:00:00".to_string(),
+            updated: "2024-01-01 00:00:00".to_string(),
+            chat_provider: None,
+            chat_model: None,
+            chat_temperature: None,
+            chat_max_tokens: None,
+        })
+    }
+
+    #[test]
+    fn objects_belong_to_the_organization_they_are_under() {
+        let acme = org("acme");
+        assert!(owns_object(&acme, "acme/12-report.pdf"));
+        assert!(owns_object(&acme, "acme/report"));
+        assert!(!owns_object(&acme, "acme"));
+        assert!(!owns_object(&acme, "acme-12-report.pdf"));
+        assert!(!owns_object(&acme, "acme2/12-report.pdf"));
+        assert!(!owns_object(&acme, "/acme/12-report.pdf"));
+    }
+
+    #[test]
+    fn attachment_ids_come_from_the_start_of_the_name() {
+        assert_eq!(attachment_id("acme/12-report.pdf"), Some(12));
+        assert_eq!(attachment_id("acme/12-my-report.pdf"), Some(12));
+        assert_eq!(attachment_id("acme/12"), None);
+        assert_eq!(attachment_id("acme/report.pdf"), None);
+        assert_eq!(attachment_id("12-report.pdf"), None);
+        assert_eq!(attachment_id("acme/-report.pdf"), None);
+        assert_eq!(attachment_id("acme/twelve-report.pdf"), None);
+    }
+}
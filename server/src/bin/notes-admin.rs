@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Context, Result};
+use demo_notes_app::{admin, db_pool::DbPool, init_tracing, AppState};
+use std::{collections::HashMap, path::Path};
+
+const USAGE: &str = "Usage: notes-admin <command>
+
+Commands:
+  migrate                                  Run the database migrations.
+  create-org <login> <name>                Create an organization.
+  import <login> <file>                    Create a note for each line of a JSON Lines file.
+  upload-attachment <login> <file>...      Upload and confirm attachments.
+  reindex <login>                          Embed and index all the organization's notes again.
+  rekey <login>                            Rekey all the organization's notes.
+  set-chat-model <login> [<setting>=<value>]...
+                                           Override the server's chat model provider, model, temperature, or
+                                           max_tokens for the organization. Settings left out use the server's.
+  seed                                     Upload the demo attachments and import the demo notes.
+
+Uses the same environment as the server.";
+
+/// A demo organization and what it's seeded with.
+struct Seed {
+    login: &'static str,
+    /// Each attachment's file and the ID the notes file refers to it by.
+    attachments: [(&'static str, u32); 2],
+    notes: &'static str,
+}
+
+const SEED: [Seed; 2] = [
+    Seed {
+        login: "notes-demo-1",
+        attachments: [("ramonesb.jpg", 1), ("slf.jpg", 2)],
+        notes: "notes_data_demo_1",
+    },
+    Seed {
+        login: "notes-demo-2",
+        attachments: [("squash.jpg", 3), ("gourds.jpg", 4)],
+        notes: "notes_data_demo_2",
+    },
+];
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_tracing();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args.as_slice() {
+        ["migrate"] => {
+            DbPool::from_env().await?.run_migrations().await?;
+            println!("Migrations are up to date.");
+        }
+        ["create-org", login, name] => {
+            let id = admin::create_organization(&DbPool::from_env().await?, login, name).await?;
+            println!("Created organization `{login}` with ID {id}.");
+        }
+        ["import", login, file] => {
+            let state = app_state().await?;
+            import(&state, login, file, &HashMap::new()).await?;
+        }
+        ["upload-attachment", login, files @ ..] if !files.is_empty() => {
+            let state = app_state().await?;
+            for file in files {
+                upload(&state, login, file).await?;
+            }
+        }
+        ["reindex", login] => {
+            let state = app_state().await?;
+            let count = admin::reindex(&state, login).await?;
+            println!("Indexed {count} notes for `{login}`.");
+        }
+        ["rekey", login] => {
+            let state = app_state().await?;
+            let count = admin::rekey(&state, login).await?;
+            println!("Rekeyed {count} notes for `{login}`.");
+        }
+        ["set-chat-model", login, settings @ ..] => {
+            let state = app_state().await?;
+            admin::set_chat_model(&state, login, settings).await?;
+            println!("Updated the chat model settings for `{login}`.");
+        }
+        ["seed"] => {
+            let state = app_state().await?;
+            for Seed {
+                login,
+                attachments,
+                notes,
+            } in SEED
+            {
+                let mut attachment_ids = HashMap::new();
+                for (file, seed_id) in attachments {
+                    attachment_ids.insert(seed_id, upload(&state, login, file).await?);
+                }
+                import(&state, login, notes, &attachment_ids).await?;
+            }
+        }
+        _ => return Err(anyhow!("{USAGE}")),
+    }
+    Ok(())
+}
+
+/// Connects to everything the server does, for commands that work on notes.
+async fn app_state() -> Result<AppState> {
+    AppState::from_env(DbPool::from_env().await?).await
+}
+
+async fn import(
+    state: &AppState,
+    login: &str,
+    file: &str,
+    attachment_ids: &HashMap<u32, u32>,
+) -> Result<()> {
+    let jsonl =
+        std::fs::read_to_string(file).with_context(|| format!("Couldn't read `{file}`."))?;
+    let ids = admin::import_notes(state, login, &jsonl, attachment_ids).await?;
+    println!("Imported {} notes from `{file}` for `{login}`.", ids.len());
+    Ok(())
+}
+
+/// Returns the attachment's ID.
+async fn upload(state: &AppState, login: &str, file: &str) -> Result<u32> {
+    let bytes = std::fs::read(file).with_context(|| format!("Couldn't read `{file}`."))?;
+    let filename = Path::new(file)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("`{file}` isn't a file name."))?;
+    let id = admin::upload_attachment(state, login, filename, bytes).await?;
+    println!("Attachment `{file}` for `{login}` is ID {id}.");
+    Ok(id)
+}
@@ -1,10 +1,14 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
-use serde::Serialize;
-use tracing::error;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    db::{self},
-    AppState, CurrentOrganization,
+    embeddings::{self, CategorySuggestion},
+    error::handle_err,
+    note_service, AppState, CurrentOrganization,
 };
 
 #[derive(Debug, Serialize)]
@@ -12,13 +16,50 @@ pub struct CategoryListResponse {
     result: Vec<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SuggestCategoryRequest {
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SuggestCategoryResponse {
+    result: Vec<CategorySuggestion>,
+}
+
 pub async fn list(
-    State(AppState { db, sdk, .. }): State<AppState>,
+    State(AppState { note_repo, sdk, .. }): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let result = db::list_categories(&db, org, sdk).await.map_err(|e| {
-        error!("{:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+) -> Result<impl IntoResponse, Response> {
+    let result = note_service::list_categories(note_repo, org, sdk)
+        .await
+        .map_err(handle_err)?;
     Ok(Json(CategoryListResponse { result }))
 }
+
+pub async fn suggest(
+    State(AppState {
+        note_repo,
+        sdk,
+        chat_models,
+        prompts,
+        ..
+    }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+    Json(input): Json<SuggestCategoryRequest>,
+) -> Result<impl IntoResponse, Response> {
+    let chat_model = chat_models.for_org(&org.0).map_err(handle_err)?;
+    let existing = note_service::list_categories(note_repo, org, sdk)
+        .await
+        .map_err(handle_err)?;
+    let result = embeddings::suggest_categories(
+        &*chat_model,
+        &prompts,
+        &input.title,
+        &input.body,
+        &existing,
+    )
+    .await
+    .map_err(handle_err)?;
+    Ok(Json(SuggestCategoryResponse { result }))
+}
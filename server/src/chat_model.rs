@@ -0,0 +1,530 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::{stream, stream::BoxStream, Stream, StreamExt};
+use ollama_rs::{
+    generation::{
+        chat::{request::ChatMessageRequest, ChatMessage},
+        options::GenerationOptions,
+        parameters::FormatType,
+    },
+    Ollama,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::{db::OrganizationTable, CHATBOT_MODEL_NAME};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatModelRole {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatModelMessage {
+    pub role: ChatModelRole,
+    pub content: String,
+}
+
+impl ChatModelMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        ChatModelMessage {
+            role: ChatModelRole::System,
+            content: content.into(),
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        ChatModelMessage {
+            role: ChatModelRole::User,
+            content: content.into(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        ChatModelMessage {
+            role: ChatModelRole::Assistant,
+            content: content.into(),
+        }
+    }
+}
+
+/// The text of a streamed answer, a piece at a time. The stream ends when the answer is complete.
+pub type ChatModelStream = BoxStream<'static, Result<String>>;
+
+/// A model that continues a conversation. Used for chat answers, category suggestions, and summaries.
+#[async_trait]
+pub trait ChatModel: Send + Sync {
+    /// Returns the model's whole reply. With `json` the model is told to reply with a JSON object.
+    async fn complete(&self, messages: Vec<ChatModelMessage>, json: bool) -> Result<String>;
+
+    /// Returns the model's reply as it's produced.
+    async fn stream(&self, messages: Vec<ChatModelMessage>) -> Result<ChatModelStream>;
+}
+
+/// Which model to use and how to sample from it. Set for the whole server with `CHAT_*` environment variables,
+/// which an organization's own `chat_*` columns override.
+#[derive(Debug, Clone)]
+pub struct ChatModelSettings {
+    pub provider: String,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl ChatModelSettings {
+    fn for_org(&self, org: &OrganizationTable) -> ChatModelSettings {
+        ChatModelSettings {
+            provider: org
+                .chat_provider
+                .clone()
+                .unwrap_or_else(|| self.provider.clone()),
+            model: org.chat_model.clone().or_else(|| self.model.clone()),
+            temperature: org.chat_temperature.or(self.temperature),
+            max_tokens: org
+                .chat_max_tokens
+                .and_then(|max_tokens| u32::try_from(max_tokens).ok())
+                .or(self.max_tokens),
+        }
+    }
+}
+
+/// Hands out the chat model configured for each organization.
+pub struct ChatModels {
+    defaults: ChatModelSettings,
+    ollama: Ollama,
+    http: reqwest::Client,
+    openai_base_url: String,
+    openai_api_key: Option<String>,
+    scripted: Arc<ScriptedChatModel>,
+}
+
+impl ChatModels {
+    /// Reads the server wide defaults: `CHAT_PROVIDER` (`ollama`, `openai`, or `scripted`), `CHAT_MODEL`,
+    /// `CHAT_TEMPERATURE`, and `CHAT_MAX_TOKENS`. The scripted provider replies with the answers in the JSON array
+    /// of strings at `CHAT_SCRIPT_FILE`.
+    pub fn from_env() -> Result<ChatModels> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let temperature = var("CHAT_TEMPERATURE")
+            .map(|temperature| temperature.parse::<f32>())
+            .transpose()
+            .map_err(|e| anyhow!("CHAT_TEMPERATURE must be a number: {e}"))?;
+        let max_tokens = var("CHAT_MAX_TOKENS")
+            .map(|max_tokens| max_tokens.parse::<u32>())
+            .transpose()
+            .map_err(|e| anyhow!("CHAT_MAX_TOKENS must be a number: {e}"))?;
+        let scripted = match var("CHAT_SCRIPT_FILE") {
+            Some(path) => {
+                let script = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Couldn't read CHAT_SCRIPT_FILE `{path}`."))?;
+                let responses =
+                    serde_json::from_str::<Vec<String>>(&script).with_context(|| {
+                        format!("CHAT_SCRIPT_FILE `{path}` isn't an array of strings.")
+                    })?;
+                ScriptedChatModel::new(responses)
+            }
+            None => ScriptedChatModel::default(),
+        };
+        let models = ChatModels {
+            defaults: ChatModelSettings {
+                provider: var("CHAT_PROVIDER").unwrap_or_else(|| "ollama".to_string()),
+                model: var("CHAT_MODEL"),
+                temperature,
+                max_tokens,
+            },
+            ollama: Ollama::default(),
+            http: reqwest::Client::new(),
+            openai_base_url: var("OPENAI_BASE_URL")
+                .unwrap_or_else(|| "https://api.openai.com/v1".to_string()),
+            openai_api_key: var("OPENAI_API_KEY"),
+            scripted: Arc::new(scripted),
+        };
+        // fail at startup rather than on the first chat if the default is misconfigured
+        models.build(&models.defaults)?;
+        Ok(models)
+    }
+
+    /// Every organization gets the given scripted model unless it's configured otherwise.
+    #[cfg(test)]
+    pub fn scripted(model: ScriptedChatModel) -> ChatModels {
+        ChatModels {
+            defaults: ChatModelSettings {
+                provider: "scripted".to_string(),
+                model: None,
+                temperature: None,
+                max_tokens: None,
+            },
+            ollama: Ollama::default(),
+            http: reqwest::Client::new(),
+            openai_base_url: "https://api.openai.com/v1".to_string(),
+            openai_api_key: None,
+            scripted: Arc::new(model),
+        }
+    }
+
+    /// The chat model the organization is configured to use.
+    pub fn for_org(&self, org: &OrganizationTable) -> Result<Arc<dyn ChatModel>> {
+        self.build(&self.defaults.for_org(org))
+    }
+
+    fn build(&self, settings: &ChatModelSettings) -> Result<Arc<dyn ChatModel>> {
+        let model: Arc<dyn ChatModel> = match settings.provider.as_str() {
+            "ollama" => Arc::new(OllamaChatModel {
+                client: self.ollama.clone(),
+                model: settings
+                    .model
+                    .clone()
+                    .unwrap_or_else(|| CHATBOT_MODEL_NAME.to_string()),
+                temperature: settings.temperature,
+                max_tokens: settings.max_tokens,
+            }),
+            "openai" => Arc::new(OpenAiChatModel {
+                client: self.http.clone(),
+                base_url: self.openai_base_url.clone(),
+                api_key: self.openai_api_key.clone(),
+                model: settings
+                    .model
+                    .clone()
+                    .unwrap_or_else(|| "gpt-4o-mini".to_string()),
+                temperature: settings.temperature,
+                max_tokens: settings.max_tokens,
+            }),
+            "scripted" => self.scripted.clone(),
+            other => return Err(anyhow!("Unknown chat provider `{other}`.")),
+        };
+        Ok(model)
+    }
+}
+
+/// A chat model served by the local Ollama.
+pub struct OllamaChatModel {
+    client: Ollama,
+    model: String,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+}
+
+impl OllamaChatModel {
+    fn request(&self, messages: Vec<ChatModelMessage>) -> ChatMessageRequest {
+        let messages = messages
+            .into_iter()
+            .map(|message| match message.role {
+                ChatModelRole::System => ChatMessage::system(message.content),
+                ChatModelRole::User => ChatMessage::user(message.content),
+                ChatModelRole::Assistant => ChatMessage::assistant(message.content),
+            })
+            .collect();
+        let request = ChatMessageRequest::new(self.model.clone(), messages);
+        if self.temperature.is_none() && self.max_tokens.is_none() {
+            // leave the Modelfile's parameters alone
+            return request;
+        }
+        let mut options = GenerationOptions::default();
+        if let Some(temperature) = self.temperature {
+            options = options.temperature(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            options = options.num_predict(max_tokens.try_into().unwrap_or(i32::MAX));
+        }
+        request.options(options)
+    }
+}
+
+#[async_trait]
+impl ChatModel for OllamaChatModel {
+    async fn complete(&self, messages: Vec<ChatModelMessage>, json: bool) -> Result<String> {
+        let mut request = self.request(messages);
+        if json {
+            request = request.format(FormatType::Json);
+        }
+        Ok(self
+            .client
+            .send_chat_messages(request)
+            .await?
+            .message
+            .content)
+    }
+
+    async fn stream(&self, messages: Vec<ChatModelMessage>) -> Result<ChatModelStream> {
+        let responses = self
+            .client
+            .send_chat_messages_stream(self.request(messages))
+            .await?;
+        Ok(responses
+            .map(|response| {
+                response
+                    .map(|response| response.message.content)
+                    .map_err(|()| anyhow!("Failed to read the model's response."))
+            })
+            .boxed())
+    }
+}
+
+/// A chat model behind an endpoint that speaks the OpenAI `/chat/completions` API.
+pub struct OpenAiChatModel {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAiChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatModelMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<OpenAiResponseFormat>,
+    stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAiResponseFormat {
+    #[serde(rename = "type")]
+    format_type: &'static str,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiChatResponse {
+    choices: Vec<OpenAiChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiChoice {
+    #[serde(alias = "delta")]
+    message: OpenAiMessage,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct OpenAiMessage {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+impl OpenAiChatModel {
+    async fn send(
+        &self,
+        messages: Vec<ChatModelMessage>,
+        json: bool,
+        stream: bool,
+    ) -> Result<reqwest::Response> {
+        let mut request = self
+            .client
+            .post(format!(
+                "{}/chat/completions",
+                self.base_url.trim_end_matches('/')
+            ))
+            .json(&OpenAiChatRequest {
+                model: &self.model,
+                messages,
+                temperature: self.temperature,
+                max_tokens: self.max_tokens,
+                response_format: json.then_some(OpenAiResponseFormat {
+                    format_type: "json_object",
+                }),
+                stream,
+            });
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        Ok(request.send().await?.error_for_status()?)
+    }
+}
+
+/// Pulls the text out of one server-sent event line of a streamed completion. `None` for lines that don't carry
+/// any, like keep-alives and the final `[DONE]`.
+fn openai_stream_line(line: &str) -> Option<Result<String>> {
+    let data = line.strip_prefix("data:")?.trim();
+    if data == "[DONE]" {
+        return None;
+    }
+    match serde_json::from_str::<OpenAiChatResponse>(data) {
+        Ok(chunk) => chunk
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .filter(|content| !content.is_empty())
+            .map(Ok),
+        Err(e) => Some(Err(anyhow!("Malformed chunk in the model's response: {e}"))),
+    }
+}
+
+#[async_trait]
+impl ChatModel for OpenAiChatModel {
+    async fn complete(&self, messages: Vec<ChatModelMessage>, json: bool) -> Result<String> {
+        let response = self
+            .send(messages, json, false)
+            .await?
+            .json::<OpenAiChatResponse>()
+            .await?;
+        response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .ok_or_else(|| anyhow!("The model's response didn't contain an answer."))
+    }
+
+    async fn stream(&self, messages: Vec<ChatModelMessage>) -> Result<ChatModelStream> {
+        let bytes = self.send(messages, false, true).await?.bytes_stream();
+        Ok(openai_stream_pieces(bytes).boxed())
+    }
+}
+
+/// The text of a streamed completion, from the raw bytes of the response. Events, and the UTF-8 characters in them,
+/// can be split across network chunks, so the bytes are buffered and only complete lines are decoded and parsed.
+/// Whatever's left when the response ends is the last line, even without a newline after it.
+fn openai_stream_pieces<B, E>(
+    bytes: impl Stream<Item = Result<B, E>> + Unpin,
+) -> impl Stream<Item = Result<String>>
+where
+    B: AsRef<[u8]>,
+    E: Into<anyhow::Error>,
+{
+    let decode = |line: &[u8]| {
+        String::from_utf8_lossy(line)
+            .trim_end_matches('\r')
+            .to_string()
+    };
+    // `None` once the response has ended
+    stream::unfold(Some((bytes, Vec::new())), move |state| async move {
+        let (mut bytes, mut buffer) = state?;
+        loop {
+            if let Some(newline) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line = decode(&buffer[..newline]);
+                buffer.drain(..=newline);
+                match openai_stream_line(&line) {
+                    Some(piece) => return Some((piece, Some((bytes, buffer)))),
+                    None => continue,
+                }
+            }
+            match bytes.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(chunk.as_ref()),
+                Some(Err(e)) => return Some((Err(e.into()), Some((bytes, Vec::new())))),
+                None => return openai_stream_line(&decode(&buffer)).map(|piece| (piece, None)),
+            }
+        }
+    })
+}
+
+/// Replies with a fixed script instead of running a model, cycling back to the start when it runs out. Answers
+/// are streamed a word at a time. Lets everything around the model be exercised offline and deterministically.
+pub struct ScriptedChatModel {
+    responses: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl ScriptedChatModel {
+    pub fn new(responses: Vec<String>) -> ScriptedChatModel {
+        ScriptedChatModel {
+            responses,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn next_response(&self) -> Result<String> {
+        if self.responses.is_empty() {
+            return Err(anyhow!("The chat script is empty."));
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Ok(self.responses[next % self.responses.len()].clone())
+    }
+}
+
+impl Default for ScriptedChatModel {
+    fn default() -> Self {
+        ScriptedChatModel::new(vec!["This is a scripted answer drawn from [1].".to_string()])
+    }
+}
+
+#[async_trait]
+impl ChatModel for ScriptedChatModel {
+    async fn complete(&self, _messages: Vec<ChatModelMessage>, _json: bool) -> Result<String> {
+        self.next_response()
+    }
+
+    async fn stream(&self, _messages: Vec<ChatModelMessage>) -> Result<ChatModelStream> {
+        let response = self.next_response()?;
+        let words = response
+            .split_inclusive(' ')
+            .map(|word| Ok(word.to_string()))
+            .collect::<Vec<_>>();
+        Ok(stream::iter(words).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    /// The text streamed from a response arriving in these chunks.
+    async fn pieces(chunks: &[&[u8]]) -> Vec<String> {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok::<_, anyhow::Error>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        openai_stream_pieces(stream::iter(chunks))
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    fn event(content: &str) -> String {
+        format!(
+            "data: {}",
+            serde_json::json!({ "choices": [{ "delta": { "content": content } }] })
+        )
+    }
+
+    #[tokio::test]
+    async fn the_last_line_counts_without_a_newline() {
+        let response = format!("{}\n{}", event("Hello"), event(" there"));
+        assert_eq!(pieces(&[response.as_bytes()]).await, ["Hello", " there"]);
+        let response = format!("{}\ndata: [DONE]", event("Hello"));
+        assert_eq!(pieces(&[response.as_bytes()]).await, ["Hello"]);
+    }
+
+    #[tokio::test]
+    async fn lines_can_be_split_across_chunks() {
+        let response = format!("{}\r\n\n{}\n", event("Hello"), event(" there"));
+        let response = response.as_bytes();
+        for split in 0..=response.len() {
+            assert_eq!(
+                pieces(&[&response[..split], &response[split..]]).await,
+                ["Hello", " there"],
+                "split at byte {split}"
+            );
+        }
+        let bytes = response.chunks(1).collect::<Vec<_>>();
+        assert_eq!(pieces(&bytes).await, ["Hello", " there"]);
+    }
+
+    #[tokio::test]
+    async fn characters_can_be_split_across_chunks() {
+        let response = format!("{}\n{}\n", event("Grüße"), event(" 👋"));
+        let bytes = response.as_bytes().chunks(1).collect::<Vec<_>>();
+        assert_eq!(pieces(&bytes).await, ["Grüße", " 👋"]);
+    }
+
+    #[tokio::test]
+    async fn lines_without_text_are_skipped() {
+        let response = format!(
+            ": keep-alive\n\n{}\n{}\ndata: [DONE]\n",
+            event(""),
+            event("Hi")
+        );
+        assert_eq!(pieces(&[response.as_bytes()]).await, ["Hi"]);
+    }
+}
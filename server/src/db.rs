@@ -1,29 +1,14 @@
 use crate::{
-    attachments::{AttachmentInfo, CreateAttachmentRequest, CreateAttachmentResponse},
-    notes::{CreateNoteRequest, UpdateNoteRequest},
+    attachments::AttachmentPolicy,
+    db_pool::{with_pool, DbPool},
+    prompt_templates::PromptTemplate,
     CurrentOrganization,
 };
-use anyhow::{anyhow, Result};
-use aws_sdk_s3::presigning::PresigningConfig;
+use anyhow::Result;
 use base64::{engine::general_purpose::STANDARD, Engine};
-use futures::future::join_all;
-use ironcore_alloy::{
-    deterministic::{DeterministicFieldOps, EncryptedField, EncryptedFields, PlaintextField},
-    standard::{
-        EdekWithKeyIdHeader, EncryptedDocument, EncryptedDocuments, PlaintextDocument,
-        StandardDocumentOps,
-    },
-    AlloyMetadata, DerivationPath, DocumentId, EncryptedBytes, FieldId, PlaintextBytes, SaasShield,
-    SecretPath, TenantId,
-};
-use itertools::Itertools;
+use ironcore_alloy::EncryptedBytes;
 use serde::{Deserialize, Serialize};
-use sqlx::{
-    prelude::{FromRow, Type},
-    Sqlite, SqlitePool, Transaction,
-};
-use std::{collections::HashMap, sync::Arc, time::Duration};
-use tracing::warn;
+use sqlx::prelude::{FromRow, Type};
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 #[sqlx(transparent)]
@@ -44,14 +29,14 @@ impl EncryptedString {
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 #[sqlx(transparent)]
-pub struct DeterministicallyEncryptedString(String);
+pub struct DeterministicallyEncryptedString(pub String);
 
 impl DeterministicallyEncryptedString {
-    fn new(bytes: EncryptedBytes) -> DeterministicallyEncryptedString {
+    pub fn new(bytes: EncryptedBytes) -> DeterministicallyEncryptedString {
         DeterministicallyEncryptedString(STANDARD.encode(bytes.0))
     }
 
-    fn to_enc_bytes(&self) -> Result<EncryptedBytes> {
+    pub fn to_enc_bytes(&self) -> Result<EncryptedBytes> {
         STANDARD
             .decode(&self.0)
             .map(EncryptedBytes)
@@ -61,53 +46,95 @@ impl DeterministicallyEncryptedString {
 
 #[derive(Clone, Debug, FromRow, Serialize)]
 pub struct AttachmentTable {
+    #[sqlx(try_from = "i64")]
     pub id: u32,
-    pub note_id: Option<u32>,
+    pub note_id: Option<i64>,
     pub filename: String,
     pub created: String,
+    pub org_id: Option<i64>,
+    pub content_type: Option<String>,
+    pub size: Option<i64>,
+    pub confirmed: bool,
+}
+
+#[derive(Clone, Debug, FromRow, Serialize)]
+pub struct AttachmentPolicyTable {
+    #[sqlx(try_from = "i64")]
+    pub org_id: u32,
+    pub max_size_bytes: i64,
+    /// Comma separated
+    pub allowed_mime_types: String,
+    #[sqlx(try_from = "i64")]
+    pub max_attachments_per_note: u32,
+    pub created: String,
+    pub updated: String,
+}
+
+impl From<AttachmentPolicyTable> for AttachmentPolicy {
+    fn from(row: AttachmentPolicyTable) -> Self {
+        AttachmentPolicy {
+            max_size_bytes: row.max_size_bytes,
+            allowed_mime_types: row
+                .allowed_mime_types
+                .split(',')
+                .map(|mime_type| mime_type.trim().to_string())
+                .filter(|mime_type| !mime_type.is_empty())
+                .collect(),
+            max_attachments_per_note: row.max_attachments_per_note,
+        }
+    }
 }
 
 #[derive(Clone, Debug, FromRow, Serialize)]
 pub struct NoteTable {
+    #[sqlx(try_from = "i64")]
     pub id: u32,
+    #[sqlx(try_from = "i64")]
     pub org_id: u32,
     pub category: Option<DeterministicallyEncryptedString>,
     #[sqlx(rename = "title")]
     pub enc_title: EncryptedString,
     #[sqlx(rename = "body")]
     pub enc_body: EncryptedString,
+    #[sqlx(rename = "summary")]
+    pub enc_summary: Option<EncryptedString>,
     pub edek: String,
     pub created: String,
     pub updated: String,
 }
 
-/// Non-encryption parts of a note
-#[derive(Clone, Debug, Serialize)]
-pub struct NoteMetadata {
-    pub id: u32,
-    pub org_id: u32,
-    pub created: String,
-    pub updated: String,
-}
-
-#[derive(Debug, Default, Clone, FromRow, Serialize)]
-pub struct Note {
-    pub id: u32,
-    pub category: Option<String>,
-    pub title: String,
-    pub body: String,
+/// The parts of a note stored alongside it in the search index so searches can be filtered on them.
+#[derive(Clone, Debug, FromRow)]
+pub struct NoteIndexFields {
+    pub category: Option<DeterministicallyEncryptedString>,
     pub created: String,
     pub updated: String,
-    pub attachments: Vec<AttachmentInfo>,
 }
 
 #[derive(Debug, FromRow, Serialize, Clone)]
 pub struct OrganizationTable {
+    #[sqlx(try_from = "i64")]
     pub id: u32,
     pub login: String,
     pub name: String,
     pub created: String,
     pub updated: String,
+    /// Overrides of the server's chat model settings. `NULL` uses the server's.
+    pub chat_provider: Option<String>,
+    pub chat_model: Option<String>,
+    pub chat_temperature: Option<f32>,
+    pub chat_max_tokens: Option<i64>,
+}
+
+/// One saved version of an organization's prompt template.
+#[derive(Debug, FromRow, Serialize, Clone)]
+pub struct PromptTemplateTable {
+    #[sqlx(try_from = "i64")]
+    pub version: u32,
+    pub system: String,
+    pub note_format: String,
+    pub answer_style: String,
+    pub created: String,
 }
 
 pub struct EncryptedNote {
@@ -117,654 +144,147 @@ pub struct EncryptedNote {
     pub edek: String,
 }
 
-#[derive(Debug, Type, Deserialize, FromRow)]
+#[derive(Debug, Deserialize, FromRow)]
 pub struct OnlyCategory {
-    category: DeterministicallyEncryptedString,
+    pub category: DeterministicallyEncryptedString,
 }
 
-#[derive(Debug, Type, Deserialize, FromRow)]
+#[derive(Debug, Deserialize, FromRow)]
 pub struct OnlyEdek {
     pub edek: EncryptedString,
 }
 
-async fn create_attachment_vec(
-    aws_sdk: aws_sdk_s3::Client,
-    org: &CurrentOrganization,
-    attachment_tables: Vec<AttachmentTable>,
-) -> Result<Vec<AttachmentInfo>> {
-    join_all(attachment_tables.into_iter().map(|attachment| {
-        create_attachment_info(aws_sdk.clone(), org, attachment.filename, attachment.id)
-    }))
-    .await
-    .into_iter()
-    .collect::<Result<_, _>>()
-}
-
-async fn create_attachment_info(
-    aws_sdk: aws_sdk_s3::Client,
-    org: &CurrentOrganization,
-    filename: String,
-    attachment_id: u32,
-) -> Result<AttachmentInfo> {
-    let content_type = if filename.ends_with(".jpg") {
-        Some("image/jpeg".to_string())
-    } else {
-        None
-    };
-    let presigned_request = aws_sdk
-        .get_object()
-        .bucket("icl-demo-notes-app")
-        .key(format!("{}/{}-{}", org.0.login, attachment_id, filename))
-        .set_response_content_type(content_type)
-        .presigned(PresigningConfig::expires_in(Duration::from_secs(9999))?)
-        .await?;
-
-    Ok(AttachmentInfo {
-        id: attachment_id,
-        filename: filename.to_string(),
-        url: presigned_request.uri().to_string(),
-    })
-}
-
-async fn update_attachments(
-    trx: &mut Transaction<'static, Sqlite>,
-    attachments_to_update: Vec<u32>,
-    note_id: u32,
-) -> Result<Vec<AttachmentTable>> {
-    let mut updated_attachments: Vec<AttachmentTable> =
-        Vec::with_capacity(attachments_to_update.len());
-    for attachment_id in attachments_to_update.iter() {
-        let res = sqlx::query_as::<_, AttachmentTable>(
-            "UPDATE attachment SET note_id = $1 WHERE id = $2 RETURNING *",
-        )
-        .bind(note_id)
-        .bind(attachment_id)
-        .fetch_optional(&mut **trx)
-        .await?;
-
-        if let Some(attachment) = res {
-            updated_attachments.push(attachment);
-        };
-    }
-    Ok(updated_attachments)
-}
-
-async fn get_attachments_and_create_info(
-    note: Note,
-    org: &CurrentOrganization,
-    pool: &SqlitePool,
-    aws_sdk: aws_sdk_s3::Client,
-) -> Result<Note> {
-    let mut conn = pool.acquire().await?;
-    let attachment_tables =
-        sqlx::query_as::<_, AttachmentTable>("SELECT * FROM attachment WHERE note_id = $1")
-            .bind(note.id)
-            .fetch_all(&mut *conn)
-            .await?;
-
-    let attachments = create_attachment_vec(aws_sdk, org, attachment_tables).await?;
-
-    Ok(Note {
-        attachments,
-        ..note
-    })
-}
-
-async fn encrypt_note(
-    note: CreateNoteRequest,
-    organization: CurrentOrganization,
-    sdk: Arc<SaasShield>,
-) -> Result<EncryptedNote> {
-    let metadata = AlloyMetadata::new_simple(TenantId(organization.0.login));
-    let plaintext_document = PlaintextDocument(
-        [
-            (
-                FieldId("title".to_string()),
-                PlaintextBytes(note.title.into_bytes()),
-            ),
-            (
-                FieldId("body".to_string()),
-                PlaintextBytes(note.body.into_bytes()),
-            ),
-        ]
-        .into(),
-    );
-    let mut encrypted = sdk
-        .standard()
-        .encrypt(plaintext_document, &metadata)
-        .await?;
-
-    let enc_category = encrypt_category(note.category, sdk, &metadata).await?;
-    let enc_title = encrypted
-        .document
-        .remove(&FieldId("title".to_string()))
-        .ok_or(anyhow!("ironcore_alloy didn't encrypt this field"))?;
-    let enc_body = encrypted
-        .document
-        .remove(&FieldId("body".to_string()))
-        .ok_or(anyhow!("ironcore_alloy didn't encrypt this field"))?;
-    let edek = STANDARD.encode(encrypted.edek.0);
-    Ok(EncryptedNote {
-        title: EncryptedString::new(enc_title),
-        body: EncryptedString::new(enc_body),
-        category: enc_category,
-        edek,
-    })
-}
-
-async fn encrypt_category(
-    category: Option<String>,
-    sdk: Arc<SaasShield>,
-    metadata: &AlloyMetadata,
-) -> Result<Option<DeterministicallyEncryptedString>> {
-    let plaintext_category = category.map(|category| PlaintextField {
-        plaintext_field: PlaintextBytes(category.into_bytes()),
-        secret_path: SecretPath("".to_string()),
-        derivation_path: DerivationPath("note/category".to_string()),
-    });
-    let result = match plaintext_category {
-        Some(category) => Some(DeterministicallyEncryptedString::new(
-            sdk.deterministic()
-                .encrypt(category, metadata)
-                .await?
-                .encrypted_field,
-        )),
-        None => None,
-    };
-    Ok(result)
-}
-
-async fn decrypt_note(
-    row: NoteTable,
-    sdk: Arc<SaasShield>,
-    metadata: &AlloyMetadata,
-) -> Result<Note> {
-    let enc_document = EncryptedDocument {
-        edek: EdekWithKeyIdHeader(EncryptedBytes(STANDARD.decode(row.edek)?)),
-        document: [
-            (FieldId("title".to_string()), row.enc_title.to_enc_bytes()?),
-            (FieldId("body".to_string()), row.enc_body.to_enc_bytes()?),
-        ]
-        .into(),
-    };
-    let mut decrypted = sdk.standard().decrypt(enc_document, metadata).await?;
-    let dec_title = decrypted
-        .0
-        .remove(&FieldId("title".to_string()))
-        .ok_or(anyhow!("ironcore_alloy didn't decrypt this field"))?;
-    let dec_body = decrypted
-        .0
-        .remove(&FieldId("body".to_string()))
-        .ok_or(anyhow!("ironcore_alloy didn't decrypt this field"))?;
-    let dec_category = match row.category {
-        Some(category) => Some(String::from_utf8(
-            sdk.deterministic()
-                .decrypt(
-                    EncryptedField {
-                        encrypted_field: category.to_enc_bytes()?,
-                        secret_path: SecretPath("".to_string()),
-                        derivation_path: DerivationPath("note/category".to_string()),
-                    },
-                    metadata,
-                )
-                .await?
-                .plaintext_field
-                .0,
-        )?),
-        None => None,
-    };
-
-    Ok(Note {
-        id: row.id,
-        category: dec_category,
-        title: String::from_utf8(dec_title.0)?,
-        body: String::from_utf8(dec_body.0)?,
-        created: row.created,
-        updated: row.updated,
-        attachments: vec![],
-    })
-}
-
-async fn decrypt_notes(
-    rows: Vec<NoteTable>,
-    sdk: Arc<SaasShield>,
-    metadata: &AlloyMetadata,
-) -> Result<Vec<Note>> {
-    let ids = rows.iter().map(|row| row.id).collect_vec();
-    let (std_enc_documents, det_enc_documents, notes_metadata) = rows.into_iter().try_fold(
-        (HashMap::new(), HashMap::new(), HashMap::new()),
-        |(mut std_enc_map, mut det_enc_map, mut metadata_map), row| {
-            std_enc_map.insert(
-                DocumentId(row.id.to_string()),
-                EncryptedDocument {
-                    edek: EdekWithKeyIdHeader(EncryptedBytes(STANDARD.decode(row.edek)?)),
-                    document: [
-                        (FieldId("title".to_string()), row.enc_title.to_enc_bytes()?),
-                        (FieldId("body".to_string()), row.enc_body.to_enc_bytes()?),
-                    ]
-                    .into(),
-                },
-            );
-            if let Some(category) = row.category {
-                det_enc_map.insert(
-                    FieldId(row.id.to_string()),
-                    EncryptedField {
-                        encrypted_field: category.to_enc_bytes()?,
-                        secret_path: SecretPath("".to_string()),
-                        derivation_path: DerivationPath("note/category".to_string()),
-                    },
-                );
-            };
-            metadata_map.insert(
-                row.id,
-                NoteMetadata {
-                    id: row.id,
-                    org_id: row.org_id,
-                    created: row.created,
-                    updated: row.updated,
-                },
-            );
-            Ok::<_, anyhow::Error>((std_enc_map, det_enc_map, metadata_map))
-        },
-    )?;
-    let mut std_decrypted_docs = sdk
-        .standard()
-        .decrypt_batch(EncryptedDocuments(std_enc_documents), metadata)
-        .await?;
-    let mut dec_categories = sdk
-        .deterministic()
-        .decrypt_batch(EncryptedFields(det_enc_documents), metadata)
-        .await?;
-
-    let (mut successes, failures): (HashMap<_, _>, Vec<_>) = notes_metadata
-        .into_iter()
-        .map(|(doc_id, metadata)| {
-            let mut std_dec_data = std_decrypted_docs
-                .successes
-                .0
-                .remove(&DocumentId(doc_id.to_string()))
-                .ok_or(anyhow!(
-                    "ironcore_alloy couldn't decrypt note with ID `{}`",
-                    doc_id
-                ))?;
-            let dec_title = std_dec_data
-                .0
-                .remove(&FieldId("title".to_string()))
-                .ok_or(anyhow!(
-                    "ironcore_alloy couldn't decrypt title of note with ID `{}`",
-                    doc_id
-                ))?;
-            let dec_body = std_dec_data
-                .0
-                .remove(&FieldId("body".to_string()))
-                .ok_or(anyhow!(
-                    "ironcore_alloy couldn't decrypt body of note with ID `{}`",
-                    doc_id
-                ))?;
-            let maybe_category = match dec_categories
-                .successes
-                .0
-                .remove(&FieldId(doc_id.to_string()))
-            {
-                Some(category) => {
-                    Some(String::from_utf8(category.plaintext_field.0).map_err(|_| {
-                        anyhow!(
-                            "ironcore_alloy couldn't decrypt category of note with ID `{}`",
-                            doc_id
-                        )
-                    })?)
-                }
-                None => None,
-            };
-
-            Ok::<_, anyhow::Error>((
-                doc_id,
-                Note {
-                    id: doc_id,
-                    category: maybe_category,
-                    title: String::from_utf8(dec_title.0)?,
-                    body: String::from_utf8(dec_body.0)?,
-                    created: metadata.created,
-                    updated: metadata.updated,
-                    attachments: vec![], // this gets filled in later
-                },
-            ))
-        })
-        .partition_result();
-    failures
-        .into_iter()
-        .chain(
-            std_decrypted_docs
-                .failures
-                .into_values()
-                .chain(dec_categories.failures.into_values())
-                .map(|e| e.into()),
-        )
-        .for_each(|failure| warn!("Failed to decrypt note on list call: {}", failure));
-    let sorted_result = ids
-        .into_iter()
-        .flat_map(|id| successes.remove(&id))
-        .collect();
-    Ok(sorted_result)
-}
-
-pub async fn create_attachment(
-    pool: &SqlitePool,
-    attachment: CreateAttachmentRequest,
-    org: &CurrentOrganization,
-    aws_sdk: aws_sdk_s3::Client,
-) -> Result<CreateAttachmentResponse> {
-    let mut trx = pool.begin().await?;
-    let new_attachment = sqlx::query_as::<_, AttachmentTable>(
-        "INSERT INTO attachment (filename) VALUES ($1) RETURNING *",
-    )
-    .bind(attachment.filename)
-    .fetch_one(&mut *trx)
-    .await?;
-    trx.commit().await?;
-
-    let presigned_request = aws_sdk
-        .put_object()
-        .bucket("icl-demo-notes-app")
-        .key(format!(
-            "{}/{}-{}",
-            org.0.login, new_attachment.id, new_attachment.filename
-        ))
-        .presigned(PresigningConfig::expires_in(Duration::from_secs(9999))?)
-        .await?;
-
-    let info =
-        create_attachment_info(aws_sdk, org, new_attachment.filename, new_attachment.id).await?;
-
-    Ok(CreateAttachmentResponse {
-        filename: info.filename,
-        id: info.id,
-        note_id: new_attachment.note_id,
-        presigned_put_url: presigned_request.uri().to_string(),
-        url: info.url,
-    })
+/// The current time in SQLite's `current_timestamp` format, which the timestamp columns use on every backend.
+pub fn current_timestamp() -> String {
+    chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
 }
 
-pub async fn create_note(
-    pool: &SqlitePool,
-    note: CreateNoteRequest,
+/// The organization's current prompt template, or `None` if it has never saved one.
+pub async fn get_prompt_template(
+    pool: &DbPool,
     organization: &CurrentOrganization,
-    sdk: Arc<SaasShield>,
-    aws_sdk: aws_sdk_s3::Client,
-) -> Result<Note> {
-    let mut trx = pool.begin().await?;
-    let encrypted_note = encrypt_note(note.clone(), organization.clone(), sdk).await?;
-    let res = sqlx::query_as::<_, NoteTable>(
-        "INSERT INTO note (org_id, title, body, category, edek) VALUES ($1, $2, $3, $4, $5) RETURNING *",
-    )
-    .bind(organization.0.id)
-    .bind(encrypted_note.title)
-    .bind(encrypted_note.body)
-    .bind(encrypted_note.category)
-    .bind(encrypted_note.edek)
-    .fetch_one(&mut *trx)
-    .await?;
-
-    let mut updated_attachments: Vec<AttachmentTable> = Vec::with_capacity(note.attachments.len());
-    for attachment_id in note.attachments.iter() {
-        let res = sqlx::query_as::<_, AttachmentTable>(
-            "UPDATE attachment SET note_id = $1 WHERE id = $2 RETURNING *",
+) -> Result<Option<PromptTemplateTable>> {
+    Ok(with_pool!(pool, |pool| {
+        sqlx::query_as::<_, PromptTemplateTable>(
+            "SELECT version, system, note_format, answer_style, created FROM prompt_template WHERE org_id = $1 ORDER BY version DESC LIMIT 1",
         )
-        .bind(res.id)
-        .bind(attachment_id)
-        .fetch_one(&mut *trx)
-        .await?;
-
-        updated_attachments.push(res);
-    }
-    trx.commit().await?;
-
-    let attachments = create_attachment_vec(aws_sdk, organization, updated_attachments).await?;
-
-    Ok(Note {
-        id: res.id,
-        category: note.category,
-        title: note.title,
-        body: note.body,
-        created: res.created,
-        updated: res.updated,
-        attachments,
-    })
-}
-
-pub async fn update_note(
-    pool: &SqlitePool,
-    note: UpdateNoteRequest,
-    id: u32,
-    organization: &CurrentOrganization,
-    sdk: Arc<SaasShield>,
-    aws_sdk: aws_sdk_s3::Client,
-) -> Result<Note> {
-    let mut trx = pool.begin().await?;
-    let encrypted_note = encrypt_note(note.clone(), organization.clone(), sdk).await?;
-    let res = sqlx::query_as::<_, NoteTable>(
-        "UPDATE note SET title = $1, body = $2, category = $3, edek = $4, updated = (SELECT current_timestamp) WHERE id = $5 AND org_id = $6 RETURNING *",
-    )
-    .bind(encrypted_note.title)
-    .bind(encrypted_note.body)
-    .bind(encrypted_note.category)
-    .bind(encrypted_note.edek)
-    .bind(id)
-    .bind(organization.0.id)
-    .fetch_one(&mut *trx)
-    .await?;
-
-    // clear all the existing attachments
-    sqlx::query("UPDATE attachment SET note_id = NULL WHERE note_id=$1")
-        .bind(res.id)
-        .execute(&mut *trx)
-        .await?;
-
-    let updated_attachments = update_attachments(&mut trx, note.attachments, res.id).await?;
-
-    trx.commit().await?;
-
-    let attachments: Vec<AttachmentInfo> =
-        create_attachment_vec(aws_sdk, organization, updated_attachments).await?;
-
-    Ok(Note {
-        id: res.id,
-        category: note.category,
-        title: note.title,
-        body: note.body,
-        created: res.created,
-        updated: res.updated,
-        attachments,
-    })
+        .bind(i64::from(organization.0.id))
+        .fetch_optional(pool)
+        .await?
+    }))
 }
 
-pub async fn get_note(
-    pool: &SqlitePool,
-    id: u32,
+/// A single saved version of the organization's prompt template.
+pub async fn get_prompt_template_version(
+    pool: &DbPool,
     organization: &CurrentOrganization,
-    sdk: Arc<SaasShield>,
-    aws_sdk: aws_sdk_s3::Client,
-) -> Result<Option<Note>> {
-    let mut conn = pool.acquire().await?;
-    match sqlx::query_as::<_, NoteTable>("SELECT * FROM note WHERE id = $1 AND org_id = $2")
-        .bind(id)
-        .bind(organization.0.id)
-        .fetch_optional(&mut *conn)
+    version: u32,
+) -> Result<Option<PromptTemplateTable>> {
+    Ok(with_pool!(pool, |pool| {
+        sqlx::query_as::<_, PromptTemplateTable>(
+            "SELECT version, system, note_format, answer_style, created FROM prompt_template WHERE org_id = $1 AND version = $2",
+        )
+        .bind(i64::from(organization.0.id))
+        .bind(i64::from(version))
+        .fetch_optional(pool)
         .await?
-    {
-        Some(row) => {
-            let decrypted_note = decrypt_note(
-                row,
-                sdk.clone(),
-                &AlloyMetadata::new_simple(TenantId(organization.0.login.clone())),
-            )
-            .await?;
-            get_attachments_and_create_info(decrypted_note, organization, pool, aws_sdk)
-                .await
-                .map(Some)
-        }
-        None => Ok(None),
-    }
+    }))
 }
 
-pub async fn get_edek(
-    pool: &SqlitePool,
-    id: u32,
+/// Every saved version of the organization's prompt template, newest first.
+pub async fn list_prompt_templates(
+    pool: &DbPool,
     organization: &CurrentOrganization,
-) -> Result<Option<OnlyEdek>> {
-    let mut conn = pool.acquire().await?;
-    Ok(
-        sqlx::query_as::<_, OnlyEdek>("SELECT edek FROM note WHERE id = $1 AND org_id = $2")
-            .bind(id)
-            .bind(organization.0.id)
-            .fetch_optional(&mut *conn)
-            .await?,
-    )
+) -> Result<Vec<PromptTemplateTable>> {
+    Ok(with_pool!(pool, |pool| {
+        sqlx::query_as::<_, PromptTemplateTable>(
+            "SELECT version, system, note_format, answer_style, created FROM prompt_template WHERE org_id = $1 ORDER BY version DESC",
+        )
+        .bind(i64::from(organization.0.id))
+        .fetch_all(pool)
+        .await?
+    }))
 }
 
-pub async fn put_edek(
-    pool: &SqlitePool,
-    id: u32,
+/// Saves the template as the organization's next version, which becomes the one in use.
+pub async fn put_prompt_template(
+    pool: &DbPool,
     organization: &CurrentOrganization,
-    edek: EncryptedString,
-) -> Result<u32> {
-    let mut conn = pool.acquire().await?;
-
-    let result = sqlx::query("UPDATE note SET edek=$1 WHERE id = $2 AND org_id = $3")
-        .bind(edek.0)
-        .bind(id)
-        .bind(organization.0.id)
-        .execute(&mut *conn)
+    template: &PromptTemplate,
+) -> Result<PromptTemplateTable> {
+    Ok(with_pool!(pool, |pool| {
+        let mut trx = pool.begin().await?;
+        let version = sqlx::query_scalar::<_, i64>(
+            "SELECT COALESCE(MAX(version), 0) + 1 FROM prompt_template WHERE org_id = $1",
+        )
+        .bind(i64::from(organization.0.id))
+        .fetch_one(&mut *trx)
         .await?;
-    Ok(result.rows_affected() as u32)
-}
-
-pub async fn list_notes(
-    pool: &SqlitePool,
-    org: CurrentOrganization,
-    sdk: Arc<SaasShield>,
-    category: Option<String>,
-    aws_sdk: aws_sdk_s3::Client,
-) -> Result<Vec<Note>> {
-    let metadata = AlloyMetadata::new_simple(TenantId(org.0.login.clone()));
-    let mut conn = pool.acquire().await?;
-    let query = match encrypt_category(category, sdk.clone(), &metadata).await? {
-        Some(cat) => {
-            sqlx::query_as::<_, NoteTable>(
-            "SELECT * FROM note WHERE note.org_id=$1 AND note.category IS NOT NULL AND note.category=$2",
+        let saved = sqlx::query_as::<_, PromptTemplateTable>(
+            "INSERT INTO prompt_template (org_id, version, system, note_format, answer_style) VALUES ($1, $2, $3, $4, $5) RETURNING version, system, note_format, answer_style, created",
         )
-        .bind(org.0.id)
-        .bind(cat.0)
-        }
-        None => {
-            sqlx::query_as::<_, NoteTable>("SELECT * FROM note WHERE note.org_id=$1").bind(org.0.id)
-        }
-    };
-
-    let db_result = query.fetch_all(&mut *conn).await?;
-    let decrypted_notes = decrypt_notes(db_result, sdk, &metadata).await?;
-
-    let result = join_all(
-        decrypted_notes
-            .into_iter()
-            .map(|note| get_attachments_and_create_info(note, &org, pool, aws_sdk.clone())),
-    )
-    .await
-    .into_iter()
-    .collect::<Result<_>>()?;
-    Ok(result)
-}
-
-pub async fn search_notes(
-    pool: &SqlitePool,
-    ids: Vec<u32>,
-    org: &CurrentOrganization,
-    sdk: Arc<SaasShield>,
-    aws_sdk: aws_sdk_s3::Client,
-) -> Result<Vec<Note>> {
-    let mut conn = pool.acquire().await?;
-
-    let parameters = ids.iter().map(|_| "?").collect::<Vec<&str>>().join(", ");
-    let sql = format!(
-        "SELECT * FROM note WHERE note.org_id=? AND note.id IN ({})",
-        parameters
-    );
-    let mut query = sqlx::query_as::<_, NoteTable>(&sql).bind(org.0.id);
-    for id in &ids {
-        query = query.bind(id);
-    }
-
-    let db_result = query.fetch_all(&mut *conn).await?;
-    let metadata = AlloyMetadata::new_simple(TenantId(org.0.login.clone()));
-    let decrypted_notes = decrypt_notes(db_result, sdk, &metadata).await?;
-    let result = join_all(
-        decrypted_notes
-            .into_iter()
-            .map(|note| get_attachments_and_create_info(note, org, pool, aws_sdk.clone())),
-    )
-    .await
-    .into_iter()
-    .collect::<Result<Vec<_>>>()?;
-    let mut hashmap_result = result
-        .into_iter()
-        .map(|note| (note.id, note))
-        .collect::<HashMap<_, _>>();
-    let sorted_result = ids
-        .into_iter()
-        .flat_map(|id| hashmap_result.remove(&id))
-        .collect();
-    Ok(sorted_result)
+        .bind(i64::from(organization.0.id))
+        .bind(version)
+        .bind(&template.system)
+        .bind(&template.note_format)
+        .bind(&template.answer_style)
+        .fetch_one(&mut *trx)
+        .await?;
+        trx.commit().await?;
+        saved
+    }))
 }
 
 pub async fn get_organization(
-    pool: &SqlitePool,
+    pool: &DbPool,
     login: &str,
 ) -> Result<Option<OrganizationTable>, sqlx::Error> {
-    let mut conn = pool.acquire().await?;
-    sqlx::query_as::<_, OrganizationTable>("SELECT * FROM organization WHERE login = $1")
-        .bind(login)
-        .fetch_optional(&mut *conn)
-        .await
+    with_pool!(pool, |pool| {
+        sqlx::query_as::<_, OrganizationTable>("SELECT * FROM organization WHERE login = $1")
+            .bind(login)
+            .fetch_optional(pool)
+            .await
+    })
 }
 
-pub async fn list_categories(
-    pool: &SqlitePool,
-    org: CurrentOrganization,
-    sdk: Arc<SaasShield>,
-) -> Result<Vec<String>> {
-    let mut conn = pool.acquire().await?;
-    let result = sqlx::query_as::<_, OnlyCategory>(
-        "SELECT DISTINCT n.category FROM organization AS o JOIN note AS n ON n.org_id = o.id WHERE o.id=$1 AND n.category IS NOT NULL",
-    )
-    .bind(org.0.id)
-    .fetch_all(&mut *conn)
-    .await?;
-
-    let encrypted_fields: HashMap<_, _> = (0..)
-        .zip(result.into_iter().map(|c| c.category))
-        .map(|(n, cat)| -> Result<_> { Ok((FieldId(n.to_string()), create_encrypted_field(cat)?)) })
-        .collect::<Result<_, _>>()?;
-    let decrypted = sdk
-        .deterministic()
-        .decrypt_batch(
-            EncryptedFields(encrypted_fields),
-            &AlloyMetadata::new_simple(TenantId(org.0.login)),
+pub async fn create_organization(
+    pool: &DbPool,
+    login: &str,
+    name: &str,
+) -> Result<OrganizationTable> {
+    Ok(with_pool!(pool, |pool| {
+        sqlx::query_as::<_, OrganizationTable>(
+            "INSERT INTO organization (login, name) VALUES ($1, $2) RETURNING *",
         )
-        .await?;
-    let decrypted_strings = decrypted
-        .successes
-        .0
-        .into_values()
-        .map(|field_value| String::from_utf8(field_value.plaintext_field.0).unwrap())
-        .sorted()
-        .collect();
-    Ok(decrypted_strings)
+        .bind(login)
+        .bind(name)
+        .fetch_one(pool)
+        .await?
+    }))
 }
 
-fn create_encrypted_field(d: DeterministicallyEncryptedString) -> Result<EncryptedField> {
-    Ok(EncryptedField {
-        encrypted_field: d.to_enc_bytes()?,
-        secret_path: SecretPath("".to_string()),
-        derivation_path: DerivationPath("note/category".to_string()),
-    })
+/// Sets all of an organization's chat model overrides at once, `NULL` for any left to the server's settings.
+pub async fn set_organization_chat_model(
+    pool: &DbPool,
+    org_id: u32,
+    provider: Option<&str>,
+    model: Option<&str>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+) -> Result<()> {
+    with_pool!(pool, |pool| {
+        sqlx::query(
+            "UPDATE organization SET chat_provider = $1, chat_model = $2, chat_temperature = $3, \
+             chat_max_tokens = $4, updated = $5 WHERE id = $6",
+        )
+        .bind(provider)
+        .bind(model)
+        .bind(temperature)
+        .bind(max_tokens.map(i64::from))
+        .bind(current_timestamp())
+        .bind(i64::from(org_id))
+        .execute(pool)
+        .await?;
+    });
+    Ok(())
 }
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use sqlx::{
+    migrate::{MigrateDatabase, Migrator},
+    postgres::{PgPool, Postgres},
+    sqlite::{Sqlite, SqliteConnectOptions, SqliteJournalMode, SqlitePool},
+};
+use std::{path::Path, str::FromStr};
+use tracing::info;
+
+const DEFAULT_DATABASE_URL: &str = "sqlite://sqlite.db";
+
+/// The database the server stores its data in. SQLite is used by default, Postgres lets several servers share one
+/// database.
+#[derive(Clone, Debug)]
+pub enum DbPool {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+}
+
+/// Runs `$body` with `$pool` bound to whichever backend's pool this is. The body is compiled once per backend, so any
+/// SQL in it has to work on both.
+macro_rules! with_pool {
+    ($db:expr, |$pool:ident| $body:expr) => {
+        match $db {
+            $crate::db_pool::DbPool::Sqlite($pool) => $body,
+            $crate::db_pool::DbPool::Postgres($pool) => $body,
+        }
+    };
+}
+pub(crate) use with_pool;
+
+impl DbPool {
+    /// Connects to the database in `DATABASE_URL`, which picks the backend by its scheme: `sqlite://` or
+    /// `postgres://`. The database is created if it doesn't exist.
+    pub async fn from_env() -> Result<DbPool> {
+        let url = std::env::var("DATABASE_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .unwrap_or_else(|| DEFAULT_DATABASE_URL.to_string());
+        DbPool::connect(&url).await
+    }
+
+    pub async fn connect(url: &str) -> Result<DbPool> {
+        if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            if !Postgres::database_exists(url).await? {
+                info!("Creating Postgres database");
+                Postgres::create_database(url).await?;
+            }
+            Ok(DbPool::Postgres(PgPool::connect(url).await?))
+        } else {
+            if !Sqlite::database_exists(url).await.unwrap_or(false) {
+                info!("Creating database {}", url);
+                Sqlite::create_database(url).await?;
+            }
+            let options =
+                SqliteConnectOptions::from_str(url)?.journal_mode(SqliteJournalMode::Delete);
+            Ok(DbPool::Sqlite(SqlitePool::connect_with(options).await?))
+        }
+    }
+
+    /// Each backend has its own set of migrations, under `migrations/sqlite` and `migrations/postgres`. Postgres
+    /// locks the migrations table while they run, so several servers can start at once.
+    pub async fn run_migrations(&self) -> Result<()> {
+        let migrator = self.migrator().await?;
+        with_pool!(self, |pool| migrator.run(pool).await?);
+        Ok(())
+    }
+
+    /// Runs only the migrations older than `version`, to set up a database from before it.
+    #[cfg(test)]
+    pub(crate) async fn run_migrations_before(&self, version: i64) -> Result<()> {
+        let mut migrator = self.migrator().await?;
+        migrator.migrations = migrator
+            .iter()
+            .filter(|migration| migration.version < version)
+            .cloned()
+            .collect();
+        with_pool!(self, |pool| migrator.run(pool).await?);
+        Ok(())
+    }
+
+    async fn migrator(&self) -> Result<Migrator> {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR")?;
+        let backend = match self {
+            DbPool::Sqlite(_) => "sqlite",
+            DbPool::Postgres(_) => "postgres",
+        };
+        Ok(Migrator::new(Path::new(&crate_dir).join("migrations").join(backend)).await?)
+    }
+}
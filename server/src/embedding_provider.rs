@@ -0,0 +1,238 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use ollama_rs::{generation::embeddings::request::GenerateEmbeddingsRequest, Ollama};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::SENTENCE_MODEL_NAME;
+
+/// Turns text into plaintext embedding vectors, which are then encrypted with Cloaked AI before they're indexed.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// Length of every vector this provider produces. The search index's vector fields are created with this size.
+    fn dimensions(&self) -> usize;
+
+    /// The provider and model the vectors come from, such as `ollama/all-minilm`. Vectors from different models
+    /// aren't comparable even when they're the same length.
+    fn model(&self) -> String;
+
+    /// Embeds each input, returning the vectors in the same order.
+    async fn embed(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>>;
+}
+
+/// Picks the provider from `EMBEDDING_PROVIDER`: `ollama` (the default), `openai` for any OpenAI compatible
+/// embeddings endpoint, or `hash` for the offline provider.
+pub fn from_env() -> Result<Arc<dyn EmbeddingProvider>> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let dimensions = var("EMBEDDING_DIMENSIONS")
+        .map(|dimensions| dimensions.parse::<usize>())
+        .transpose()
+        .map_err(|e| anyhow!("EMBEDDING_DIMENSIONS must be a number: {e}"))?;
+    if dimensions == Some(0) {
+        return Err(anyhow!("EMBEDDING_DIMENSIONS must be more than 0."));
+    }
+    let provider: Arc<dyn EmbeddingProvider> =
+        match var("EMBEDDING_PROVIDER").as_deref().unwrap_or("ollama") {
+            "ollama" => Arc::new(OllamaEmbeddings {
+                client: Ollama::default(),
+                model: var("EMBEDDING_MODEL").unwrap_or_else(|| SENTENCE_MODEL_NAME.to_string()),
+                dimensions: dimensions.unwrap_or(384),
+            }),
+            "openai" => Arc::new(OpenAiEmbeddings {
+                client: reqwest::Client::new(),
+                base_url: var("OPENAI_BASE_URL")
+                    .unwrap_or_else(|| "https://api.openai.com/v1".to_string()),
+                api_key: var("OPENAI_API_KEY"),
+                model: var("EMBEDDING_MODEL")
+                    .unwrap_or_else(|| "text-embedding-3-small".to_string()),
+                requested_dimensions: dimensions,
+                dimensions: dimensions.unwrap_or(1536),
+            }),
+            "hash" => Arc::new(HashEmbeddings {
+                dimensions: dimensions.unwrap_or(384),
+            }),
+            other => return Err(anyhow!("Unknown EMBEDDING_PROVIDER `{other}`.")),
+        };
+    Ok(provider)
+}
+
+/// Makes sure a provider returned one vector per input, each as long as the index's vector fields. A model that
+/// doesn't match the configured dimensions would otherwise only fail once Elasticsearch rejects the document.
+fn check_embeddings(
+    provider: &dyn EmbeddingProvider,
+    count: usize,
+    embeddings: Vec<Vec<f32>>,
+) -> Result<Vec<Vec<f32>>> {
+    if embeddings.len() != count {
+        return Err(anyhow!(
+            "`{}` returned {} embeddings for {count} inputs.",
+            provider.model(),
+            embeddings.len()
+        ));
+    }
+    if let Some(wrong) = embeddings
+        .iter()
+        .find(|embedding| embedding.len() != provider.dimensions())
+    {
+        return Err(anyhow!(
+            "`{}` returned {} dimensions instead of {}. Check EMBEDDING_DIMENSIONS.",
+            provider.model(),
+            wrong.len(),
+            provider.dimensions()
+        ));
+    }
+    Ok(embeddings)
+}
+
+/// Embeddings from a model served by the local Ollama.
+pub struct OllamaEmbeddings {
+    client: Ollama,
+    model: String,
+    dimensions: usize,
+}
+
+#[async_trait]
+impl EmbeddingProvider for OllamaEmbeddings {
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn model(&self) -> String {
+        format!("ollama/{}", self.model)
+    }
+
+    async fn embed(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let count = inputs.len();
+        let request = GenerateEmbeddingsRequest::new(self.model.clone(), inputs.into());
+        let embeddings = self.client.generate_embeddings(request).await?.embeddings;
+        check_embeddings(self, count, embeddings)
+    }
+}
+
+/// Embeddings from an endpoint that speaks the OpenAI `/embeddings` API.
+pub struct OpenAiEmbeddings {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    /// Only sent when configured, since not every compatible endpoint accepts it.
+    requested_dimensions: Option<usize>,
+    dimensions: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAiEmbeddingsRequest<'a> {
+    model: &'a str,
+    input: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiEmbeddingsResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAiEmbeddings {
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn model(&self) -> String {
+        format!("openai/{}", self.model)
+    }
+
+    async fn embed(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let count = inputs.len();
+        let mut request = self
+            .client
+            .post(format!(
+                "{}/embeddings",
+                self.base_url.trim_end_matches('/')
+            ))
+            .json(&OpenAiEmbeddingsRequest {
+                model: &self.model,
+                input: inputs,
+                dimensions: self.requested_dimensions,
+            });
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let mut data = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OpenAiEmbeddingsResponse>()
+            .await?
+            .data;
+        // the API doesn't promise to return the embeddings in order
+        data.sort_by_key(|embedding| embedding.index);
+        let embeddings = data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect();
+        check_embeddings(self, count, embeddings)
+    }
+}
+
+/// Deterministic embeddings computed in process by hashing each word into a bucket of the vector. Texts that share
+/// words end up close together, which is enough to exercise search without a model or network access.
+pub struct HashEmbeddings {
+    dimensions: usize,
+}
+
+impl HashEmbeddings {
+    #[cfg(test)]
+    pub fn new(dimensions: usize) -> HashEmbeddings {
+        HashEmbeddings { dimensions }
+    }
+
+    fn embed_one(&self, input: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimensions];
+        for word in input
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let hash = fnv1a(&word.to_lowercase());
+            let sign = if hash & (1 << 63) == 0 { 1.0 } else { -1.0 };
+            vector[(hash % self.dimensions as u64) as usize] += sign;
+        }
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm == 0.0 {
+            // cosine similarity is undefined for the zero vector, so text without words gets a fixed direction
+            vector[0] = 1.0;
+        } else {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+/// 64-bit FNV-1a, used because its output is stable across platforms and Rust versions.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[async_trait]
+impl EmbeddingProvider for HashEmbeddings {
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn model(&self) -> String {
+        "hash".to_string()
+    }
+
+    async fn embed(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        Ok(inputs.iter().map(|input| self.embed_one(input)).collect())
+    }
+}
@@ -1,93 +1,342 @@
 use crate::{
-    db::Note,
-    notes::{CreateNoteRequest, QueryChatbotRequest, SearchNoteRequest},
-    search_service::Knn,
-    CurrentOrganization, CHATBOT_MODEL_NAME, SENTENCE_MODEL_NAME,
+    chat_model::{ChatModel, ChatModelMessage},
+    embedding_provider::EmbeddingProvider,
+    encryption::Encryptor,
+    field_encryption::{encrypt_records, encrypted_record},
+    note_service::Note,
+    notes::{ChatRole, CreateNoteRequest, QueryChatbotRequest, SearchNoteRequest},
+    prompt_templates::PromptTemplate,
+    search_service::{
+        InvalidSearch, Knn, Page, ATTACHMENT_PASSAGE_VECTOR_FIELD, MAX_NUM_CANDIDATES,
+        MAX_RESULT_WINDOW, PASSAGE_VECTOR_FIELD,
+    },
+    CurrentOrganization,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::{stream, Stream, StreamExt};
 use ironcore_alloy::{
-    vector::{EncryptedVector, PlaintextVector, PlaintextVectors, VectorId, VectorOps},
-    AlloyMetadata, DerivationPath, SaasShield, SecretPath, TenantId,
+    vector::{EncryptedVector, PlaintextVector, PlaintextVectors, VectorId},
+    DerivationPath, SecretPath, TenantId,
 };
 use itertools::Itertools;
-use ollama_rs::{
-    generation::{
-        completion::request::GenerationRequest, embeddings::request::GenerateEmbeddingsRequest,
-    },
-    Ollama,
-};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+/// Query vectors have to be generated with the same derivation path the embeddings were encrypted with.
+const EMBEDDING_DERIVATION_PATH: &str = "note/embedding";
+
+encrypted_record! {
+    /// One of a note's embeddings, of its title, body, or a passage of the body or its attachments' text.
+    struct Embedding => EncryptedEmbedding {
+        vector: Vec<f32> => vector(EMBEDDING_DERIVATION_PATH),
+    }
+}
+
 #[derive(Debug)]
 pub struct EncryptedEmbeddings {
     pub enc_title: EncryptedVector,
     pub enc_body: EncryptedVector,
+    pub enc_passages: Vec<(Passage, EncryptedVector)>,
+    pub enc_attachment_passages: Vec<EncryptedVector>,
+}
+
+/// Tuning for the kNN queries generated from a search.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct KnnOptions {
+    /// Nearest neighbors returned by each vector field.
+    pub k: u32,
+    /// Candidates considered per shard before picking the `k` nearest.
+    pub num_candidates: u32,
+    /// Weight of each vector field's similarity in the score.
+    pub boost: f32,
+    /// Leave out notes whose vectors are less similar than this to the query, see `Knn::similarity`.
+    pub similarity: Option<f32>,
+}
+
+impl Default for KnnOptions {
+    fn default() -> Self {
+        KnnOptions {
+            k: 3,
+            num_candidates: 15,
+            boost: 0.8,
+            similarity: None,
+        }
+    }
+}
+
+impl KnnOptions {
+    /// The page holding every note a query of `fields` vector fields can return, since each contributes its own
+    /// `k` notes.
+    pub fn page(&self, fields: u32) -> Result<Page, InvalidSearch> {
+        if self.num_candidates > MAX_NUM_CANDIDATES {
+            return Err(InvalidSearch::TooManyCandidates);
+        }
+        let max = self.num_candidates.min(MAX_RESULT_WINDOW / fields);
+        if self.k == 0 || self.k > max {
+            return Err(InvalidSearch::KOutOfRange { max });
+        }
+        Ok(Page {
+            from: 0,
+            size: self.k * fields,
+        })
+    }
+}
+
+/// Words in each passage of a note body or its attachments' text. The sentence model only looks at the first couple
+/// hundred tokens of its input, so long text is embedded a passage at a time.
+const PASSAGE_WORDS: usize = 100;
+/// Words shared between neighboring passages, so text that straddles a boundary is still embedded together.
+const PASSAGE_OVERLAP_WORDS: usize = 25;
+
+/// A span of a note's body, as byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Passage {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Passage {
+    /// The passage's text, or `None` if the body has changed such that it no longer lines up.
+    pub fn text<'a>(&self, body: &'a str) -> Option<&'a str> {
+        body.get(self.start..self.end)
+    }
+}
+
+/// Splits text into overlapping passages of whole words.
+pub fn split_passages(body: &str) -> Vec<Passage> {
+    let mut words = vec![];
+    let mut word_start = None;
+    for (i, c) in body.char_indices() {
+        match (c.is_whitespace(), word_start) {
+            (true, Some(start)) => {
+                words.push((start, i));
+                word_start = None;
+            }
+            (false, None) => word_start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(start) = word_start {
+        words.push((start, body.len()));
+    }
+
+    let mut passages = vec![];
+    let mut first_word = 0;
+    while first_word < words.len() {
+        let last_word = (first_word + PASSAGE_WORDS).min(words.len()) - 1;
+        passages.push(Passage {
+            start: words[first_word].0,
+            end: words[last_word].1,
+        });
+        if last_word == words.len() - 1 {
+            break;
+        }
+        first_word += PASSAGE_WORDS - PASSAGE_OVERLAP_WORDS;
+    }
+    passages
+}
+
+/// Rough number of tokens of note text we're willing to put in a chat prompt. The demo model has a small context
+/// window, so notes past this are dropped and a note that doesn't fit on its own is truncated.
+const CHAT_CONTEXT_TOKEN_BUDGET: usize = 1500;
+/// Most notes that will be given to the model as sources for a single answer.
+pub const CHAT_MAX_SOURCES: usize = 5;
+/// Most earlier messages of a conversation that are sent back to the model.
+const CHAT_MAX_HISTORY_MESSAGES: usize = 10;
+/// Characters of a source note's body returned alongside a citation.
+const CITATION_SNIPPET_LENGTH: usize = 160;
+/// Cosine similarity a note has to reach before the chatbot will answer from it, unless overridden with
+/// `CHAT_SIMILARITY_THRESHOLD`.
+const CHAT_SIMILARITY_THRESHOLD: f32 = 0.3;
+/// The chatbot's answer when no note is similar enough to the question. Given without asking the model, which
+/// would otherwise answer confidently from whatever unrelated notes it was handed.
+pub const NO_RELEVANT_NOTES_ANSWER: &str = "I couldn't find any notes relevant to that question.";
+
+/// The least similar a note can be to a chat question and still be given to the model as a source.
+pub fn chat_similarity_threshold_from_env() -> Result<f32> {
+    match std::env::var("CHAT_SIMILARITY_THRESHOLD") {
+        Ok(threshold) if !threshold.is_empty() => threshold
+            .parse::<f32>()
+            .ok()
+            .filter(|threshold| (-1.0..=1.0).contains(threshold))
+            .ok_or_else(|| anyhow!("CHAT_SIMILARITY_THRESHOLD must be a number from -1 to 1.")),
+        _ => Ok(CHAT_SIMILARITY_THRESHOLD),
+    }
+}
+/// Default system prompts, each of which can be overridden by the environment variable of the same name.
+const CHAT_SYSTEM_PROMPT: &str = "You are a chatbot for a note-taking app. Briefly answer the user's question using the numbered notes below. Keep your answer grounded in the facts of the notes, and cite each note you use by its number in square brackets, like [1].";
+const CATEGORY_SYSTEM_PROMPT: &str = "You suggest categories for notes in a note-taking app. Suggest up to 3 short categories for the note below, best first. Strongly prefer reusing one of the existing categories when it fits, spelled exactly as it is listed, and only make up a new category when none of them fit. Respond with JSON like {\"suggestions\": [{\"category\": \"Gardening\", \"confidence\": 0.9}]}, where confidence is between 0 and 1.";
+const SUMMARY_SYSTEM_PROMPT: &str = "You summarize notes for a note-taking app. Write a summary of the note below in one or two short sentences. Only use facts from the note, and respond with just the summary.";
+
+/// The system prompts given to the chat model for each kind of request. These are separate from the `SYSTEM` line
+/// in the Modelfile so they can be tuned without rebuilding the model.
+#[derive(Debug, Clone)]
+pub struct Prompts {
+    pub chat: String,
+    pub category: String,
+    pub summary: String,
 }
 
+impl Prompts {
+    pub fn from_env() -> Prompts {
+        let prompt =
+            |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
+        Prompts {
+            chat: prompt("CHAT_SYSTEM_PROMPT", CHAT_SYSTEM_PROMPT),
+            category: prompt("CATEGORY_SYSTEM_PROMPT", CATEGORY_SYSTEM_PROMPT),
+            summary: prompt("SUMMARY_SYSTEM_PROMPT", SUMMARY_SYSTEM_PROMPT),
+        }
+    }
+}
 #[derive(Debug, Serialize)]
 pub struct QueryChatbotResponse {
     pub response: String,
+    pub citations: Vec<Citation>,
+}
+
+/// Pieces of a streamed chatbot answer, in the order they're produced.
+#[derive(Debug)]
+pub enum ChatStreamEvent {
+    /// The next bit of the answer's text.
+    Token(String),
+    /// The answer is complete; these are the notes it cites.
+    Done(Vec<Citation>),
+    Error(String),
+}
+
+/// A note the chatbot's answer was based on.
+#[derive(Debug, Clone, Serialize)]
+pub struct Citation {
+    /// The `[n]` label the note was given in the prompt, which is how the answer refers to it.
+    pub source: usize,
     pub note_id: u32,
+    pub title: String,
+    /// The passage the answer was drawn from, or the start of the body if the whole note was used.
+    pub snippet: String,
+    pub passage: Option<Passage>,
+    /// The note's search score for the question, `None` for notes carried over from earlier in the conversation.
+    pub score: Option<f32>,
+}
+
+/// A note given to the chatbot, narrowed down to the passage that matched the question if there was one.
+#[derive(Debug, Clone)]
+pub struct ChatSource {
+    pub note: Note,
+    pub passage: Option<Passage>,
+    /// How well the note matched the question, or `None` if it was only included because the conversation had
+    /// already referenced it.
+    pub score: Option<f32>,
+}
+
+impl ChatSource {
+    fn text(&self) -> &str {
+        self.passage
+            .and_then(|passage| passage.text(&self.note.body))
+            .unwrap_or(&self.note.body)
+    }
 }
 
 pub async fn generate_and_encrypt_embedding(
-    ai_sdk: Ollama,
-    sdk: Arc<SaasShield>,
+    embedder: &dyn EmbeddingProvider,
+    sdk: Arc<dyn Encryptor>,
     note: CreateNoteRequest,
+    attachment_text: Option<String>,
     organization: &CurrentOrganization,
 ) -> Result<EncryptedEmbeddings> {
-    let metadata = AlloyMetadata::new_simple(TenantId(organization.0.login.clone()));
-    let request = GenerateEmbeddingsRequest::new(
-        SENTENCE_MODEL_NAME.to_string(),
-        vec![note.body, note.title].into(),
+    let tenant_id = TenantId(organization.0.login.clone());
+    let passage_inputs = |prefix: &str, text: &str| {
+        split_passages(text)
+            .into_iter()
+            .enumerate()
+            .map(|(i, passage)| {
+                (
+                    format!("{prefix}-{i}"),
+                    passage.text(text).map(str::to_string),
+                )
+            })
+            .collect_vec()
+    };
+    let passages = split_passages(&note.body);
+    let body_passage_inputs = passage_inputs("passage", &note.body);
+    // attachments are only embedded a passage at a time, their text is usually too long to embed whole
+    let attachment_passage_inputs = passage_inputs(
+        "attachment-passage",
+        attachment_text.as_deref().unwrap_or_default(),
     );
-    let embedding = ai_sdk.generate_embeddings(request).await?.embeddings;
-    let plaintext_vectors = ["body", "title"]
+    let attachment_passage_count = attachment_passage_inputs.len();
+    let (names, inputs): (Vec<_>, Vec<_>) = [
+        ("body".to_string(), Some(note.body)),
+        ("title".to_string(), Some(note.title)),
+    ]
+    .into_iter()
+    .chain(body_passage_inputs)
+    .chain(attachment_passage_inputs)
+    .filter_map(|(name, input)| input.map(|input| (name, input)))
+    .unzip();
+    let embedding = embedder.embed(inputs).await?;
+    let embeddings = names
         .into_iter()
         .zip(embedding)
-        .map(|(k, v)| {
-            (
-                VectorId(k.to_string()),
-                PlaintextVector {
-                    plaintext_vector: v,
-                    secret_path: SecretPath("".to_string()),
-                    derivation_path: DerivationPath("note/embedding".to_string()),
-                },
-            )
+        .map(|(name, vector)| (name, Embedding { vector }));
+    let mut encrypted = encrypt_records(embeddings, &*sdk, &tenant_id).await?;
+    if let Some((name, failure)) = encrypted.failures.into_iter().next() {
+        return Err(anyhow!(
+            "ironcore_alloy couldn't encrypt the {} embedding: {}",
+            name,
+            failure
+        ));
+    }
+    let mut take = |name: &str| {
+        encrypted
+            .successes
+            .remove(name)
+            .map(|embedding| embedding.vector)
+    };
+    let enc_body = take("body").ok_or_else(|| anyhow!("ironcore_alloy didn't encrypt the body"))?;
+    let enc_title =
+        take("title").ok_or_else(|| anyhow!("ironcore_alloy didn't encrypt the title"))?;
+    let enc_passages = passages
+        .into_iter()
+        .enumerate()
+        .map(|(i, passage)| {
+            take(&format!("passage-{i}"))
+                .map(|vector| (passage, vector))
+                .ok_or_else(|| anyhow!("ironcore_alloy didn't encrypt passage {}", i))
         })
-        .collect();
-    let mut encrypted_embeddings = sdk
-        .vector()
-        .encrypt_batch(PlaintextVectors(plaintext_vectors), &metadata)
-        .await?;
-    let enc_body = encrypted_embeddings
-        .successes
-        .0
-        .remove(&VectorId("body".to_string()))
-        .unwrap();
-    let enc_title = encrypted_embeddings
-        .successes
-        .0
-        .remove(&VectorId("title".to_string()))
-        .unwrap();
+        .collect::<Result<_>>()?;
+    let enc_attachment_passages = (0..attachment_passage_count)
+        .map(|i| {
+            take(&format!("attachment-passage-{i}"))
+                .ok_or_else(|| anyhow!("ironcore_alloy didn't encrypt attachment passage {}", i))
+        })
+        .collect::<Result<_>>()?;
     Ok(EncryptedEmbeddings {
         enc_title,
         enc_body,
+        enc_passages,
+        enc_attachment_passages,
     })
 }
 
 pub async fn generate_query_embeddings(
-    ai_sdk: Ollama,
-    sdk: Arc<SaasShield>,
+    embedder: &dyn EmbeddingProvider,
+    sdk: Arc<dyn Encryptor>,
     search: SearchNoteRequest,
+    options: KnnOptions,
     organization: &CurrentOrganization,
 ) -> Result<Vec<Knn>> {
-    let metadata = AlloyMetadata::new_simple(TenantId(organization.0.login.clone()));
-    let input = search.title.into_iter().chain(search.body).collect_vec();
-    let request = GenerateEmbeddingsRequest::new(SENTENCE_MODEL_NAME.to_string(), input.into());
-    let embedding = ai_sdk.generate_embeddings(request).await?.embeddings;
-    let plaintext_vectors = ["title", "body"]
+    let tenant_id = TenantId(organization.0.login.clone());
+    let (names, input): (Vec<_>, Vec<_>) = [
+        ("title", search.title),
+        ("body", search.body),
+        ("attachment", search.attachment_text),
+    ]
+    .into_iter()
+    .filter_map(|(name, input)| input.map(|input| (name, input)))
+    .unzip();
+    let embedding = embedder.embed(input).await?;
+    let plaintext_vectors = names
         .into_iter()
         .zip(embedding)
         .map(|(field_name, vector)| {
@@ -96,50 +345,392 @@ pub async fn generate_query_embeddings(
                 PlaintextVector {
                     plaintext_vector: vector,
                     secret_path: SecretPath("".to_string()),
-                    derivation_path: DerivationPath("note/embedding".to_string()),
+                    derivation_path: DerivationPath(EMBEDDING_DERIVATION_PATH.to_string()),
                 },
             )
         })
         .collect();
-    Ok(sdk
-        .vector()
-        .generate_query_vectors(PlaintextVectors(plaintext_vectors), &metadata)
+    let knns = sdk
+        .generate_query_vectors(PlaintextVectors(plaintext_vectors), &tenant_id)
         .await?
         .0
         .into_iter()
         .map(|(field_name, mut vector)| Knn {
-            field: format!("{}_vector", field_name.0),
+            // attachments are only embedded a passage at a time
+            field: match field_name.0.as_str() {
+                "attachment" => ATTACHMENT_PASSAGE_VECTOR_FIELD.to_string(),
+                name => format!("{name}_vector"),
+            },
             query_vector: vector.remove(0).encrypted_vector, // we won't be in rotation for the demo
-            num_candidates: 15,
-            k: 3,
-            boost: 0.8,
+            num_candidates: options.num_candidates,
+            k: options.k,
+            boost: options.boost,
+            similarity: options.similarity,
+            filter: vec![],
         })
-        .collect_vec())
+        .collect_vec();
+    // passages are embedded the same way as whole bodies, so the body query vector searches them too
+    let passage_knn = knns
+        .iter()
+        .find(|knn| knn.field == "body_vector")
+        .map(|knn| Knn {
+            field: PASSAGE_VECTOR_FIELD.to_string(),
+            ..knn.clone()
+        });
+    Ok(knns.into_iter().chain(passage_knn).collect_vec())
+}
+
+/// Very rough token estimate, good enough to keep the prompt inside the model's context window.
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Truncates to at most `max_chars` characters, marking the cut with an ellipsis.
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((byte_index, _)) => format!("{}...", &text[..byte_index]),
+        None => text.to_string(),
+    }
+}
+
+/// Takes sources in relevance order until the token budget runs out. The first source is always included,
+/// truncated if it doesn't fit on its own.
+fn select_sources(candidates: Vec<ChatSource>) -> Vec<ChatSource> {
+    let mut remaining = CHAT_CONTEXT_TOKEN_BUDGET;
+    let mut sources = Vec::new();
+    for source in candidates.into_iter().take(CHAT_MAX_SOURCES) {
+        let cost = estimate_tokens(&source.note.title) + estimate_tokens(source.text());
+        if cost <= remaining {
+            remaining -= cost;
+            sources.push(source);
+        } else if sources.is_empty() {
+            let body = truncate_chars(
+                source.text(),
+                remaining.saturating_sub(estimate_tokens(&source.note.title)) * 4,
+            );
+            sources.push(ChatSource {
+                note: Note {
+                    body,
+                    ..source.note
+                },
+                passage: None,
+                score: source.score,
+            });
+            break;
+        } else {
+            break;
+        }
+    }
+    sources
+}
+
+/// Finds the `[n]` source labels the model used in its answer, in the order they first appear.
+fn cited_source_numbers(response: &str, source_count: usize) -> Vec<usize> {
+    response
+        .split('[')
+        .skip(1)
+        .filter_map(|rest| rest.split_once(']'))
+        .filter_map(|(label, _)| label.trim().parse::<usize>().ok())
+        .filter(|number| (1..=source_count).contains(number))
+        .unique()
+        .collect_vec()
+}
+
+/// Builds the conversation sent to the model from the organization's template: a system prompt containing the
+/// labeled source notes, the recent history, then the new question.
+fn build_chat_messages(
+    template: &PromptTemplate,
+    sources: &[ChatSource],
+    request: QueryChatbotRequest,
+) -> Vec<ChatModelMessage> {
+    let labeled_notes = sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| template.render_note(i + 1, &source.note.title, source.text()))
+        .join("\n\n");
+    let recent_history = &request.history[request
+        .history
+        .len()
+        .saturating_sub(CHAT_MAX_HISTORY_MESSAGES)..];
+    let (history_text, history_messages) = if template.includes_history() {
+        let text = recent_history
+            .iter()
+            .map(|message| match message.role {
+                ChatRole::User => format!("user: {}", message.content),
+                ChatRole::Assistant => format!("assistant: {}", message.content),
+            })
+            .join("\n");
+        (text, vec![])
+    } else {
+        let messages = recent_history
+            .iter()
+            .map(|message| match message.role {
+                ChatRole::User => ChatModelMessage::user(message.content.clone()),
+                ChatRole::Assistant => ChatModelMessage::assistant(message.content.clone()),
+            })
+            .collect_vec();
+        (String::new(), messages)
+    };
+    let system_prompt = template.render_system(&labeled_notes, &request.question, &history_text);
+    [ChatModelMessage::system(system_prompt)]
+        .into_iter()
+        .chain(history_messages)
+        .chain([ChatModelMessage::user(request.question)])
+        .collect_vec()
+}
+
+fn citations_for(response: &str, sources: &[ChatSource]) -> Vec<Citation> {
+    // if the model didn't cite anything we can't tell which notes it used, so point at everything it was given
+    let cited = match cited_source_numbers(response, sources.len()) {
+        numbers if numbers.is_empty() => (1..=sources.len()).collect_vec(),
+        numbers => numbers,
+    };
+    cited
+        .into_iter()
+        .map(|number| {
+            let source = &sources[number - 1];
+            let snippet = match source.passage {
+                Some(_) => source.text().to_string(),
+                None => truncate_chars(&source.note.body, CITATION_SNIPPET_LENGTH),
+            };
+            Citation {
+                source: number,
+                note_id: source.note.id,
+                title: source.note.title.clone(),
+                snippet,
+                passage: source.passage,
+                score: source.score,
+            }
+        })
+        .collect()
 }
 
 pub async fn query_chatbot(
-    ai_sdk: Ollama,
-    note: Note,
+    chat_model: &dyn ChatModel,
+    template: &PromptTemplate,
+    candidates: Vec<ChatSource>,
     request: QueryChatbotRequest,
 ) -> Result<QueryChatbotResponse> {
-    let prompt = format!(
-        r###"
-        note:
-        {}: {}
-
-        question:
-        {}
-        "###,
-        note.title, note.body, request.question
-    );
-    let res = ai_sdk
-        .generate(GenerationRequest::new(
-            CHATBOT_MODEL_NAME.to_string(),
-            prompt,
-        ))
-        .await?;
+    if candidates.is_empty() {
+        return Ok(QueryChatbotResponse {
+            response: NO_RELEVANT_NOTES_ANSWER.to_string(),
+            citations: vec![],
+        });
+    }
+    let sources = select_sources(candidates);
+    let messages = build_chat_messages(template, &sources, request);
+    let response = chat_model.complete(messages, false).await?;
+    let citations = citations_for(&response, &sources);
     Ok(QueryChatbotResponse {
-        response: res.response,
-        note_id: note.id,
+        response,
+        citations,
     })
 }
+
+/// Most category suggestions returned for a note.
+const MAX_CATEGORY_SUGGESTIONS: usize = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CategorySuggestion {
+    pub category: String,
+    /// How sure the model is that the category fits, from 0 to 1.
+    pub confidence: f32,
+    /// Whether the category is already used by one of the organization's notes.
+    #[serde(default)]
+    pub existing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CategorySuggestions {
+    suggestions: Vec<CategorySuggestion>,
+}
+
+/// Asks the chat model to categorize a draft note, preferring the organization's existing categories.
+pub async fn suggest_categories(
+    chat_model: &dyn ChatModel,
+    prompts: &Prompts,
+    title: &str,
+    body: &str,
+    existing_categories: &[String],
+) -> Result<Vec<CategorySuggestion>> {
+    let existing = if existing_categories.is_empty() {
+        "(none yet)".to_string()
+    } else {
+        existing_categories.join(", ")
+    };
+    let note = truncate_chars(body, CHAT_CONTEXT_TOKEN_BUDGET * 4);
+    let prompt = format!("existing categories: {existing}\n\ntitle: {title}\nbody: {note}");
+    let response = chat_model
+        .complete(
+            vec![
+                ChatModelMessage::system(prompts.category.clone()),
+                ChatModelMessage::user(prompt),
+            ],
+            true,
+        )
+        .await?;
+    let suggestions = serde_json::from_str::<CategorySuggestions>(&response)
+        .map_err(|e| anyhow!("Chat model returned malformed category suggestions: {e}"))?
+        .suggestions;
+    Ok(rank_category_suggestions(suggestions, existing_categories))
+}
+
+/// Cleans up what the model suggested: existing categories are matched regardless of case and spelled the way
+/// they already are, duplicates are merged, and the rest are sorted by confidence.
+fn rank_category_suggestions(
+    suggestions: Vec<CategorySuggestion>,
+    existing_categories: &[String],
+) -> Vec<CategorySuggestion> {
+    suggestions
+        .into_iter()
+        .filter_map(|suggestion| {
+            let category = suggestion.category.trim();
+            if category.is_empty() {
+                return None;
+            }
+            let existing = existing_categories
+                .iter()
+                .find(|existing| existing.eq_ignore_ascii_case(category));
+            Some(CategorySuggestion {
+                category: existing.map_or(category, |existing| existing).to_string(),
+                confidence: suggestion.confidence.clamp(0.0, 1.0),
+                existing: existing.is_some(),
+            })
+        })
+        .sorted_by(|a, b| b.confidence.total_cmp(&a.confidence))
+        .unique_by(|suggestion| suggestion.category.clone())
+        .take(MAX_CATEGORY_SUGGESTIONS)
+        .collect()
+}
+
+/// Asks the chat model for a short summary of a note.
+pub async fn summarize_note(
+    chat_model: &dyn ChatModel,
+    prompts: &Prompts,
+    note: &Note,
+) -> Result<String> {
+    let body = truncate_chars(&note.body, CHAT_CONTEXT_TOKEN_BUDGET * 4);
+    let summary = chat_model
+        .complete(
+            vec![
+                ChatModelMessage::system(prompts.summary.clone()),
+                ChatModelMessage::user(format!("title: {}\nbody: {body}", note.title)),
+            ],
+            false,
+        )
+        .await?;
+    Ok(summary.trim().to_string())
+}
+
+/// Same as `query_chatbot`, but yields the answer as the model produces it. The last event is always either
+/// `Done` with the citations or an `Error`.
+pub async fn stream_chatbot(
+    chat_model: &dyn ChatModel,
+    template: &PromptTemplate,
+    candidates: Vec<ChatSource>,
+    request: QueryChatbotRequest,
+) -> Result<impl Stream<Item = ChatStreamEvent>> {
+    if candidates.is_empty() {
+        let events = [
+            ChatStreamEvent::Token(NO_RELEVANT_NOTES_ANSWER.to_string()),
+            ChatStreamEvent::Done(vec![]),
+        ];
+        return Ok(stream::iter(events).left_stream());
+    }
+    let sources = select_sources(candidates);
+    let messages = build_chat_messages(template, &sources, request);
+    let responses = chat_model.stream(messages).await?;
+    // the full answer is accumulated as it streams by so the citations can be worked out at the end
+    let events = stream::unfold(
+        Some((responses, String::new(), sources)),
+        |state| async move {
+            let (mut responses, mut answer, sources) = state?;
+            match responses.next().await {
+                Some(Ok(piece)) => {
+                    answer.push_str(&piece);
+                    Some((
+                        ChatStreamEvent::Token(piece),
+                        Some((responses, answer, sources)),
+                    ))
+                }
+                Some(Err(e)) => Some((ChatStreamEvent::Error(e.to_string()), None)),
+                None => {
+                    let citations = citations_for(&answer, &sources);
+                    Some((ChatStreamEvent::Done(citations), None))
+                }
+            }
+        },
+    );
+    Ok(events.right_stream())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` numbered words, separated by varying whitespace.
+    fn words(count: usize) -> String {
+        (0..count)
+            .map(|i| format!("wörd{i}"))
+            .collect::<Vec<_>>()
+            .join(" \n\t")
+    }
+
+    fn passage_words(body: &str) -> Vec<Vec<&str>> {
+        split_passages(body)
+            .iter()
+            .map(|passage| passage.text(body).unwrap().split_whitespace().collect())
+            .collect()
+    }
+
+    #[test]
+    fn blank_text_has_no_passages() {
+        assert!(split_passages("").is_empty());
+        assert!(split_passages(" \n\t ").is_empty());
+    }
+
+    #[test]
+    fn passages_start_and_end_on_words() {
+        let body = format!("  {}\n", words(3));
+        assert_eq!(passage_words(&body), [["wörd0", "wörd1", "wörd2"]]);
+        let passages = split_passages(&body);
+        assert_eq!(passages[0].start, 2);
+        assert_eq!(passages[0].end, body.len() - 1);
+    }
+
+    #[test]
+    fn neighboring_passages_overlap() {
+        let body = words(PASSAGE_WORDS);
+        assert_eq!(passage_words(&body).len(), 1);
+
+        let body = words(PASSAGE_WORDS + 1);
+        let passages = passage_words(&body);
+        assert_eq!(passages.len(), 2);
+        assert_eq!(passages[0].len(), PASSAGE_WORDS);
+        assert_eq!(
+            passages[1].len(),
+            PASSAGE_OVERLAP_WORDS + 1,
+            "the second passage is the overlap plus the one word left"
+        );
+        assert_eq!(
+            passages[0][PASSAGE_WORDS - PASSAGE_OVERLAP_WORDS..],
+            passages[1][..PASSAGE_OVERLAP_WORDS]
+        );
+        assert_eq!(passages[1].last(), Some(&"wörd100"));
+    }
+
+    #[test]
+    fn every_word_is_in_a_passage() {
+        let body = words(3 * PASSAGE_WORDS);
+        let passages = passage_words(&body);
+        let stride = PASSAGE_WORDS - PASSAGE_OVERLAP_WORDS;
+        assert_eq!(passages.len(), (2 * PASSAGE_WORDS).div_ceil(stride) + 1);
+        for (i, passage) in passages.iter().enumerate() {
+            assert_eq!(passage[0], format!("wörd{}", i * stride));
+        }
+        assert_eq!(
+            passages.last().unwrap().last().unwrap().to_string(),
+            format!("wörd{}", 3 * PASSAGE_WORDS - 1)
+        );
+    }
+}
@@ -0,0 +1,704 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use ironcore_alloy::{
+    deterministic::{
+        DeterministicDecryptBatchResult, DeterministicFieldOps, EncryptedField, EncryptedFields,
+        PlaintextField,
+    },
+    saas_shield::config::SaasShieldConfiguration,
+    standalone::config::{
+        RotatableSecret, StandaloneConfiguration, StandaloneSecret, StandardSecrets, VectorSecret,
+    },
+    standard::{
+        EdekWithKeyIdHeader, EncryptedDocument, EncryptedDocuments, PlaintextDocument,
+        PlaintextDocumentWithEdek, RekeyEdeksBatchResult, StandardDecryptBatchResult,
+        StandardDocumentOps,
+    },
+    vector::{GenerateVectorQueryResult, PlaintextVectors, VectorEncryptBatchResult, VectorOps},
+    AlloyMetadata, DocumentId, SaasShield, Secret, SecretPath, Standalone, TenantId,
+};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Arc};
+
+/// The encryption operations the app uses, each done for a single tenant. Implemented by the SaaS Shield and
+/// Standalone SDKs, and in tests by a fake.
+#[async_trait]
+pub trait Encryptor: Send + Sync {
+    /// Standard encryption of a document's fields under a newly generated EDEK.
+    async fn encrypt(
+        &self,
+        document: PlaintextDocument,
+        tenant_id: &TenantId,
+    ) -> Result<EncryptedDocument>;
+
+    /// Standard encryption of more fields of a document under its existing EDEK.
+    async fn encrypt_with_existing_edek(
+        &self,
+        document: PlaintextDocumentWithEdek,
+        tenant_id: &TenantId,
+    ) -> Result<EncryptedDocument>;
+
+    async fn decrypt(
+        &self,
+        document: EncryptedDocument,
+        tenant_id: &TenantId,
+    ) -> Result<PlaintextDocument>;
+
+    async fn decrypt_batch(
+        &self,
+        documents: EncryptedDocuments,
+        tenant_id: &TenantId,
+    ) -> Result<StandardDecryptBatchResult>;
+
+    /// Re-encrypts EDEKs to the tenant's current key.
+    async fn rekey_edeks(
+        &self,
+        edeks: HashMap<DocumentId, EdekWithKeyIdHeader>,
+        tenant_id: &TenantId,
+    ) -> Result<RekeyEdeksBatchResult>;
+
+    /// Deterministic encryption, so the same value always encrypts the same way and can be matched on.
+    async fn encrypt_field(
+        &self,
+        field: PlaintextField,
+        tenant_id: &TenantId,
+    ) -> Result<EncryptedField>;
+
+    async fn decrypt_fields(
+        &self,
+        fields: EncryptedFields,
+        tenant_id: &TenantId,
+    ) -> Result<DeterministicDecryptBatchResult>;
+
+    /// Vector encryption of embeddings before they're indexed.
+    async fn encrypt_vectors(
+        &self,
+        vectors: PlaintextVectors,
+        tenant_id: &TenantId,
+    ) -> Result<VectorEncryptBatchResult>;
+
+    /// Encrypts query embeddings so they can be searched with against the encrypted index.
+    async fn generate_query_vectors(
+        &self,
+        vectors: PlaintextVectors,
+        tenant_id: &TenantId,
+    ) -> Result<GenerateVectorQueryResult>;
+}
+
+fn metadata(tenant_id: &TenantId) -> Arc<AlloyMetadata> {
+    AlloyMetadata::new_simple(tenant_id.clone())
+}
+
+/// The SaaS Shield and Standalone SDKs have the same operations, so they implement `Encryptor` the same way.
+macro_rules! impl_encryptor {
+    ($sdk:ty) => {
+        #[async_trait]
+        impl Encryptor for $sdk {
+            async fn encrypt(
+                &self,
+                document: PlaintextDocument,
+                tenant_id: &TenantId,
+            ) -> Result<EncryptedDocument> {
+                Ok(self
+                    .standard()
+                    .encrypt(document, &metadata(tenant_id))
+                    .await?)
+            }
+
+            async fn encrypt_with_existing_edek(
+                &self,
+                document: PlaintextDocumentWithEdek,
+                tenant_id: &TenantId,
+            ) -> Result<EncryptedDocument> {
+                Ok(self
+                    .standard()
+                    .encrypt_with_existing_edek(document, &metadata(tenant_id))
+                    .await?)
+            }
+
+            async fn decrypt(
+                &self,
+                document: EncryptedDocument,
+                tenant_id: &TenantId,
+            ) -> Result<PlaintextDocument> {
+                Ok(self
+                    .standard()
+                    .decrypt(document, &metadata(tenant_id))
+                    .await?)
+            }
+
+            async fn decrypt_batch(
+                &self,
+                documents: EncryptedDocuments,
+                tenant_id: &TenantId,
+            ) -> Result<StandardDecryptBatchResult> {
+                Ok(self
+                    .standard()
+                    .decrypt_batch(documents, &metadata(tenant_id))
+                    .await?)
+            }
+
+            async fn rekey_edeks(
+                &self,
+                edeks: HashMap<DocumentId, EdekWithKeyIdHeader>,
+                tenant_id: &TenantId,
+            ) -> Result<RekeyEdeksBatchResult> {
+                Ok(self
+                    .standard()
+                    .rekey_edeks(edeks, &metadata(tenant_id), None)
+                    .await?)
+            }
+
+            async fn encrypt_field(
+                &self,
+                field: PlaintextField,
+                tenant_id: &TenantId,
+            ) -> Result<EncryptedField> {
+                Ok(self
+                    .deterministic()
+                    .encrypt(field, &metadata(tenant_id))
+                    .await?)
+            }
+
+            async fn decrypt_fields(
+                &self,
+                fields: EncryptedFields,
+                tenant_id: &TenantId,
+            ) -> Result<DeterministicDecryptBatchResult> {
+                Ok(self
+                    .deterministic()
+                    .decrypt_batch(fields, &metadata(tenant_id))
+                    .await?)
+            }
+
+            async fn encrypt_vectors(
+                &self,
+                vectors: PlaintextVectors,
+                tenant_id: &TenantId,
+            ) -> Result<VectorEncryptBatchResult> {
+                Ok(self
+                    .vector()
+                    .encrypt_batch(vectors, &metadata(tenant_id))
+                    .await?)
+            }
+
+            async fn generate_query_vectors(
+                &self,
+                vectors: PlaintextVectors,
+                tenant_id: &TenantId,
+            ) -> Result<GenerateVectorQueryResult> {
+                Ok(self
+                    .vector()
+                    .generate_query_vectors(vectors, &metadata(tenant_id))
+                    .await?)
+            }
+        }
+    };
+}
+
+impl_encryptor!(SaasShield);
+impl_encryptor!(Standalone);
+
+/// Which SDK to encrypt with, from `ENCRYPTION_MODE`: `saas-shield` (the default) uses the Tenant Security Proxy
+/// with `TSP_API_KEY`, `standalone` derives keys from locally configured secrets so no TSP is needed.
+pub fn from_env() -> Result<Arc<dyn Encryptor>> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let encryptor: Arc<dyn Encryptor> =
+        match var("ENCRYPTION_MODE").as_deref().unwrap_or("saas-shield") {
+            "saas-shield" => {
+                let api_key = var("TSP_API_KEY").ok_or_else(|| {
+                    anyhow!("TSP_API_KEY must be set, or use ENCRYPTION_MODE=standalone.")
+                })?;
+                let config = SaasShieldConfiguration::new(
+                    "http://localhost:32804".to_string(),
+                    api_key,
+                    true,
+                    Some(1.0),
+                )?;
+                SaasShield::new(&config)
+            }
+            "standalone" => Arc::new(StandaloneEncryptor::from_env()?),
+            other => return Err(anyhow!("Unknown ENCRYPTION_MODE `{other}`.")),
+        };
+    Ok(encryptor)
+}
+
+/// Every secret is used as the only, primary, secret for its tenant.
+const STANDALONE_SECRET_ID: i32 = 1;
+/// Embeddings from the supported providers are normalized, so no value is larger than 1. The SDK's suggested
+/// starting point is the square root of the largest value.
+const DEFAULT_APPROXIMATION_FACTOR: f32 = 1.0;
+
+/// A tenant's standalone secret as configured: either one secret that a separate secret for each kind of
+/// encryption is derived from, or the three secrets themselves. All are base64 encoded.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum TenantSecret {
+    Derived(String),
+    PerType {
+        standard: String,
+        deterministic: String,
+        vector: String,
+    },
+}
+
+impl TenantSecret {
+    /// The standard, deterministic, and vector secrets, in that order. Keeping them apart means keys of one kind
+    /// reveal nothing about the others.
+    fn secrets(&self) -> Result<[Vec<u8>; 3]> {
+        let decode = |secret: &str| Ok::<_, anyhow::Error>(STANDARD.decode(secret.trim())?);
+        match self {
+            TenantSecret::Derived(secret) => {
+                let secret = decode(secret)?;
+                Ok(
+                    ["standard", "deterministic", "vector"]
+                        .map(|kind| derive_secret(&secret, kind)),
+                )
+            }
+            TenantSecret::PerType {
+                standard,
+                deterministic,
+                vector,
+            } => Ok([decode(standard)?, decode(deterministic)?, decode(vector)?]),
+        }
+    }
+}
+
+/// HKDF-SHA256 (RFC 5869) of the secret down to one 32 byte secret for the given kind of encryption.
+fn derive_secret(secret: &[u8], kind: &str) -> Vec<u8> {
+    let hmac = |key: &[u8], parts: &[&[u8]]| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take a key of any size");
+        parts.iter().for_each(|part| mac.update(part));
+        mac.finalize().into_bytes()
+    };
+    let pseudorandom_key = hmac(b"demo-notes-app standalone secret", &[secret]);
+    hmac(&pseudorandom_key, &[kind.as_bytes(), &[1]]).to_vec()
+}
+
+/// Standalone SDKs keyed by locally configured secrets, one per tenant. Tenants without their own secret share
+/// the default one, which still gives each tenant different keys since they're derived from the tenant ID.
+pub struct StandaloneEncryptor {
+    tenants: HashMap<String, Arc<Standalone>>,
+    default: Option<Arc<Standalone>>,
+}
+
+impl StandaloneEncryptor {
+    /// Secrets are base64 encoded and at least 32 random bytes. `STANDALONE_SECRET` is the default and
+    /// `STANDALONE_TENANT_SECRETS_FILE` can point at a JSON object of tenant IDs to their own secrets, each a
+    /// `TenantSecret`; at least one is required. `STANDALONE_APPROXIMATION_FACTOR` sets how much noise vector
+    /// encryption adds.
+    pub fn from_env() -> Result<StandaloneEncryptor> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let approximation_factor = var("STANDALONE_APPROXIMATION_FACTOR")
+            .map(|factor| factor.parse::<f32>())
+            .transpose()
+            .map_err(|e| anyhow!("STANDALONE_APPROXIMATION_FACTOR must be a number: {e}"))?
+            .unwrap_or(DEFAULT_APPROXIMATION_FACTOR);
+        let tenant_secrets = match var("STANDALONE_TENANT_SECRETS_FILE") {
+            Some(path) => {
+                let file = std::fs::read_to_string(&path).with_context(|| {
+                    format!("Couldn't read STANDALONE_TENANT_SECRETS_FILE `{path}`.")
+                })?;
+                serde_json::from_str::<HashMap<String, TenantSecret>>(&file).with_context(|| {
+                    format!(
+                        "STANDALONE_TENANT_SECRETS_FILE `{path}` isn't an object of tenant IDs to secrets."
+                    )
+                })?
+            }
+            None => HashMap::new(),
+        };
+        let default_secret = var("STANDALONE_SECRET");
+        if default_secret.is_none() && tenant_secrets.is_empty() {
+            return Err(anyhow!(
+                "Standalone encryption needs STANDALONE_SECRET or STANDALONE_TENANT_SECRETS_FILE."
+            ));
+        }
+        StandaloneEncryptor::new(
+            default_secret.as_deref(),
+            tenant_secrets,
+            approximation_factor,
+        )
+    }
+
+    pub fn new(
+        default_secret: Option<&str>,
+        tenant_secrets: HashMap<String, TenantSecret>,
+        approximation_factor: f32,
+    ) -> Result<StandaloneEncryptor> {
+        let default = default_secret
+            .map(|secret| {
+                standalone_sdk(
+                    &TenantSecret::Derived(secret.to_string()),
+                    approximation_factor,
+                )
+                .context("Invalid default secret.")
+            })
+            .transpose()?;
+        let tenants = tenant_secrets
+            .into_iter()
+            .map(|(tenant, secret)| {
+                let sdk = standalone_sdk(&secret, approximation_factor)
+                    .with_context(|| format!("Invalid secret for tenant `{tenant}`."))?;
+                Ok((tenant, sdk))
+            })
+            .collect::<Result<_>>()?;
+        Ok(StandaloneEncryptor { tenants, default })
+    }
+
+    fn sdk(&self, tenant_id: &TenantId) -> Result<&Standalone> {
+        self.tenants
+            .get(&tenant_id.0)
+            .or(self.default.as_ref())
+            .map(Arc::as_ref)
+            .ok_or_else(|| {
+                anyhow!(
+                    "No standalone secret is configured for tenant `{}`.",
+                    tenant_id.0
+                )
+            })
+    }
+}
+
+/// A Standalone SDK with separate secrets for standard, deterministic, and vector encryption. The app only uses the
+/// empty secret path, so that's the only one configured.
+fn standalone_sdk(secret: &TenantSecret, approximation_factor: f32) -> Result<Arc<Standalone>> {
+    let [standard, deterministic, vector] = secret.secrets()?.map(|secret| {
+        Ok::<_, anyhow::Error>(StandaloneSecret::new(
+            STANDALONE_SECRET_ID,
+            Secret::new(secret)?,
+        ))
+    });
+    let rotatable = |secret| RotatableSecret::new(Some(secret), None);
+    let config = StandaloneConfiguration::new(
+        StandardSecrets::new(Some(STANDALONE_SECRET_ID), vec![standard?])?,
+        [(SecretPath("".to_string()), rotatable(deterministic?)?)].into(),
+        [(
+            SecretPath("".to_string()),
+            VectorSecret::new(approximation_factor, rotatable(vector?)?),
+        )]
+        .into(),
+    );
+    Ok(Standalone::new(&config))
+}
+
+#[async_trait]
+impl Encryptor for StandaloneEncryptor {
+    async fn encrypt(
+        &self,
+        document: PlaintextDocument,
+        tenant_id: &TenantId,
+    ) -> Result<EncryptedDocument> {
+        self.sdk(tenant_id)?.encrypt(document, tenant_id).await
+    }
+
+    async fn encrypt_with_existing_edek(
+        &self,
+        document: PlaintextDocumentWithEdek,
+        tenant_id: &TenantId,
+    ) -> Result<EncryptedDocument> {
+        self.sdk(tenant_id)?
+            .encrypt_with_existing_edek(document, tenant_id)
+            .await
+    }
+
+    async fn decrypt(
+        &self,
+        document: EncryptedDocument,
+        tenant_id: &TenantId,
+    ) -> Result<PlaintextDocument> {
+        self.sdk(tenant_id)?.decrypt(document, tenant_id).await
+    }
+
+    async fn decrypt_batch(
+        &self,
+        documents: EncryptedDocuments,
+        tenant_id: &TenantId,
+    ) -> Result<StandardDecryptBatchResult> {
+        self.sdk(tenant_id)?
+            .decrypt_batch(documents, tenant_id)
+            .await
+    }
+
+    async fn rekey_edeks(
+        &self,
+        edeks: HashMap<DocumentId, EdekWithKeyIdHeader>,
+        tenant_id: &TenantId,
+    ) -> Result<RekeyEdeksBatchResult> {
+        self.sdk(tenant_id)?.rekey_edeks(edeks, tenant_id).await
+    }
+
+    async fn encrypt_field(
+        &self,
+        field: PlaintextField,
+        tenant_id: &TenantId,
+    ) -> Result<EncryptedField> {
+        self.sdk(tenant_id)?.encrypt_field(field, tenant_id).await
+    }
+
+    async fn decrypt_fields(
+        &self,
+        fields: EncryptedFields,
+        tenant_id: &TenantId,
+    ) -> Result<DeterministicDecryptBatchResult> {
+        self.sdk(tenant_id)?.decrypt_fields(fields, tenant_id).await
+    }
+
+    async fn encrypt_vectors(
+        &self,
+        vectors: PlaintextVectors,
+        tenant_id: &TenantId,
+    ) -> Result<VectorEncryptBatchResult> {
+        self.sdk(tenant_id)?
+            .encrypt_vectors(vectors, tenant_id)
+            .await
+    }
+
+    async fn generate_query_vectors(
+        &self,
+        vectors: PlaintextVectors,
+        tenant_id: &TenantId,
+    ) -> Result<GenerateVectorQueryResult> {
+        self.sdk(tenant_id)?
+            .generate_query_vectors(vectors, tenant_id)
+            .await
+    }
+}
+
+#[cfg(test)]
+pub use fake::FakeEncryptor;
+
+#[cfg(test)]
+mod fake {
+    use super::*;
+    use anyhow::anyhow;
+    use ironcore_alloy::{
+        deterministic::PlaintextFields,
+        standard::PlaintextDocuments,
+        vector::{EncryptedVector, EncryptedVectors},
+        EncryptedBytes, PlaintextBytes,
+    };
+
+    /// Reversible stand-in for the SaaS Shield SDK. "Encrypting" tags the plaintext with the tenant, and
+    /// decrypting checks the tag, so data read back under the wrong tenant fails the way it would for real.
+    /// Vectors are left as they are so similarity search still works.
+    #[derive(Debug, Default)]
+    pub struct FakeEncryptor;
+
+    fn tag(tenant_id: &TenantId) -> Vec<u8> {
+        format!("fake:{}:", tenant_id.0).into_bytes()
+    }
+
+    fn seal(bytes: Vec<u8>, tenant_id: &TenantId) -> EncryptedBytes {
+        EncryptedBytes([tag(tenant_id), bytes].concat())
+    }
+
+    fn open(bytes: EncryptedBytes, tenant_id: &TenantId) -> Result<PlaintextBytes> {
+        bytes
+            .0
+            .strip_prefix(tag(tenant_id).as_slice())
+            .map(|plaintext| PlaintextBytes(plaintext.to_vec()))
+            .ok_or_else(|| anyhow!("Data wasn't encrypted for tenant `{}`.", tenant_id.0))
+    }
+
+    fn edek(tenant_id: &TenantId) -> EdekWithKeyIdHeader {
+        EdekWithKeyIdHeader(seal(b"edek".to_vec(), tenant_id))
+    }
+
+    fn check_edek(edek: EdekWithKeyIdHeader, tenant_id: &TenantId) -> Result<()> {
+        open(edek.0, tenant_id).map(|_| ())
+    }
+
+    impl FakeEncryptor {
+        fn seal_document(
+            &self,
+            document: PlaintextDocument,
+            tenant_id: &TenantId,
+        ) -> EncryptedDocument {
+            EncryptedDocument {
+                edek: edek(tenant_id),
+                document: document
+                    .0
+                    .into_iter()
+                    .map(|(field, bytes)| (field, seal(bytes.0, tenant_id)))
+                    .collect(),
+            }
+        }
+
+        fn open_document(
+            &self,
+            document: EncryptedDocument,
+            tenant_id: &TenantId,
+        ) -> Result<PlaintextDocument> {
+            check_edek(document.edek, tenant_id)?;
+            Ok(PlaintextDocument(
+                document
+                    .document
+                    .into_iter()
+                    .map(|(field, bytes)| Ok((field, open(bytes, tenant_id)?)))
+                    .collect::<Result<_>>()?,
+            ))
+        }
+    }
+
+    #[async_trait]
+    impl Encryptor for FakeEncryptor {
+        async fn encrypt(
+            &self,
+            document: PlaintextDocument,
+            tenant_id: &TenantId,
+        ) -> Result<EncryptedDocument> {
+            Ok(self.seal_document(document, tenant_id))
+        }
+
+        async fn encrypt_with_existing_edek(
+            &self,
+            document: PlaintextDocumentWithEdek,
+            tenant_id: &TenantId,
+        ) -> Result<EncryptedDocument> {
+            check_edek(document.edek, tenant_id)?;
+            Ok(self.seal_document(document.document, tenant_id))
+        }
+
+        async fn decrypt(
+            &self,
+            document: EncryptedDocument,
+            tenant_id: &TenantId,
+        ) -> Result<PlaintextDocument> {
+            self.open_document(document, tenant_id)
+        }
+
+        async fn decrypt_batch(
+            &self,
+            documents: EncryptedDocuments,
+            tenant_id: &TenantId,
+        ) -> Result<StandardDecryptBatchResult> {
+            let mut successes = HashMap::new();
+            let mut failures = HashMap::new();
+            for (id, document) in documents.0 {
+                match self.open_document(document, tenant_id) {
+                    Ok(document) => {
+                        successes.insert(id, document);
+                    }
+                    Err(e) => {
+                        failures.insert(
+                            id,
+                            ironcore_alloy::errors::AlloyError::DecryptError { msg: e.to_string() },
+                        );
+                    }
+                }
+            }
+            Ok(StandardDecryptBatchResult {
+                successes: PlaintextDocuments(successes),
+                failures,
+            })
+        }
+
+        async fn rekey_edeks(
+            &self,
+            edeks: HashMap<DocumentId, EdekWithKeyIdHeader>,
+            tenant_id: &TenantId,
+        ) -> Result<RekeyEdeksBatchResult> {
+            let successes = edeks
+                .into_iter()
+                .map(|(id, old)| {
+                    check_edek(old, tenant_id)?;
+                    Ok((id, edek(tenant_id)))
+                })
+                .collect::<Result<_>>()?;
+            Ok(RekeyEdeksBatchResult {
+                successes,
+                failures: HashMap::new(),
+            })
+        }
+
+        async fn encrypt_field(
+            &self,
+            field: PlaintextField,
+            tenant_id: &TenantId,
+        ) -> Result<EncryptedField> {
+            Ok(EncryptedField {
+                encrypted_field: seal(field.plaintext_field.0, tenant_id),
+                secret_path: field.secret_path,
+                derivation_path: field.derivation_path,
+            })
+        }
+
+        async fn decrypt_fields(
+            &self,
+            fields: EncryptedFields,
+            tenant_id: &TenantId,
+        ) -> Result<DeterministicDecryptBatchResult> {
+            let mut successes = HashMap::new();
+            let mut failures = HashMap::new();
+            for (id, field) in fields.0 {
+                match open(field.encrypted_field, tenant_id) {
+                    Ok(plaintext_field) => {
+                        successes.insert(
+                            id,
+                            PlaintextField {
+                                plaintext_field,
+                                secret_path: field.secret_path,
+                                derivation_path: field.derivation_path,
+                            },
+                        );
+                    }
+                    Err(e) => {
+                        failures.insert(
+                            id,
+                            ironcore_alloy::errors::AlloyError::DecryptError { msg: e.to_string() },
+                        );
+                    }
+                }
+            }
+            Ok(DeterministicDecryptBatchResult {
+                successes: PlaintextFields(successes),
+                failures,
+            })
+        }
+
+        async fn encrypt_vectors(
+            &self,
+            vectors: PlaintextVectors,
+            tenant_id: &TenantId,
+        ) -> Result<VectorEncryptBatchResult> {
+            let successes = vectors
+                .0
+                .into_iter()
+                .map(|(id, vector)| {
+                    (
+                        id,
+                        EncryptedVector {
+                            encrypted_vector: vector.plaintext_vector,
+                            secret_path: vector.secret_path,
+                            derivation_path: vector.derivation_path,
+                            paired_icl_info: seal(vec![], tenant_id),
+                        },
+                    )
+                })
+                .collect();
+            Ok(VectorEncryptBatchResult {
+                successes: EncryptedVectors(successes),
+                failures: HashMap::new(),
+            })
+        }
+
+        async fn generate_query_vectors(
+            &self,
+            vectors: PlaintextVectors,
+            tenant_id: &TenantId,
+        ) -> Result<GenerateVectorQueryResult> {
+            let encrypted = self.encrypt_vectors(vectors, tenant_id).await?;
+            Ok(GenerateVectorQueryResult(
+                encrypted
+                    .successes
+                    .0
+                    .into_iter()
+                    .map(|(id, vector)| (id, vec![vector]))
+                    .collect(),
+            ))
+        }
+    }
+}
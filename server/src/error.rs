@@ -0,0 +1,19 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use tracing::error;
+
+use crate::attachments::PolicyViolation;
+
+/// Policy violations are the caller's fault and are reported as such, anything else is logged and hidden
+/// behind a 500.
+pub fn handle_err(e: anyhow::Error) -> Response {
+    match e.downcast::<PolicyViolation>() {
+        Ok(violation) => violation.into_response(),
+        Err(e) => {
+            error!("{:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,640 @@
+use crate::encryption::Encryptor;
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use ironcore_alloy::{
+    deterministic::{EncryptedField, EncryptedFields, PlaintextField},
+    standard::{
+        EdekWithKeyIdHeader, EncryptedDocument, EncryptedDocuments, PlaintextDocument,
+        PlaintextDocumentWithEdek,
+    },
+    vector::{EncryptedVector, PlaintextVector, PlaintextVectors, VectorId},
+    DerivationPath, DocumentId, EncryptedBytes, FieldId, PlaintextBytes, SecretPath, TenantId,
+};
+use std::{collections::HashMap, hash::Hash};
+
+/// How a field of an `EncryptedRecord` is encrypted.
+#[derive(Clone, Copy, Debug)]
+pub enum FieldEncryption {
+    /// Encrypted with the record's EDEK, along with its other standard fields. Nothing can be learned from the
+    /// ciphertext, so it can't be searched on either.
+    Standard,
+    /// The same value always encrypts the same way, so the ciphertext can be filtered and grouped on.
+    Deterministic { derivation_path: &'static str },
+    /// Embeddings, encrypted so similar vectors stay similar. This can't be undone, so vector fields are left out
+    /// when a record is decrypted.
+    Vector { derivation_path: &'static str },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    pub encryption: FieldEncryption,
+}
+
+pub enum FieldValue {
+    Bytes(Vec<u8>),
+    Vector(Vec<f32>),
+}
+
+pub enum FieldCiphertext {
+    Bytes(EncryptedBytes),
+    Vector(EncryptedVector),
+}
+
+/// Any record's encrypted fields by name. Standard fields share the `edek`.
+#[derive(Default)]
+pub struct RecordCiphertext {
+    pub edek: Option<EdekWithKeyIdHeader>,
+    pub fields: HashMap<&'static str, FieldCiphertext>,
+}
+
+/// A record with fields that are encrypted the way `FIELDS` says. Implemented with `encrypted_record!`, which
+/// also declares the `Encrypted` form of the record.
+pub trait EncryptedRecord: Sized {
+    type Encrypted;
+
+    const FIELDS: &'static [FieldSchema];
+
+    /// The record's field values by name. Fields that are `None` are left out.
+    fn into_plaintext(self) -> HashMap<&'static str, FieldValue>;
+
+    fn from_plaintext(fields: HashMap<&'static str, FieldValue>) -> Result<Self>;
+
+    fn into_ciphertext(encrypted: Self::Encrypted) -> RecordCiphertext;
+
+    fn from_ciphertext(ciphertext: RecordCiphertext) -> Result<Self::Encrypted>;
+}
+
+/// The types an `encrypted_record!` field can have, and what they encrypt to. Strings get standard or
+/// deterministic encryption and embeddings get vector encryption. Either can be optional.
+pub trait FieldType: Sized {
+    type Encrypted;
+
+    fn into_plaintext(self) -> Option<FieldValue>;
+
+    fn from_plaintext(name: &str, value: Option<FieldValue>) -> Result<Self>;
+
+    fn into_ciphertext(encrypted: Self::Encrypted) -> Option<FieldCiphertext>;
+
+    fn from_ciphertext(name: &str, value: Option<FieldCiphertext>) -> Result<Self::Encrypted>;
+}
+
+impl FieldType for String {
+    type Encrypted = EncryptedBytes;
+
+    fn into_plaintext(self) -> Option<FieldValue> {
+        Some(FieldValue::Bytes(self.into_bytes()))
+    }
+
+    fn from_plaintext(name: &str, value: Option<FieldValue>) -> Result<Self> {
+        match value {
+            Some(FieldValue::Bytes(bytes)) => String::from_utf8(bytes)
+                .map_err(|_| anyhow!("Field `{name}` didn't decrypt to a string.")),
+            Some(FieldValue::Vector(_)) => Err(anyhow!("Field `{name}` is a vector.")),
+            None => Err(anyhow!("Field `{name}` is missing.")),
+        }
+    }
+
+    fn into_ciphertext(encrypted: EncryptedBytes) -> Option<FieldCiphertext> {
+        Some(FieldCiphertext::Bytes(encrypted))
+    }
+
+    fn from_ciphertext(name: &str, value: Option<FieldCiphertext>) -> Result<EncryptedBytes> {
+        match value {
+            Some(FieldCiphertext::Bytes(bytes)) => Ok(bytes),
+            Some(FieldCiphertext::Vector(_)) => Err(anyhow!("Field `{name}` is a vector.")),
+            None => Err(anyhow!("Field `{name}` is missing.")),
+        }
+    }
+}
+
+impl FieldType for Vec<f32> {
+    type Encrypted = EncryptedVector;
+
+    fn into_plaintext(self) -> Option<FieldValue> {
+        Some(FieldValue::Vector(self))
+    }
+
+    fn from_plaintext(name: &str, value: Option<FieldValue>) -> Result<Self> {
+        match value {
+            Some(FieldValue::Vector(vector)) => Ok(vector),
+            Some(FieldValue::Bytes(_)) => Err(anyhow!("Field `{name}` isn't a vector.")),
+            None => Err(anyhow!("Field `{name}` is missing.")),
+        }
+    }
+
+    fn into_ciphertext(encrypted: EncryptedVector) -> Option<FieldCiphertext> {
+        Some(FieldCiphertext::Vector(encrypted))
+    }
+
+    fn from_ciphertext(name: &str, value: Option<FieldCiphertext>) -> Result<EncryptedVector> {
+        match value {
+            Some(FieldCiphertext::Vector(vector)) => Ok(vector),
+            Some(FieldCiphertext::Bytes(_)) => Err(anyhow!("Field `{name}` isn't a vector.")),
+            None => Err(anyhow!("Field `{name}` is missing.")),
+        }
+    }
+}
+
+impl<T: FieldType> FieldType for Option<T> {
+    type Encrypted = Option<T::Encrypted>;
+
+    fn into_plaintext(self) -> Option<FieldValue> {
+        self.and_then(T::into_plaintext)
+    }
+
+    fn from_plaintext(name: &str, value: Option<FieldValue>) -> Result<Self> {
+        value
+            .map(|value| T::from_plaintext(name, Some(value)))
+            .transpose()
+    }
+
+    fn into_ciphertext(encrypted: Option<T::Encrypted>) -> Option<FieldCiphertext> {
+        encrypted.and_then(T::into_ciphertext)
+    }
+
+    fn from_ciphertext(name: &str, value: Option<FieldCiphertext>) -> Result<Option<T::Encrypted>> {
+        value
+            .map(|value| T::from_ciphertext(name, Some(value)))
+            .transpose()
+    }
+}
+
+/// Declares a record and how each of its fields is encrypted, and implements `EncryptedRecord` for it. Alongside
+/// the record it declares its encrypted form, a struct with the same fields holding their ciphertext plus the EDEK
+/// the standard fields were encrypted with.
+///
+/// ```text
+/// encrypted_record! {
+///     pub struct Note => EncryptedNote {
+///         pub category: Option<String> => deterministic("note/category"),
+///         pub title: String => standard,
+///         pub embedding: Vec<f32> => vector("note/embedding"),
+///     }
+/// }
+/// ```
+macro_rules! encrypted_record {
+    (@encryption standard) => {
+        $crate::field_encryption::FieldEncryption::Standard
+    };
+    (@encryption deterministic $derivation_path:expr) => {
+        $crate::field_encryption::FieldEncryption::Deterministic {
+            derivation_path: $derivation_path,
+        }
+    };
+    (@encryption vector $derivation_path:expr) => {
+        $crate::field_encryption::FieldEncryption::Vector {
+            derivation_path: $derivation_path,
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident => $encrypted:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident: $ty:ty => $encryption:ident $(($derivation_path:expr))?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        $vis struct $encrypted {
+            pub edek: Option<ironcore_alloy::standard::EdekWithKeyIdHeader>,
+            $(
+                $field_vis $field: <$ty as $crate::field_encryption::FieldType>::Encrypted,
+            )*
+        }
+
+        impl $crate::field_encryption::EncryptedRecord for $name {
+            type Encrypted = $encrypted;
+
+            const FIELDS: &'static [$crate::field_encryption::FieldSchema] = &[$(
+                $crate::field_encryption::FieldSchema {
+                    name: stringify!($field),
+                    encryption: $crate::field_encryption::encrypted_record!(
+                        @encryption $encryption $($derivation_path)?
+                    ),
+                },
+            )*];
+
+            fn into_plaintext(
+                self,
+            ) -> std::collections::HashMap<&'static str, $crate::field_encryption::FieldValue> {
+                [$((
+                    stringify!($field),
+                    $crate::field_encryption::FieldType::into_plaintext(self.$field),
+                )),*]
+                .into_iter()
+                .filter_map(|(name, value)| value.map(|value| (name, value)))
+                .collect()
+            }
+
+            fn from_plaintext(
+                mut fields: std::collections::HashMap<&'static str, $crate::field_encryption::FieldValue>,
+            ) -> anyhow::Result<Self> {
+                Ok($name {
+                    $(
+                        $field: $crate::field_encryption::FieldType::from_plaintext(
+                            stringify!($field),
+                            fields.remove(stringify!($field)),
+                        )?,
+                    )*
+                })
+            }
+
+            fn into_ciphertext(encrypted: $encrypted) -> $crate::field_encryption::RecordCiphertext {
+                $crate::field_encryption::RecordCiphertext {
+                    edek: encrypted.edek,
+                    fields: [$((
+                        stringify!($field),
+                        <$ty as $crate::field_encryption::FieldType>::into_ciphertext(encrypted.$field),
+                    )),*]
+                    .into_iter()
+                    .filter_map(|(name, value)| value.map(|value| (name, value)))
+                    .collect(),
+                }
+            }
+
+            fn from_ciphertext(
+                mut ciphertext: $crate::field_encryption::RecordCiphertext,
+            ) -> anyhow::Result<$encrypted> {
+                Ok($encrypted {
+                    edek: ciphertext.edek,
+                    $(
+                        $field: <$ty as $crate::field_encryption::FieldType>::from_ciphertext(
+                            stringify!($field),
+                            ciphertext.fields.remove(stringify!($field)),
+                        )?,
+                    )*
+                })
+            }
+        }
+    };
+}
+pub(crate) use encrypted_record;
+
+/// The outcome of encrypting or decrypting several records. A record that fails doesn't stop the others.
+pub struct BatchResult<K, T> {
+    pub successes: HashMap<K, T>,
+    pub failures: HashMap<K, anyhow::Error>,
+}
+
+/// The records of a batch as they're put together, by their position in it. A record stays failed after its
+/// first error.
+struct Batch<K, T> {
+    keys: Vec<K>,
+    records: Vec<Result<T>>,
+}
+
+impl<K: Eq + Hash, T: Default> Batch<K, T> {
+    fn new(keys: Vec<K>) -> Batch<K, T> {
+        let records = keys.iter().map(|_| Ok(T::default())).collect();
+        Batch { keys, records }
+    }
+
+    fn update<X>(&mut self, i: usize, result: Result<X>, apply: impl FnOnce(&mut T, X)) {
+        let Ok(record) = &mut self.records[i] else {
+            return;
+        };
+        match result {
+            Ok(value) => apply(record, value),
+            Err(e) => self.records[i] = Err(e),
+        }
+    }
+
+    fn fail(&mut self, i: usize, error: anyhow::Error) {
+        self.update(i, Err::<(), _>(error), |_, _| {});
+    }
+
+    fn finish<U>(self, convert: impl Fn(T) -> Result<U>) -> BatchResult<K, U> {
+        let mut result = BatchResult {
+            successes: HashMap::new(),
+            failures: HashMap::new(),
+        };
+        for (key, record) in self.keys.into_iter().zip(self.records) {
+            match record.and_then(&convert) {
+                Ok(record) => {
+                    result.successes.insert(key, record);
+                }
+                Err(e) => {
+                    result.failures.insert(key, e);
+                }
+            }
+        }
+        result
+    }
+}
+
+fn field_schema<R: EncryptedRecord>(name: &str) -> Result<&'static FieldSchema> {
+    R::FIELDS
+        .iter()
+        .find(|field| field.name == name)
+        .ok_or_else(|| anyhow!("`{name}` isn't an encrypted field of this record."))
+}
+
+/// Identifies a field of the record at position `i` in a batch.
+fn batch_field_id(i: usize, name: &str) -> String {
+    format!("{i}/{name}")
+}
+
+fn only<T>(mut result: BatchResult<(), T>) -> Result<T> {
+    match result.failures.remove(&()) {
+        Some(e) => Err(e),
+        None => result
+            .successes
+            .remove(&())
+            .ok_or_else(|| anyhow!("ironcore_alloy didn't return the record")),
+    }
+}
+
+pub async fn encrypt_record<R: EncryptedRecord>(
+    record: R,
+    sdk: &dyn Encryptor,
+    tenant_id: &TenantId,
+) -> Result<R::Encrypted> {
+    only(encrypt_all(vec![((), record, None)], sdk, tenant_id).await?)
+}
+
+/// Encrypts the record's standard fields with an EDEK other fields were encrypted with, so they can all be
+/// decrypted together.
+pub async fn encrypt_record_with_edek<R: EncryptedRecord>(
+    record: R,
+    edek: EdekWithKeyIdHeader,
+    sdk: &dyn Encryptor,
+    tenant_id: &TenantId,
+) -> Result<R::Encrypted> {
+    only(encrypt_all(vec![((), record, Some(edek))], sdk, tenant_id).await?)
+}
+
+/// Each record's standard fields get their own EDEK. All the vector fields are encrypted in a single call.
+pub async fn encrypt_records<K: Eq + Hash, R: EncryptedRecord>(
+    records: impl IntoIterator<Item = (K, R)>,
+    sdk: &dyn Encryptor,
+    tenant_id: &TenantId,
+) -> Result<BatchResult<K, R::Encrypted>> {
+    let records = records
+        .into_iter()
+        .map(|(key, record)| (key, record, None))
+        .collect();
+    encrypt_all(records, sdk, tenant_id).await
+}
+
+async fn encrypt_all<K: Eq + Hash, R: EncryptedRecord>(
+    records: Vec<(K, R, Option<EdekWithKeyIdHeader>)>,
+    sdk: &dyn Encryptor,
+    tenant_id: &TenantId,
+) -> Result<BatchResult<K, R::Encrypted>> {
+    let (keys, records): (Vec<_>, Vec<_>) = records
+        .into_iter()
+        .map(|(key, record, edek)| (key, (record, edek)))
+        .unzip();
+    let mut batch = Batch::<K, RecordCiphertext>::new(keys);
+    let mut documents = vec![];
+    let mut fields = vec![];
+    let mut vectors = HashMap::new();
+    let mut vector_fields = vec![];
+    for (i, (record, edek)) in records.into_iter().enumerate() {
+        let mut document = HashMap::new();
+        for (name, value) in record.into_plaintext() {
+            let encryption = match field_schema::<R>(name) {
+                Ok(field) => field.encryption,
+                Err(e) => {
+                    batch.fail(i, e);
+                    continue;
+                }
+            };
+            match (encryption, value) {
+                (FieldEncryption::Standard, FieldValue::Bytes(bytes)) => {
+                    document.insert(FieldId(name.to_string()), PlaintextBytes(bytes));
+                }
+                (FieldEncryption::Deterministic { derivation_path }, FieldValue::Bytes(bytes)) => {
+                    fields.push((
+                        i,
+                        name,
+                        PlaintextField {
+                            plaintext_field: PlaintextBytes(bytes),
+                            secret_path: SecretPath("".to_string()),
+                            derivation_path: DerivationPath(derivation_path.to_string()),
+                        },
+                    ));
+                }
+                (FieldEncryption::Vector { derivation_path }, FieldValue::Vector(vector)) => {
+                    vectors.insert(
+                        VectorId(batch_field_id(i, name)),
+                        PlaintextVector {
+                            plaintext_vector: vector,
+                            secret_path: SecretPath("".to_string()),
+                            derivation_path: DerivationPath(derivation_path.to_string()),
+                        },
+                    );
+                    vector_fields.push((i, name));
+                }
+                (encryption, _) => batch.fail(
+                    i,
+                    anyhow!("Field `{name}` can't be given {encryption:?} encryption."),
+                ),
+            }
+        }
+        if !document.is_empty() {
+            documents.push((i, PlaintextDocument(document), edek));
+        }
+    }
+
+    let encrypted_documents =
+        join_all(documents.into_iter().map(|(i, document, edek)| async move {
+            let encrypted = match edek {
+                Some(edek) => {
+                    sdk.encrypt_with_existing_edek(
+                        PlaintextDocumentWithEdek { edek, document },
+                        tenant_id,
+                    )
+                    .await
+                }
+                None => sdk.encrypt(document, tenant_id).await,
+            };
+            (i, encrypted)
+        }))
+        .await;
+    for (i, encrypted) in encrypted_documents {
+        let encrypted = encrypted.and_then(|encrypted| {
+            let fields = encrypted
+                .document
+                .into_iter()
+                .map(|(field, bytes)| {
+                    Ok((
+                        field_schema::<R>(&field.0)?.name,
+                        FieldCiphertext::Bytes(bytes),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok((encrypted.edek, fields))
+        });
+        batch.update(i, encrypted, |record, (edek, fields)| {
+            record.edek = Some(edek);
+            record.fields.extend(fields);
+        });
+    }
+
+    let encrypted_fields = join_all(fields.into_iter().map(|(i, name, field)| async move {
+        (i, name, sdk.encrypt_field(field, tenant_id).await)
+    }))
+    .await;
+    for (i, name, encrypted) in encrypted_fields {
+        batch.update(i, encrypted, |record, field| {
+            record
+                .fields
+                .insert(name, FieldCiphertext::Bytes(field.encrypted_field));
+        });
+    }
+
+    if !vectors.is_empty() {
+        let mut encrypted = sdk
+            .encrypt_vectors(PlaintextVectors(vectors), tenant_id)
+            .await?;
+        for (i, name) in vector_fields {
+            let id = VectorId(batch_field_id(i, name));
+            let vector = match encrypted.failures.remove(&id) {
+                Some(e) => Err(e.into()),
+                None => encrypted
+                    .successes
+                    .0
+                    .remove(&id)
+                    .ok_or_else(|| anyhow!("ironcore_alloy didn't encrypt field `{name}`")),
+            };
+            batch.update(i, vector, |record, vector| {
+                record.fields.insert(name, FieldCiphertext::Vector(vector));
+            });
+        }
+    }
+
+    Ok(batch.finish(R::from_ciphertext))
+}
+
+pub async fn decrypt_record<R: EncryptedRecord>(
+    encrypted: R::Encrypted,
+    sdk: &dyn Encryptor,
+    tenant_id: &TenantId,
+) -> Result<R> {
+    only(decrypt_records(vec![((), encrypted)], sdk, tenant_id).await?)
+}
+
+/// Decrypts all the records' standard fields in one call and all their deterministic fields in another.
+pub async fn decrypt_records<K: Eq + Hash, R: EncryptedRecord>(
+    records: impl IntoIterator<Item = (K, R::Encrypted)>,
+    sdk: &dyn Encryptor,
+    tenant_id: &TenantId,
+) -> Result<BatchResult<K, R>> {
+    let (keys, records): (Vec<_>, Vec<_>) = records.into_iter().unzip();
+    let mut batch = Batch::<K, HashMap<&'static str, FieldValue>>::new(keys);
+    let mut documents = HashMap::new();
+    let mut fields = HashMap::new();
+    let mut deterministic_fields = vec![];
+    let mut document_positions = vec![];
+    for (i, record) in records.into_iter().enumerate() {
+        let ciphertext = R::into_ciphertext(record);
+        let mut document = HashMap::new();
+        for (name, value) in ciphertext.fields {
+            let encryption = match field_schema::<R>(name) {
+                Ok(field) => field.encryption,
+                Err(e) => {
+                    batch.fail(i, e);
+                    continue;
+                }
+            };
+            match (encryption, value) {
+                (FieldEncryption::Standard, FieldCiphertext::Bytes(bytes)) => {
+                    document.insert(FieldId(name.to_string()), bytes);
+                }
+                (
+                    FieldEncryption::Deterministic { derivation_path },
+                    FieldCiphertext::Bytes(bytes),
+                ) => {
+                    fields.insert(
+                        FieldId(batch_field_id(i, name)),
+                        EncryptedField {
+                            encrypted_field: bytes,
+                            secret_path: SecretPath("".to_string()),
+                            derivation_path: DerivationPath(derivation_path.to_string()),
+                        },
+                    );
+                    deterministic_fields.push((i, name));
+                }
+                (FieldEncryption::Vector { .. }, FieldCiphertext::Vector(_)) => {}
+                (encryption, _) => batch.fail(
+                    i,
+                    anyhow!("Field `{name}` wasn't given {encryption:?} encryption."),
+                ),
+            }
+        }
+        if !document.is_empty() {
+            match ciphertext.edek {
+                Some(edek) => {
+                    documents.insert(
+                        DocumentId(i.to_string()),
+                        EncryptedDocument { document, edek },
+                    );
+                    document_positions.push(i);
+                }
+                None => batch.fail(i, anyhow!("The record's standard fields have no EDEK.")),
+            }
+        }
+    }
+
+    if !documents.is_empty() {
+        let mut decrypted = sdk
+            .decrypt_batch(EncryptedDocuments(documents), tenant_id)
+            .await?;
+        for i in document_positions {
+            let id = DocumentId(i.to_string());
+            let document = match decrypted.failures.remove(&id) {
+                Some(e) => Err(e.into()),
+                None => decrypted
+                    .successes
+                    .0
+                    .remove(&id)
+                    .ok_or_else(|| anyhow!("ironcore_alloy didn't decrypt the record")),
+            };
+            let values = document.and_then(|document| {
+                document
+                    .0
+                    .into_iter()
+                    .map(|(field, bytes)| {
+                        Ok((
+                            field_schema::<R>(&field.0)?.name,
+                            FieldValue::Bytes(bytes.0),
+                        ))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            });
+            batch.update(i, values, |record, values| record.extend(values));
+        }
+    }
+
+    if !fields.is_empty() {
+        let mut decrypted = sdk
+            .decrypt_fields(EncryptedFields(fields), tenant_id)
+            .await?;
+        for (i, name) in deterministic_fields {
+            let id = FieldId(batch_field_id(i, name));
+            let field = match decrypted.failures.remove(&id) {
+                Some(e) => Err(e.into()),
+                None => decrypted
+                    .successes
+                    .0
+                    .remove(&id)
+                    .ok_or_else(|| anyhow!("ironcore_alloy didn't decrypt field `{name}`")),
+            };
+            batch.update(i, field, |record, field| {
+                record.insert(name, FieldValue::Bytes(field.plaintext_field.0));
+            });
+        }
+    }
+
+    Ok(batch.finish(R::from_plaintext))
+}
@@ -0,0 +1,53 @@
+use crate::{embedding_provider::EmbeddingProvider, notes::CreateNoteRequest, CurrentOrganization};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
+use sha2::Sha256;
+use tracing::warn;
+
+/// Key for fingerprinting the content a note's search document was built from. A note's embeddings only need to be
+/// regenerated when its fingerprint changes. The fingerprint is keyed so it can't be used to guess at the contents
+/// of the encrypted note.
+pub struct FingerprintKey(Vec<u8>);
+
+impl FingerprintKey {
+    /// Reads the key from `CONTENT_FINGERPRINT_KEY`. Without one a random key is used, which means every note is
+    /// embedded again the first time it's saved after a restart.
+    pub fn from_env() -> FingerprintKey {
+        match std::env::var("CONTENT_FINGERPRINT_KEY") {
+            Ok(key) if !key.is_empty() => FingerprintKey(key.into_bytes()),
+            _ => {
+                warn!("CONTENT_FINGERPRINT_KEY isn't set, using a random key for this run.");
+                FingerprintKey(rand::random::<[u8; 32]>().to_vec())
+            }
+        }
+    }
+
+    /// Fingerprints everything the search document is built from: the embedding model and its dimensions, the
+    /// title, the body, and which attachments the note has. Each part is length prefixed so moving text between
+    /// them changes the fingerprint.
+    pub fn fingerprint(
+        &self,
+        org: &CurrentOrganization,
+        embedder: &dyn EmbeddingProvider,
+        note: &CreateNoteRequest,
+    ) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC can take a key of any size");
+        let model = embedder.model();
+        let dimensions = embedder.dimensions().to_string();
+        let attachments = note.attachments.iter().sorted().join(",");
+        for part in [
+            &org.0.login,
+            &model,
+            &dimensions,
+            &note.title,
+            &note.body,
+            &attachments,
+        ] {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part.as_bytes());
+        }
+        STANDARD.encode(mac.finalize().into_bytes())
+    }
+}
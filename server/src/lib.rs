@@ -0,0 +1,356 @@
+use anyhow::{Context, Result};
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderValue, Method, StatusCode,
+    },
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post, put},
+    Router,
+};
+use axum_extra::extract::CookieJar;
+use chat_model::ChatModels;
+use db::{get_organization, OrganizationTable};
+use db_pool::DbPool;
+use elasticsearch::{
+    http::transport::Transport,
+    indices::{IndicesCreateParts, IndicesExistsParts, IndicesPutMappingParts},
+    Elasticsearch,
+};
+use embedding_provider::EmbeddingProvider;
+use embeddings::Prompts;
+use encryption::Encryptor;
+use fingerprint::FingerprintKey;
+use object_store::ObjectStore;
+use repository::{AttachmentRepository, NoteRepository, SqlRepository};
+use search_index::{ElasticsearchIndex, SearchIndex};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::Duration};
+use tower::{layer::Layer, BoxError, ServiceBuilder};
+use tower_http::{
+    cors::CorsLayer,
+    normalize_path::{NormalizePath, NormalizePathLayer},
+    trace::TraceLayer,
+};
+use tracing::{error, info, trace, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+#[derive(Clone)]
+pub struct AppState {
+    db: DbPool,
+    note_repo: Arc<dyn NoteRepository>,
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    sdk: Arc<dyn Encryptor>,
+    aws_sdk: Arc<dyn ObjectStore>,
+    es_sdk: Arc<dyn SearchIndex>,
+    chat_models: Arc<ChatModels>,
+    embedder: Arc<dyn EmbeddingProvider>,
+    prompts: Arc<Prompts>,
+    chat_similarity_threshold: f32,
+    fingerprint_key: Arc<FingerprintKey>,
+    /// Needed on top of an organization to use the `/api/admin` routes. They're turned off without one.
+    admin_token: Option<Arc<str>>,
+}
+pub const INDEX_NAME: &str = "demo";
+pub const ATTACHMENT_BUCKET: &str = "icl-demo-notes-app";
+pub const SENTENCE_MODEL_NAME: &str = "all-minilm";
+pub const CHATBOT_MODEL_NAME: &str = "llama-demo";
+
+pub mod admin;
+mod attachment_text;
+mod attachments;
+mod categories;
+mod chat_model;
+mod db;
+pub mod db_pool;
+mod embedding_provider;
+mod embeddings;
+mod encryption;
+mod error;
+mod field_encryption;
+mod fingerprint;
+mod note_service;
+mod notes;
+mod object_store;
+mod prompt_templates;
+mod repository;
+mod search_index;
+mod search_service;
+#[cfg(test)]
+mod tests;
+
+/// Vector fields are sized to match the embedding provider.
+fn index_mappings(dimensions: usize) -> Value {
+    let vector_mapping = json!({
+        "type": "dense_vector",
+        "dims": dimensions,
+        "index": "true",
+        "similarity": "cosine",
+    });
+    // SQLite's `current_timestamp` format, plus the ISO 8601 forms for anything else
+    let date_mapping = json!({
+        "type": "date",
+        "format": "yyyy-MM-dd HH:mm:ss||strict_date_optional_time",
+    });
+    json!({
+        "properties": {
+            "title_vector": vector_mapping,
+            "body_vector": vector_mapping,
+            "category": { "type": "keyword" },
+            "created": date_mapping,
+            "updated": date_mapping,
+            "passages": {
+                "type": "nested",
+                "properties": {
+                    "start": { "type": "integer" },
+                    "end": { "type": "integer" },
+                    "vector": vector_mapping,
+                }
+            },
+            "attachment_passages": {
+                "type": "nested",
+                "properties": {
+                    "vector": vector_mapping,
+                }
+            },
+        }
+    })
+}
+
+async fn set_up_search_client(vector_dimensions: usize) -> Result<Elasticsearch> {
+    let transport = Transport::single_node("http://localhost:8675")?;
+    let client = Elasticsearch::new(transport);
+    info!("Trying to create search service index.");
+    let index_exists_response = client
+        .indices()
+        .exists(IndicesExistsParts::Index(&[INDEX_NAME]))
+        .send()
+        .await?;
+
+    // create index if it doesn't exist
+    if index_exists_response.status_code() == StatusCode::NOT_FOUND {
+        client
+            .indices()
+            .create(IndicesCreateParts::Index(INDEX_NAME))
+            .body(json!({ "mappings": index_mappings(vector_dimensions) }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        info!("Search service index created.");
+    } else {
+        // new fields may have been added since the index was created, mappings can be extended in place
+        client
+            .indices()
+            .put_mapping(IndicesPutMappingParts::Index(&[INDEX_NAME]))
+            .body(index_mappings(vector_dimensions))
+            .send()
+            .await?
+            .error_for_status_code()
+            .context("Couldn't update the search index mappings. If the embedding provider's dimensions changed, the index has to be deleted and recreated.")?;
+        info!("Search service index already exists.")
+    }
+    Ok(client)
+}
+
+/// All the routes, with their middleware.
+pub fn app(state: AppState) -> NormalizePath<Router> {
+    let timed = Router::new()
+        .route("/api/notes", get(notes::list).post(notes::create))
+        .route("/api/attachments", post(attachments::create))
+        .route("/api/attachments/:id/confirm", post(attachments::confirm))
+        .route("/api/notes/:id", get(notes::get).put(notes::update))
+        .route("/api/notes/:id/rekey", put(notes::rekey))
+        .route("/api/notes/:id/related", get(notes::related))
+        .route("/api/notes/:id/summary", post(notes::summary))
+        .route("/api/notes/search", post(notes::search))
+        .route("/api/notes/semantic-search", post(notes::semantic_search))
+        .route("/api/categories", get(categories::list))
+        .route("/api/categories/suggest", post(categories::suggest))
+        .merge(
+            Router::new()
+                .route(
+                    "/api/admin/prompt-template",
+                    get(prompt_templates::get).put(prompt_templates::put),
+                )
+                .route(
+                    "/api/admin/prompt-template/versions",
+                    get(prompt_templates::list_versions),
+                )
+                .route(
+                    "/api/admin/prompt-template/versions/:version/restore",
+                    post(prompt_templates::restore),
+                )
+                .route_layer(middleware::from_fn_with_state(
+                    state.admin_token.clone(),
+                    admin_auth,
+                )),
+        )
+        .route("/api/chat", post(notes::chat))
+        .route("/api/chat/stream", post(notes::chat_stream))
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|error: BoxError| async move {
+                    if error.is::<tower::timeout::error::Elapsed>() {
+                        Ok(StatusCode::REQUEST_TIMEOUT)
+                    } else {
+                        Err((
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            format!("Unhandled internal error: {error}"),
+                        ))
+                    }
+                }))
+                .timeout(Duration::from_secs(30)),
+        );
+    // Not timed, since uploads and downloads as large as the attachment policy allows can take longer than that.
+    // They're only served when the server encrypts attachments, otherwise the browser goes straight to the object
+    // store.
+    let mut objects = Router::new();
+    if state.aws_sdk.through_server() {
+        objects = objects.route(
+            "/api/attachments/objects/*key",
+            get(attachments::get_object)
+                .put(attachments::put_object)
+                .layer(DefaultBodyLimit::disable()),
+        );
+    }
+    NormalizePathLayer::trim_trailing_slash().layer(
+        timed
+            .merge(objects)
+            // Add middleware to all routes
+            .layer(
+                ServiceBuilder::new()
+                    .layer(TraceLayer::new_for_http())
+                    .layer(middleware::from_fn_with_state(state.db.clone(), auth))
+                    .into_inner(),
+            )
+            .layer(
+                CorsLayer::new()
+                    .allow_origin(["http://localhost:9002".parse::<HeaderValue>().unwrap()])
+                    .allow_methods([Method::GET, Method::PUT, Method::POST])
+                    .allow_headers([CONTENT_TYPE, AUTHORIZATION])
+                    .allow_credentials(true),
+            )
+            .with_state(state),
+    )
+}
+
+/// Logs at debug level from the app and its HTTP layer unless `RUST_LOG` says otherwise.
+pub fn init_tracing() {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
+            }),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+}
+
+impl AppState {
+    /// Connects to all the services configured in the environment, creating or updating the search index.
+    pub async fn from_env(db: DbPool) -> Result<AppState> {
+        let sdk = encryption::from_env()?;
+        let embedder = embedding_provider::from_env()?;
+        let es_sdk = Arc::new(ElasticsearchIndex::new(
+            set_up_search_client(embedder.dimensions()).await?,
+            INDEX_NAME,
+        ));
+
+        let chat_models = ChatModels::from_env()?;
+
+        let aws_sdk = object_store::from_env(sdk.clone()).await?;
+
+        let repository = Arc::new(SqlRepository::new(db.clone()));
+        Ok(AppState {
+            db,
+            note_repo: repository.clone(),
+            attachment_repo: repository,
+            sdk,
+            aws_sdk,
+            es_sdk,
+            chat_models: Arc::new(chat_models),
+            embedder,
+            prompts: Arc::new(Prompts::from_env()),
+            chat_similarity_threshold: embeddings::chat_similarity_threshold_from_env()?,
+            fingerprint_key: Arc::new(FingerprintKey::from_env()),
+            admin_token: admin_token_from_env(),
+        })
+    }
+}
+
+/// Reads `ADMIN_TOKEN`, which the admin routes have to be called with.
+fn admin_token_from_env() -> Option<Arc<str>> {
+    let token = std::env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    if token.is_none() {
+        warn!("ADMIN_TOKEN isn't set, so the admin routes are turned off.");
+    }
+    token.map(Arc::from)
+}
+
+#[derive(Debug, Clone)]
+pub struct CurrentOrganization(pub OrganizationTable);
+async fn auth(
+    State(db): State<DbPool>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let jar = CookieJar::from_headers(req.headers());
+
+    let org_login = if let Some(org_login) = jar.get("organization") {
+        trace!("Found cookie {:?}", &org_login);
+        org_login.value()
+    } else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    match get_organization(&db, org_login).await {
+        Ok(Some(current_user)) => {
+            // insert the current user into a request extension so the handler can
+            // extract it
+            req.extensions_mut()
+                .insert(CurrentOrganization(current_user));
+            Ok(next.run(req).await)
+        }
+        Ok(None) => {
+            info!("Organization login for '{}' was not found.", org_login);
+            Err(StatusCode::UNAUTHORIZED)
+        }
+        Err(e) => {
+            error!(
+                "Looking up organization '{}' caused an error: '{:?}'",
+                org_login, e
+            );
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// Lets requests through to the admin routes only with `Authorization: Bearer <ADMIN_TOKEN>`, on top of the
+/// organization cookie `auth` checks.
+async fn admin_auth(
+    State(admin_token): State<Option<Arc<str>>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(admin_token) = admin_token else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    // comparing digests keeps the time taken from depending on how much of the token was right
+    if Sha256::digest(given) != Sha256::digest(admin_token.as_bytes()) {
+        info!("A request to an admin route had the wrong admin token.");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(req).await)
+}
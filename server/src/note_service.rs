@@ -0,0 +1,439 @@
+use crate::{
+    attachments::{create_attachment_infos, AttachmentInfo, PolicyViolation},
+    db::{DeterministicallyEncryptedString, EncryptedNote, EncryptedString, NoteTable},
+    encryption::Encryptor,
+    field_encryption::{
+        decrypt_record, decrypt_records, encrypt_record, encrypt_record_with_edek, encrypted_record,
+    },
+    notes::{CreateNoteRequest, UpdateNoteRequest},
+    object_store::ObjectStore,
+    repository::{AttachmentRepository, NoteRepository},
+    CurrentOrganization,
+};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::join_all;
+use ironcore_alloy::{standard::EdekWithKeyIdHeader, DocumentId, EncryptedBytes, TenantId};
+use itertools::Itertools;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
+use tracing::warn;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Note {
+    pub id: u32,
+    pub category: Option<String>,
+    pub title: String,
+    pub body: String,
+    /// A short summary of the body written by the chat model, if one has been saved since the note was last edited.
+    pub summary: Option<String>,
+    pub created: String,
+    pub updated: String,
+    pub attachments: Vec<AttachmentInfo>,
+}
+
+async fn get_attachments_and_create_info(
+    note: Note,
+    org: &CurrentOrganization,
+    attachment_repo: &dyn AttachmentRepository,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> Result<Note> {
+    let attachment_tables = attachment_repo.list_for_note(note.id).await?;
+
+    let attachments = create_attachment_infos(aws_sdk, org, attachment_tables).await?;
+
+    Ok(Note {
+        attachments,
+        ..note
+    })
+}
+
+/// Notes are listed by category, so it's deterministically encrypted. Searches filter on it in the index too.
+const CATEGORY_DERIVATION_PATH: &str = "note/category";
+
+encrypted_record! {
+    /// The parts of a note that are encrypted.
+    struct NoteFields => EncryptedNoteFields {
+        category: Option<String> => deterministic(CATEGORY_DERIVATION_PATH),
+        title: String => standard,
+        body: String => standard,
+        summary: Option<String> => standard,
+    }
+}
+
+encrypted_record! {
+    /// A summary added to an already encrypted note, which is decrypted as part of its `NoteFields`.
+    struct NoteSummary => EncryptedNoteSummary {
+        summary: String => standard,
+    }
+}
+
+encrypted_record! {
+    /// A category on its own, to filter or list notes by.
+    struct NoteCategory => EncryptedNoteCategory {
+        category: String => deterministic(CATEGORY_DERIVATION_PATH),
+    }
+}
+
+async fn encrypt_note(
+    note: CreateNoteRequest,
+    organization: CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+) -> Result<EncryptedNote> {
+    let fields = NoteFields {
+        category: note.category,
+        title: note.title,
+        body: note.body,
+        summary: None,
+    };
+    let encrypted = encrypt_record(fields, &*sdk, &TenantId(organization.0.login)).await?;
+    let edek = encrypted
+        .edek
+        .ok_or(anyhow!("ironcore_alloy didn't return an EDEK"))?;
+    Ok(EncryptedNote {
+        title: EncryptedString::new(encrypted.title),
+        body: EncryptedString::new(encrypted.body),
+        category: encrypted
+            .category
+            .map(DeterministicallyEncryptedString::new),
+        edek: STANDARD.encode(edek.0),
+    })
+}
+
+pub async fn encrypt_category(
+    category: Option<String>,
+    sdk: Arc<dyn Encryptor>,
+    tenant_id: &TenantId,
+) -> Result<Option<DeterministicallyEncryptedString>> {
+    let result = match category {
+        Some(category) => Some(DeterministicallyEncryptedString::new(
+            encrypt_record(NoteCategory { category }, &*sdk, tenant_id)
+                .await?
+                .category,
+        )),
+        None => None,
+    };
+    Ok(result)
+}
+
+fn encrypted_note_fields(row: &NoteTable) -> Result<EncryptedNoteFields> {
+    Ok(EncryptedNoteFields {
+        edek: Some(EdekWithKeyIdHeader(EncryptedBytes(
+            STANDARD.decode(&row.edek)?,
+        ))),
+        category: row
+            .category
+            .as_ref()
+            .map(DeterministicallyEncryptedString::to_enc_bytes)
+            .transpose()?,
+        title: row.enc_title.to_enc_bytes()?,
+        body: row.enc_body.to_enc_bytes()?,
+        summary: row
+            .enc_summary
+            .as_ref()
+            .map(EncryptedString::to_enc_bytes)
+            .transpose()?,
+    })
+}
+
+fn decrypted_note(row: NoteTable, fields: NoteFields) -> Note {
+    Note {
+        id: row.id,
+        category: fields.category,
+        title: fields.title,
+        body: fields.body,
+        summary: fields.summary,
+        created: row.created,
+        updated: row.updated,
+        attachments: vec![], // this gets filled in later
+    }
+}
+
+async fn decrypt_note(
+    row: NoteTable,
+    sdk: Arc<dyn Encryptor>,
+    tenant_id: &TenantId,
+) -> Result<Note> {
+    let fields = decrypt_record(encrypted_note_fields(&row)?, &*sdk, tenant_id).await?;
+    Ok(decrypted_note(row, fields))
+}
+
+/// Notes that can't be decrypted are logged and left out.
+async fn decrypt_notes(
+    rows: Vec<NoteTable>,
+    sdk: Arc<dyn Encryptor>,
+    tenant_id: &TenantId,
+) -> Result<Vec<Note>> {
+    let encrypted = rows
+        .iter()
+        .map(|row| Ok((row.id, encrypted_note_fields(row)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut decrypted = decrypt_records::<_, NoteFields>(encrypted, &*sdk, tenant_id).await?;
+    for (id, failure) in decrypted.failures {
+        warn!("Failed to decrypt note with ID `{}`: {}", id, failure);
+    }
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let fields = decrypted.successes.remove(&row.id)?;
+            Some(decrypted_note(row, fields))
+        })
+        .collect())
+}
+
+/// Makes sure the attachments being put on a note belong to the organization, have been confirmed,
+/// and that there aren't more of them than the organization's policy allows.
+async fn check_note_attachments(
+    attachment_repo: &dyn AttachmentRepository,
+    org: &CurrentOrganization,
+    attachment_ids: &[u32],
+) -> Result<()> {
+    let policy = attachment_repo.get_policy(org.0.id).await?;
+    policy.check_note_attachment_count(attachment_ids.len())?;
+    for attachment_id in attachment_ids {
+        match attachment_repo.get(*attachment_id, org.0.id).await? {
+            None => Err(PolicyViolation::UnknownAttachment(*attachment_id))?,
+            Some(attachment) if !attachment.confirmed => {
+                Err(PolicyViolation::Unconfirmed(*attachment_id))?
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+pub async fn create_note(
+    note_repo: Arc<dyn NoteRepository>,
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    note: CreateNoteRequest,
+    organization: &CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> Result<Note> {
+    check_note_attachments(&*attachment_repo, organization, &note.attachments).await?;
+    let encrypted_note = encrypt_note(note.clone(), organization.clone(), sdk).await?;
+    let (res, updated_attachments) = note_repo
+        .create(organization.0.id, encrypted_note, &note.attachments)
+        .await?;
+
+    let attachments = create_attachment_infos(aws_sdk, organization, updated_attachments).await?;
+
+    Ok(Note {
+        id: res.id,
+        category: note.category,
+        title: note.title,
+        body: note.body,
+        summary: None,
+        created: res.created,
+        updated: res.updated,
+        attachments,
+    })
+}
+
+pub async fn update_note(
+    note_repo: Arc<dyn NoteRepository>,
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    note: UpdateNoteRequest,
+    id: u32,
+    organization: &CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> Result<Note> {
+    check_note_attachments(&*attachment_repo, organization, &note.attachments).await?;
+    let encrypted_note = encrypt_note(note.clone(), organization.clone(), sdk).await?;
+    let (res, updated_attachments) = note_repo
+        .update(id, organization.0.id, encrypted_note, &note.attachments)
+        .await?;
+
+    let attachments: Vec<AttachmentInfo> =
+        create_attachment_infos(aws_sdk, organization, updated_attachments).await?;
+
+    Ok(Note {
+        id: res.id,
+        category: note.category,
+        title: note.title,
+        body: note.body,
+        summary: None,
+        created: res.created,
+        updated: res.updated,
+        attachments,
+    })
+}
+
+pub async fn get_note(
+    note_repo: Arc<dyn NoteRepository>,
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    id: u32,
+    organization: &CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> Result<Option<Note>> {
+    match note_repo.get(id, organization.0.id).await? {
+        Some(row) => {
+            let decrypted_note =
+                decrypt_note(row, sdk.clone(), &TenantId(organization.0.login.clone())).await?;
+            get_attachments_and_create_info(
+                decrypted_note,
+                organization,
+                &*attachment_repo,
+                aws_sdk,
+            )
+            .await
+            .map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Stores a summary of the note, encrypted with the note's existing EDEK so it's decrypted along with the title
+/// and body.
+pub async fn put_summary(
+    note_repo: Arc<dyn NoteRepository>,
+    id: u32,
+    organization: &CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+    summary: String,
+) -> Result<()> {
+    let edek = note_repo
+        .get_edek(id, organization.0.id)
+        .await?
+        .ok_or(anyhow!(
+            "Note with id {} not found for user {}",
+            id,
+            organization.0.login
+        ))?
+        .edek;
+    let encrypted = encrypt_record_with_edek(
+        NoteSummary { summary },
+        EdekWithKeyIdHeader(edek.to_enc_bytes()?),
+        &*sdk,
+        &TenantId(organization.0.login.clone()),
+    )
+    .await?;
+    note_repo
+        .put_summary(
+            id,
+            organization.0.id,
+            EncryptedString::new(encrypted.summary),
+        )
+        .await
+}
+
+/// Re-encrypts the notes' EDEKs to the organization's current key and stores them. Returns why each note that
+/// couldn't be rekeyed failed, by its ID.
+pub async fn rekey_notes(
+    note_repo: Arc<dyn NoteRepository>,
+    edeks: Vec<(u32, EncryptedString)>,
+    organization: &CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+) -> Result<HashMap<u32, anyhow::Error>> {
+    let edeks = edeks
+        .into_iter()
+        .map(|(id, edek)| {
+            Ok((
+                DocumentId(id.to_string()),
+                EdekWithKeyIdHeader(edek.to_enc_bytes()?),
+            ))
+        })
+        .collect::<Result<_>>()?;
+    let result = sdk
+        .rekey_edeks(edeks, &TenantId(organization.0.login.clone()))
+        .await?;
+    for (id, edek) in result.successes {
+        note_repo
+            .put_edek(
+                id.0.parse()?,
+                organization.0.id,
+                EncryptedString::new(edek.0),
+            )
+            .await?;
+    }
+    result
+        .failures
+        .into_iter()
+        .map(|(id, failure)| Ok((id.0.parse()?, failure.into())))
+        .collect()
+}
+
+pub async fn list_notes(
+    note_repo: Arc<dyn NoteRepository>,
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    org: CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+    category: Option<String>,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> Result<Vec<Note>> {
+    let tenant_id = TenantId(org.0.login.clone());
+    let category = encrypt_category(category, sdk.clone(), &tenant_id).await?;
+    let db_result = note_repo.list(org.0.id, category).await?;
+    let decrypted_notes = decrypt_notes(db_result, sdk, &tenant_id).await?;
+
+    let result = join_all(decrypted_notes.into_iter().map(|note| {
+        get_attachments_and_create_info(note, &org, &*attachment_repo, aws_sdk.clone())
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<_>>()?;
+    Ok(result)
+}
+
+/// The notes with these IDs, in the same order.
+pub async fn search_notes(
+    note_repo: Arc<dyn NoteRepository>,
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    ids: Vec<u32>,
+    org: &CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> Result<Vec<Note>> {
+    let db_result = note_repo.get_many(org.0.id, &ids).await?;
+    let decrypted_notes = decrypt_notes(db_result, sdk, &TenantId(org.0.login.clone())).await?;
+    let result = join_all(decrypted_notes.into_iter().map(|note| {
+        get_attachments_and_create_info(note, org, &*attachment_repo, aws_sdk.clone())
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>>>()?;
+    let mut hashmap_result = result
+        .into_iter()
+        .map(|note| (note.id, note))
+        .collect::<HashMap<_, _>>();
+    let sorted_result = ids
+        .into_iter()
+        .flat_map(|id| hashmap_result.remove(&id))
+        .collect();
+    Ok(sorted_result)
+}
+
+pub async fn list_categories(
+    note_repo: Arc<dyn NoteRepository>,
+    org: CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+) -> Result<Vec<String>> {
+    let encrypted = note_repo
+        .list_categories(org.0.id)
+        .await?
+        .into_iter()
+        .enumerate()
+        .map(|(n, category)| {
+            Ok((
+                n,
+                EncryptedNoteCategory {
+                    edek: None,
+                    category: category.to_enc_bytes()?,
+                },
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let decrypted =
+        decrypt_records::<_, NoteCategory>(encrypted, &*sdk, &TenantId(org.0.login)).await?;
+    for failure in decrypted.failures.into_values() {
+        warn!("Failed to decrypt category: {}", failure);
+    }
+    Ok(decrypted
+        .successes
+        .into_values()
+        .map(|decrypted| decrypted.category)
+        .sorted()
+        .collect())
+}
@@ -1,21 +1,34 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Result};
 use axum::{
     extract::{Path, Query, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Extension, Json,
 };
-use ironcore_alloy::{
-    standard::{EdekWithKeyIdHeader, StandardDocumentOps},
-    AlloyMetadata, DocumentId, TenantId,
-};
+use futures::StreamExt;
+use ironcore_alloy::TenantId;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc};
 use tracing::error;
 
 use crate::{
-    db::{self, EncryptedString, Note},
-    embeddings::{self, generate_query_embeddings},
-    search_service::{self, QueryType},
+    attachment_text,
+    attachments::AttachmentInfo,
+    embeddings::{
+        self, generate_query_embeddings, ChatSource, ChatStreamEvent, KnnOptions, CHAT_MAX_SOURCES,
+    },
+    encryption::Encryptor,
+    error::handle_err,
+    note_service::{self, Note},
+    object_store::ObjectStore,
+    prompt_templates,
+    repository::{AttachmentRepository, NoteRepository},
+    search_service::{self, DateRange, Page, QueryType, ScoredHits, SearchFilters},
     AppState, CurrentOrganization,
 };
 
@@ -40,7 +53,9 @@ pub struct NoteListResponse {
 
 #[derive(Debug, Serialize)]
 pub struct NoteSearchResponse {
-    result: Vec<Note>,
+    result: Vec<ScoredNote>,
+    /// How many notes matched in total, across all pages.
+    total: u64,
 }
 
 pub type UpdateNoteRequest = CreateNoteRequest;
@@ -49,11 +64,117 @@ pub type UpdateNoteRequest = CreateNoteRequest;
 pub struct SearchNoteRequest {
     pub title: Option<String>,
     pub body: Option<String>,
+    #[serde(default)]
+    pub attachment_text: Option<String>,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// How much each kind of match counts toward a note's rank in a hybrid search.
+    #[serde(default)]
+    pub weights: SearchWeights,
+    #[serde(flatten)]
+    pub filters: SearchFilterRequest,
+    #[serde(flatten)]
+    pub page: Page,
+}
+
+/// Optional filters accepted by the search endpoints.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SearchFilterRequest {
+    /// Only return notes in this category.
+    pub category: Option<String>,
+    pub created: Option<DateRange>,
+    pub updated: Option<DateRange>,
+}
+
+impl SearchFilterRequest {
+    /// Encrypts the category the same way it's stored so it can be matched in the index.
+    async fn into_search_filters(
+        self,
+        org: &CurrentOrganization,
+        sdk: Arc<dyn Encryptor>,
+    ) -> anyhow::Result<SearchFilters> {
+        Ok(SearchFilters {
+            category: note_service::encrypt_category(
+                self.category,
+                sdk,
+                &TenantId(org.0.login.clone()),
+            )
+            .await?,
+            created: self.created,
+            updated: self.updated,
+            exclude_note_ids: vec![],
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SemanticSearchRequest {
+    pub query: String,
+    #[serde(flatten)]
+    pub filters: SearchFilterRequest,
+    #[serde(flatten)]
+    pub knn: KnnOptions,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScoredNote {
+    #[serde(flatten)]
+    pub note: Note,
+    /// How well the note matched the query, higher is better. Only comparable to scores from the same search.
+    pub score: f32,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Match the words of the query with Cloaked Search.
+    #[default]
+    Keyword,
+    /// kNN search the encrypted embeddings for notes similar in meaning to the query.
+    Semantic,
+    /// Run both and fuse the rankings.
+    Hybrid,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct SearchWeights {
+    pub keyword: f32,
+    pub semantic: f32,
+}
+
+impl Default for SearchWeights {
+    fn default() -> Self {
+        SearchWeights {
+            keyword: 1.0,
+            semantic: 1.0,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct QueryChatbotRequest {
     pub question: String,
+    /// Earlier turns of the conversation, oldest first.
+    #[serde(default)]
+    pub history: Vec<ChatHistoryMessage>,
+    /// Notes cited earlier in the conversation. These are given to the model again along with whatever the
+    /// new question matches, so follow up questions can keep referring to them.
+    #[serde(default)]
+    pub referenced_note_ids: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatHistoryMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    User,
+    Assistant,
 }
 
 impl From<&QueryChatbotRequest> for SearchNoteRequest {
@@ -61,23 +182,27 @@ impl From<&QueryChatbotRequest> for SearchNoteRequest {
         SearchNoteRequest {
             title: Some(value.question.clone()),
             body: Some(value.question.clone()),
+            attachment_text: Some(value.question.clone()),
+            mode: SearchMode::Semantic,
+            weights: SearchWeights::default(),
+            filters: SearchFilterRequest::default(),
+            page: Page::default(),
         }
     }
 }
 
-fn handle_err(e: anyhow::Error) -> StatusCode {
-    error!("{:?}", e);
-    StatusCode::INTERNAL_SERVER_ERROR
-}
-
 pub async fn get(
     Path(id): Path<u32>,
     State(AppState {
-        db, sdk, aws_sdk, ..
+        note_repo,
+        attachment_repo,
+        sdk,
+        aws_sdk,
+        ..
     }): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let result = db::get_note(&db, id, &org, sdk, aws_sdk)
+) -> Result<impl IntoResponse, Response> {
+    let result = note_service::get_note(note_repo, attachment_repo, id, &org, sdk, aws_sdk)
         .await
         .map_err(handle_err)?;
 
@@ -86,23 +211,45 @@ pub async fn get(
 
 pub async fn update(
     Path(id): Path<u32>,
-    State(AppState {
-        db,
-        sdk,
-        es_sdk,
-        aws_sdk,
-        ai_sdk,
-    }): State<AppState>,
+    State(state): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
     Json(input): Json<UpdateNoteRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let db_result = db::update_note(&db, input.clone(), id, &org, sdk.clone(), aws_sdk)
-        .await
-        .map_err(handle_err)?;
-    let embeddings = embeddings::generate_and_encrypt_embedding(ai_sdk, sdk, input.clone(), &org)
+) -> Result<impl IntoResponse, Response> {
+    let db_result = note_service::update_note(
+        state.note_repo.clone(),
+        state.attachment_repo.clone(),
+        input.clone(),
+        id,
+        &org,
+        state.sdk.clone(),
+        state.aws_sdk.clone(),
+    )
+    .await
+    .map_err(handle_err)?;
+    let fingerprint = state
+        .fingerprint_key
+        .fingerprint(&org, state.embedder.as_ref(), &input);
+    let indexed_fingerprint = state
+        .note_repo
+        .get_content_fingerprint(id, org.0.id)
         .await
         .map_err(handle_err)?;
-    search_service::update_note(id, input, &org, es_sdk, embeddings)
+    // only the category or timestamps changed, the embeddings in the index are still good
+    if indexed_fingerprint.as_ref() == Some(&fingerprint) {
+        let index_fields = state
+            .note_repo
+            .get_index_fields(id, org.0.id)
+            .await
+            .map_err(handle_err)?;
+        let updated = search_service::update_note_fields(id, index_fields, state.es_sdk.clone())
+            .await
+            .map_err(handle_err)?;
+        if updated {
+            return Ok(Json(db_result));
+        }
+        // the note is missing from the index, such as after it was recreated, so it's indexed from scratch
+    }
+    index_note(&state, &org, id, input, &db_result.attachments)
         .await
         .map_err(handle_err)?;
     Ok(Json(db_result))
@@ -110,10 +257,11 @@ pub async fn update(
 
 pub async fn rekey(
     Path(id): Path<u32>,
-    State(AppState { db, sdk, .. }): State<AppState>,
+    State(AppState { note_repo, sdk, .. }): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let edek = db::get_edek(&db, id, &org)
+) -> Result<impl IntoResponse, Response> {
+    let edek = note_repo
+        .get_edek(id, org.0.id)
         .await
         .and_then(|maybe_note| {
             maybe_note.ok_or(anyhow!(format!(
@@ -123,140 +271,499 @@ pub async fn rekey(
         })
         .map_err(handle_err)?;
 
-    let tenant_id = TenantId(org.0.login.clone());
-    let metadata = AlloyMetadata::new_simple(tenant_id);
-    let document_id = DocumentId("".to_string());
-    let edeks = vec![(
-        document_id.clone(),
-        EdekWithKeyIdHeader(edek.edek.to_enc_bytes().map_err(handle_err)?),
-    )]
-    .into_iter()
-    .collect();
-    let result = sdk
-        .standard()
-        .rekey_edeks(edeks, &metadata, None)
+    let failures = note_service::rekey_notes(note_repo, vec![(id, edek.edek)], &org, sdk)
         .await
-        .map_err(|e| handle_err(e.into()))?;
+        .map_err(handle_err)?;
+    if let Some(failure) = failures.into_values().next() {
+        return Err(handle_err(failure));
+    }
+
+    Ok(Json(()))
+}
 
-    db::put_edek(
-        &db,
+/// Stores the note and indexes it for search.
+pub async fn create_note(
+    state: &AppState,
+    org: &CurrentOrganization,
+    input: CreateNoteRequest,
+) -> Result<Note> {
+    let note = note_service::create_note(
+        state.note_repo.clone(),
+        state.attachment_repo.clone(),
+        input.clone(),
+        org,
+        state.sdk.clone(),
+        state.aws_sdk.clone(),
+    )
+    .await?;
+    index_note(state, org, note.id, input, &note.attachments).await?;
+    Ok(note)
+}
+
+/// Embeds the note and its attachments and indexes them, replacing anything already indexed for the note.
+pub async fn index_note(
+    state: &AppState,
+    org: &CurrentOrganization,
+    id: u32,
+    input: CreateNoteRequest,
+    attachments: &[AttachmentInfo],
+) -> Result<()> {
+    let fingerprint = state
+        .fingerprint_key
+        .fingerprint(org, state.embedder.as_ref(), &input);
+    let indexed = if attachments.is_empty() {
+        None
+    } else {
+        search_service::get_indexed_attachments(org, state.es_sdk.clone(), id).await?
+    };
+    let attachment_text = match indexed {
+        Some(indexed) if indexed.are_of(&input) => indexed.attachment_text,
+        _ => {
+            attachment_text::extract_attachments_text(state.aws_sdk.clone(), org, attachments).await
+        }
+    };
+    let embeddings = embeddings::generate_and_encrypt_embedding(
+        state.embedder.as_ref(),
+        state.sdk.clone(),
+        input.clone(),
+        attachment_text.clone(),
+        org,
+    )
+    .await?;
+    let index_fields = state.note_repo.get_index_fields(id, org.0.id).await?;
+    search_service::index_note(
         id,
-        &org,
-        EncryptedString::new(result.successes.get(&document_id).unwrap().0.clone()),
+        input,
+        attachment_text,
+        index_fields,
+        org,
+        state.es_sdk.clone(),
+        embeddings,
+    )
+    .await?;
+    state
+        .note_repo
+        .put_content_fingerprint(id, org.0.id, fingerprint)
+        .await
+}
+
+pub async fn create(
+    State(state): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+    Json(input): Json<CreateNoteRequest>,
+) -> Result<impl IntoResponse, Response> {
+    let db_result = create_note(&state, &org, input).await.map_err(handle_err)?;
+
+    Ok(Json(db_result))
+}
+
+pub async fn list(
+    State(AppState {
+        note_repo,
+        attachment_repo,
+        sdk,
+        aws_sdk,
+        ..
+    }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+    query: Query<ListQuery>,
+) -> Result<impl IntoResponse, Response> {
+    let result = note_service::list_notes(
+        note_repo,
+        attachment_repo,
+        org,
+        sdk,
+        query.category.clone(),
+        aws_sdk,
     )
     .await
+    .map(|notes| NoteListResponse { result: notes })
     .map_err(handle_err)?;
 
-    Ok(Json(()))
+    Ok(Json(result))
 }
-pub async fn create(
+
+pub async fn search(
     State(AppState {
-        db,
+        note_repo,
+        attachment_repo,
         sdk,
         es_sdk,
         aws_sdk,
-        ai_sdk,
+        embedder,
+        ..
     }): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
-    Json(input): Json<CreateNoteRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let db_result = db::create_note(&db, input.clone(), &org, sdk.clone(), aws_sdk)
-        .await
-        .map_err(handle_err)?;
-    let embeddings = embeddings::generate_and_encrypt_embedding(ai_sdk, sdk, input.clone(), &org)
+    Json(input): Json<SearchNoteRequest>,
+) -> Result<impl IntoResponse, Response> {
+    let filters = input
+        .filters
+        .clone()
+        .into_search_filters(&org, sdk.clone())
         .await
         .map_err(handle_err)?;
-    search_service::index_note(db_result.id, input, &org, es_sdk, embeddings)
+    let page = input.page;
+    let end = page.end().map_err(IntoResponse::into_response)?;
+    let keyword_query = || QueryType::Keyword {
+        title: input.title.clone(),
+        body: input.body.clone(),
+        attachment_text: input.attachment_text.clone(),
+    };
+    // each vector field only returns its k nearest notes, so ask for enough of them to fill the page
+    let knn = KnnOptions {
+        k: end.max(1),
+        num_candidates: end.max(KnnOptions::default().num_candidates),
+        ..KnnOptions::default()
+    };
+    let semantic_query = || async {
+        let embeddings =
+            generate_query_embeddings(embedder.as_ref(), sdk.clone(), input.clone(), knn, &org)
+                .await?;
+        Ok::<_, anyhow::Error>(QueryType::Knn { embeddings })
+    };
+    let found = match input.mode {
+        SearchMode::Keyword => {
+            search_service::query_notes(&org, es_sdk, keyword_query(), filters, page).await
+        }
+        SearchMode::Semantic => {
+            let query = semantic_query().await.map_err(handle_err)?;
+            search_service::query_notes(&org, es_sdk, query, filters, page).await
+        }
+        SearchMode::Hybrid => {
+            // fuse everything up to the end of the requested page, then cut the page out of the fused ranking
+            let fused_page = Page { from: 0, size: end };
+            let (keyword, semantic) = futures::try_join!(
+                search_service::query_notes(
+                    &org,
+                    es_sdk.clone(),
+                    keyword_query(),
+                    filters.clone(),
+                    fused_page
+                ),
+                async {
+                    let query = semantic_query().await?;
+                    search_service::query_notes(
+                        &org,
+                        es_sdk.clone(),
+                        query,
+                        filters.clone(),
+                        fused_page,
+                    )
+                    .await
+                }
+            )
+            .map_err(handle_err)?;
+            let ids = |hits: ScoredHits| hits.hits.into_iter().map(|(id, _)| id).collect_vec();
+            // the overlap between the two result sets isn't known, so this is a lower bound
+            let total = keyword.total.max(semantic.total);
+            let fused = search_service::reciprocal_rank_fusion(&[
+                (input.weights.keyword, ids(keyword)),
+                (input.weights.semantic, ids(semantic)),
+            ]);
+            Ok(ScoredHits {
+                total,
+                hits: fused
+                    .into_iter()
+                    .skip(page.from as usize)
+                    .take(page.size as usize)
+                    .collect(),
+            })
+        }
+    }
+    .map_err(handle_err)?;
+    let result = scored_notes(note_repo, attachment_repo, found, &org, sdk, aws_sdk)
         .await
         .map_err(handle_err)?;
 
-    Ok(Json(db_result))
+    Ok(Json(result))
 }
 
-pub async fn list(
+/// Decrypts the notes that were hit, keeping the search's order and scores.
+async fn scored_notes(
+    note_repo: Arc<dyn NoteRepository>,
+    attachment_repo: Arc<dyn AttachmentRepository>,
+    found: ScoredHits,
+    org: &CurrentOrganization,
+    sdk: Arc<dyn Encryptor>,
+    aws_sdk: Arc<dyn ObjectStore>,
+) -> anyhow::Result<NoteSearchResponse> {
+    let mut scores = found.hits.iter().copied().collect::<HashMap<_, _>>();
+    let ids = found.hits.into_iter().map(|(id, _)| id).collect();
+    let result = note_service::search_notes(note_repo, attachment_repo, ids, org, sdk, aws_sdk)
+        .await?
+        .into_iter()
+        .map(|note| ScoredNote {
+            score: scores.remove(&note.id).unwrap_or_default(),
+            note,
+        })
+        .collect();
+    Ok(NoteSearchResponse {
+        result,
+        total: found.total,
+    })
+}
+
+/// Vector search on its own, returning how similar each note is to the query.
+pub async fn semantic_search(
     State(AppState {
-        db, sdk, aws_sdk, ..
+        note_repo,
+        attachment_repo,
+        sdk,
+        es_sdk,
+        aws_sdk,
+        embedder,
+        ..
     }): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
-    query: Query<ListQuery>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let result = db::list_notes(&db, org, sdk, query.category.clone(), aws_sdk)
+    Json(input): Json<SemanticSearchRequest>,
+) -> Result<impl IntoResponse, Response> {
+    let filters = input
+        .filters
+        .into_search_filters(&org, sdk.clone())
+        .await
+        .map_err(handle_err)?;
+    let search = SearchNoteRequest {
+        title: Some(input.query.clone()),
+        body: Some(input.query.clone()),
+        attachment_text: Some(input.query),
+        mode: SearchMode::Semantic,
+        weights: SearchWeights::default(),
+        filters: SearchFilterRequest::default(),
+        page: Page::default(),
+    };
+    // each of the title, body, attachment, and passage vectors can contribute its own `k` notes
+    let page = input.knn.page(4).map_err(IntoResponse::into_response)?;
+    let embeddings =
+        generate_query_embeddings(embedder.as_ref(), sdk.clone(), search, input.knn, &org)
+            .await
+            .map_err(handle_err)?;
+    let found =
+        search_service::query_notes(&org, es_sdk, QueryType::Knn { embeddings }, filters, page)
+            .await
+            .map_err(handle_err)?;
+    let result = scored_notes(note_repo, attachment_repo, found, &org, sdk, aws_sdk)
         .await
-        .map(|notes| NoteListResponse { result: notes })
         .map_err(handle_err)?;
 
     Ok(Json(result))
 }
 
-pub async fn search(
+#[derive(Debug, Default, Deserialize)]
+pub struct SummaryRequest {
+    /// Save the summary with the note so it's returned along with it until the note is next edited.
+    #[serde(default)]
+    pub store: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SummaryResponse {
+    summary: String,
+}
+
+/// Summarizes a note with the chat model. The request body is optional.
+pub async fn summary(
+    Path(id): Path<u32>,
     State(AppState {
-        db,
+        note_repo,
+        attachment_repo,
         sdk,
-        es_sdk,
         aws_sdk,
+        chat_models,
+        prompts,
         ..
     }): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
-    Json(input): Json<SearchNoteRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let found_ids = search_service::query_notes(
+    input: Option<Json<SummaryRequest>>,
+) -> Result<impl IntoResponse, Response> {
+    let Json(input) = input.unwrap_or_default();
+    let chat_model = chat_models.for_org(&org.0).map_err(handle_err)?;
+    let note = note_service::get_note(
+        note_repo.clone(),
+        attachment_repo,
+        id,
         &org,
-        es_sdk,
-        QueryType::Keyword {
-            title: input.title,
-            body: input.body,
-        },
+        sdk.clone(),
+        aws_sdk,
     )
     .await
-    .map_err(handle_err)?;
-    let result = db::search_notes(&db, found_ids, &org, sdk, aws_sdk)
+    .map_err(handle_err)?
+    .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    let summary = embeddings::summarize_note(&*chat_model, &prompts, &note)
         .await
-        .map(|notes| NoteSearchResponse { result: notes })
         .map_err(handle_err)?;
+    if input.store {
+        note_service::put_summary(note_repo, id, &org, sdk, summary.clone())
+            .await
+            .map_err(handle_err)?;
+    }
 
-    Ok(Json(result))
+    Ok(Json(SummaryResponse { summary }))
 }
 
-pub async fn chat(
+/// Notes similar to the given one, found with the vectors it was indexed with rather than by embedding it again.
+pub async fn related(
+    Path(id): Path<u32>,
+    Query(knn): Query<KnnOptions>,
     State(AppState {
-        db,
+        note_repo,
+        attachment_repo,
         sdk,
         es_sdk,
         aws_sdk,
-        ai_sdk,
+        ..
     }): State<AppState>,
     Extension(org): Extension<CurrentOrganization>,
+) -> Result<impl IntoResponse, Response> {
+    // the title and body vectors can each contribute their own `k` notes
+    let page = knn.page(2).map_err(IntoResponse::into_response)?;
+    let vectors = search_service::get_note_vectors(&org, es_sdk.clone(), id)
+        .await
+        .map_err(handle_err)?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    let filters = SearchFilters {
+        exclude_note_ids: vec![id],
+        ..SearchFilters::default()
+    };
+    let found =
+        search_service::query_notes(&org, es_sdk, vectors.related_query(knn), filters, page)
+            .await
+            .map_err(handle_err)?;
+    let result = scored_notes(note_repo, attachment_repo, found, &org, sdk, aws_sdk)
+        .await
+        .map_err(handle_err)?;
+
+    Ok(Json(result))
+}
+
+/// Finds the notes to answer a chat question from: the notes with passages similar enough to the question,
+/// followed by any the conversation has already referenced. Empty if there are neither.
+async fn find_chat_sources(
+    AppState {
+        note_repo,
+        attachment_repo,
+        sdk,
+        es_sdk,
+        aws_sdk,
+        embedder,
+        chat_similarity_threshold,
+        ..
+    }: AppState,
+    org: &CurrentOrganization,
+    input: &QueryChatbotRequest,
+) -> anyhow::Result<Vec<ChatSource>> {
+    let knn = KnnOptions {
+        similarity: Some(chat_similarity_threshold),
+        ..KnnOptions::default()
+    };
+    let embeddings =
+        generate_query_embeddings(embedder.as_ref(), sdk.clone(), input.into(), knn, org).await?;
+    let hits = search_service::query_note_passages(
+        org,
+        es_sdk,
+        QueryType::Knn { embeddings },
+        SearchFilters::default(),
+        Page::default(),
+    )
+    .await?;
+    // notes matching the new question come first, followed by the ones the conversation already referenced. The
+    // referenced notes keep their places among the sources, so new matches only fill the slots they leave, always
+    // keeping the best one
+    let referenced = input
+        .referenced_note_ids
+        .iter()
+        .copied()
+        .unique()
+        .collect_vec();
+    let new_slots = CHAT_MAX_SOURCES.saturating_sub(referenced.len()).max(1);
+    let new_ids = hits
+        .iter()
+        .map(|hit| hit.note_id)
+        .filter(|id| !referenced.contains(id))
+        .take(new_slots)
+        .collect_vec();
+    let found_ids = hits
+        .iter()
+        .map(|hit| hit.note_id)
+        .filter(|id| new_ids.contains(id) || referenced.contains(id))
+        .chain(referenced.iter().copied())
+        .unique()
+        .take(CHAT_MAX_SOURCES)
+        .collect_vec();
+    if found_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut hits = hits
+        .into_iter()
+        .map(|hit| (hit.note_id, hit))
+        .collect::<HashMap<_, _>>();
+    Ok(
+        note_service::search_notes(note_repo, attachment_repo, found_ids, org, sdk, aws_sdk)
+            .await?
+            .into_iter()
+            .map(|note| {
+                let hit = hits.remove(&note.id);
+                ChatSource {
+                    passage: hit.as_ref().and_then(|hit| hit.passage),
+                    score: hit.map(|hit| hit.score),
+                    note,
+                }
+            })
+            .collect(),
+    )
+}
+
+pub async fn chat(
+    State(state): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
     Json(input): Json<QueryChatbotRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let embeddings = generate_query_embeddings(ai_sdk.clone(), sdk.clone(), (&input).into(), &org)
+) -> Result<impl IntoResponse, Response> {
+    let chat_model = state.chat_models.for_org(&org.0).map_err(handle_err)?;
+    let template = prompt_templates::for_org(&state.db, &org, &state.prompts)
         .await
         .map_err(handle_err)?;
-    // TODO: an improvement here would be to take in ids as part of the request that are already known to be part of
-    //       the context of the conversation, and pull them out to add to the context, so the conversation could
-    //       continue while referencing earlier notes
-    let found_ids = search_service::query_notes(&org, es_sdk, QueryType::Knn { embeddings })
+    let sources = find_chat_sources(state, &org, &input)
+        .await
+        .map_err(handle_err)?;
+    let result = embeddings::query_chatbot(&*chat_model, &template, sources, input)
         .await
         .map_err(handle_err)?;
-    match found_ids.first() {
-        Some(first_id) => {
-            let decrypted_note = db::search_notes(&db, vec![*first_id], &org, sdk, aws_sdk)
-                .await
-                .map(|notes| NoteSearchResponse { result: notes })
-                .map_err(handle_err)?;
-            let result = embeddings::query_chatbot(
-                ai_sdk,
-                decrypted_note
-                    .result
-                    .first()
-                    .cloned()
-                    .unwrap_or_else(Note::default),
-                input,
-            )
-            .await
-            .map_err(handle_err)?;
 
-            Ok(Json(result))
-        }
-        None => Err(handle_err(anyhow!("Vector search returned no results."))),
-    }
+    Ok(Json(result))
+}
+
+/// Streams the chatbot's answer as Server-Sent Events: a `token` event with `{"content": ...}` for each piece of
+/// the answer, then a `citations` event with the cited notes, or an `error` event if the model fails part way.
+/// The request timeout only covers getting the stream started, so long answers aren't cut off.
+pub async fn chat_stream(
+    State(state): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+    Json(input): Json<QueryChatbotRequest>,
+) -> Result<impl IntoResponse, Response> {
+    let chat_model = state.chat_models.for_org(&org.0).map_err(handle_err)?;
+    let template = prompt_templates::for_org(&state.db, &org, &state.prompts)
+        .await
+        .map_err(handle_err)?;
+    let sources = find_chat_sources(state, &org, &input)
+        .await
+        .map_err(handle_err)?;
+    let events = embeddings::stream_chatbot(&*chat_model, &template, sources, input)
+        .await
+        .map_err(handle_err)?
+        .map(|event| {
+            let event = match event {
+                ChatStreamEvent::Token(content) => Event::default()
+                    .event("token")
+                    .json_data(json!({ "content": content })),
+                ChatStreamEvent::Done(citations) => {
+                    Event::default().event("citations").json_data(citations)
+                }
+                ChatStreamEvent::Error(message) => {
+                    error!("Chat stream failed: {}", message);
+                    Ok(Event::default().event("error").data(message))
+                }
+            };
+            event.map_err(|e| anyhow!(e))
+        });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
 }
@@ -0,0 +1,483 @@
+use crate::{encryption::Encryptor, ATTACHMENT_BUCKET};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::{self as s3, presigning::PresigningConfig, primitives::ByteStream};
+use ironcore_alloy::{
+    standard::{EdekWithKeyIdHeader, EncryptedDocument, PlaintextDocument},
+    EncryptedBytes, FieldId, PlaintextBytes, TenantId,
+};
+use reqwest::Url;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+/// How long presigned URLs stay valid.
+const PRESIGNED_URL_LIFETIME: Duration = Duration::from_secs(9999);
+
+/// What's known about a stored object without downloading it.
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub size: i64,
+    pub content_type: Option<String>,
+    /// Metadata stored along with the object, S3's `x-amz-meta-*` headers.
+    pub metadata: HashMap<String, String>,
+}
+
+/// Where attachments are kept. Implemented by the S3 proxy, which encrypts and decrypts them on the way through,
+/// by any S3 compatible store with the server doing the encryption, and in tests by an in-memory store.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// A URL the browser can download the object from, served with the given content type.
+    async fn presign_get(&self, key: &str, content_type: &str) -> Result<String>;
+
+    /// A URL the browser can upload exactly `content_length` bytes of `content_type` to.
+    async fn presign_put(
+        &self,
+        key: &str,
+        content_length: i64,
+        content_type: &str,
+    ) -> Result<String>;
+
+    /// `None` if there's no object with that key.
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>>;
+
+    async fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        content_type: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<()>;
+
+    /// `None` if there's no object with that key.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Whether browsers upload and download through the server's `/api/attachments/objects/` routes rather than
+    /// straight to the store. Those routes only exist when this is true.
+    fn through_server(&self) -> bool {
+        false
+    }
+}
+
+/// Objects in an S3 bucket.
+pub struct S3ObjectStore {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+}
+
+impl S3ObjectStore {
+    pub fn new(client: aws_sdk_s3::Client, bucket: &str) -> S3ObjectStore {
+        S3ObjectStore {
+            client,
+            bucket: bucket.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn presign_get(&self, key: &str, content_type: &str) -> Result<String> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .response_content_type(content_type)
+            .presigned(PresigningConfig::expires_in(PRESIGNED_URL_LIFETIME)?)
+            .await?;
+        Ok(request.uri().to_string())
+    }
+
+    async fn presign_put(
+        &self,
+        key: &str,
+        content_length: i64,
+        content_type: &str,
+    ) -> Result<String> {
+        // content length and type are part of the signature, so the upload has to match them
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_length(content_length)
+            .content_type(content_type)
+            .presigned(PresigningConfig::expires_in(PRESIGNED_URL_LIFETIME)?)
+            .await?;
+        Ok(request.uri().to_string())
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>> {
+        let response = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        let object = match response {
+            Ok(object) => object,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(ObjectInfo {
+            size: object.content_length().unwrap_or_default(),
+            content_type: object.content_type().map(str::to_string),
+            metadata: object.metadata().cloned().unwrap_or_default(),
+        }))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        content_type: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_type(content_type)
+            .set_metadata(Some(metadata))
+            .body(ByteStream::from(bytes))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        let object = match response {
+            Ok(object) => object,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(object.body.collect().await?.into_bytes().to_vec()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Encrypts objects in the server with the tenant's standard encryption key before storing them in another object
+/// store, and decrypts them when they're read back. Browsers can't encrypt for themselves, so uploads and downloads
+/// go through the server's `/api/attachments/objects/` routes rather than straight to the store.
+pub struct EncryptingObjectStore {
+    inner: Arc<dyn ObjectStore>,
+    sdk: Arc<dyn Encryptor>,
+    public_url: Url,
+}
+
+const CONTENT_FIELD: &str = "content";
+/// Metadata holding the size of the object before it was encrypted, so it can be found without decrypting.
+const PLAINTEXT_LENGTH_METADATA: &str = "plaintext-length";
+
+impl EncryptingObjectStore {
+    pub fn new(
+        inner: Arc<dyn ObjectStore>,
+        sdk: Arc<dyn Encryptor>,
+        public_url: &str,
+    ) -> Result<EncryptingObjectStore> {
+        Ok(EncryptingObjectStore {
+            inner,
+            sdk,
+            public_url: Url::parse(public_url)?,
+        })
+    }
+
+    /// Objects are stored under their tenant's ID, the same way the S3 proxy decides which tenant's key to use.
+    fn tenant_id(key: &str) -> Result<TenantId> {
+        let (tenant, _) = key
+            .split_once('/')
+            .ok_or_else(|| anyhow!("Object key `{key}` doesn't start with a tenant ID."))?;
+        Ok(TenantId(tenant.to_string()))
+    }
+
+    /// The server's route for reading and writing the object, with the key as percent encoded path segments.
+    fn object_url(&self, key: &str) -> Result<String> {
+        let mut url = self.public_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("SERVER_PUBLIC_URL can't be used as a base URL."))?
+            .pop_if_empty()
+            .extend(["api", "attachments", "objects"])
+            .extend(key.split('/'));
+        Ok(url.to_string())
+    }
+
+    /// Stored as the EDEK's length as 4 big endian bytes, the EDEK, then the encrypted content.
+    fn seal(document: EncryptedDocument) -> Result<Vec<u8>> {
+        let content = document
+            .document
+            .into_values()
+            .next()
+            .ok_or_else(|| anyhow!("ironcore_alloy didn't encrypt the object"))?;
+        let edek = document.edek.0 .0;
+        Ok([
+            (edek.len() as u32).to_be_bytes().as_slice(),
+            &edek,
+            &content.0,
+        ]
+        .concat())
+    }
+
+    fn unseal(bytes: Vec<u8>) -> Result<EncryptedDocument> {
+        let not_encrypted = || anyhow!("Object wasn't encrypted by the server.");
+        let (length, rest) = bytes.split_first_chunk::<4>().ok_or_else(not_encrypted)?;
+        let length = u32::from_be_bytes(*length) as usize;
+        if rest.len() < length {
+            return Err(not_encrypted());
+        }
+        let (edek, content) = rest.split_at(length);
+        Ok(EncryptedDocument {
+            edek: EdekWithKeyIdHeader(EncryptedBytes(edek.to_vec())),
+            document: [(
+                FieldId(CONTENT_FIELD.to_string()),
+                EncryptedBytes(content.to_vec()),
+            )]
+            .into(),
+        })
+    }
+}
+
+#[async_trait]
+impl ObjectStore for EncryptingObjectStore {
+    async fn presign_get(&self, key: &str, _content_type: &str) -> Result<String> {
+        self.object_url(key)
+    }
+
+    async fn presign_put(
+        &self,
+        key: &str,
+        _content_length: i64,
+        _content_type: &str,
+    ) -> Result<String> {
+        self.object_url(key)
+    }
+
+    /// The stored object is larger than what was uploaded, so the real size comes from the metadata it was stored
+    /// with.
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>> {
+        let Some(mut info) = self.inner.head(key).await? else {
+            return Ok(None);
+        };
+        info.size = info
+            .metadata
+            .remove(PLAINTEXT_LENGTH_METADATA)
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(|| anyhow!("Object `{key}` wasn't stored by the server."))?;
+        Ok(Some(info))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        content_type: &str,
+        mut metadata: HashMap<String, String>,
+    ) -> Result<()> {
+        metadata.insert(
+            PLAINTEXT_LENGTH_METADATA.to_string(),
+            bytes.len().to_string(),
+        );
+        let document =
+            PlaintextDocument([(FieldId(CONTENT_FIELD.to_string()), PlaintextBytes(bytes))].into());
+        let encrypted = self.sdk.encrypt(document, &Self::tenant_id(key)?).await?;
+        self.inner
+            .put(key, Self::seal(encrypted)?, content_type, metadata)
+            .await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(bytes) = self.inner.get(key).await? else {
+            return Ok(None);
+        };
+        let encrypted = Self::unseal(bytes)?;
+        let mut decrypted = self.sdk.decrypt(encrypted, &Self::tenant_id(key)?).await?;
+        Ok(Some(
+            decrypted
+                .0
+                .remove(&FieldId(CONTENT_FIELD.to_string()))
+                .ok_or_else(|| anyhow!("ironcore_alloy didn't decrypt the object"))?
+                .0,
+        ))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key).await
+    }
+
+    fn through_server(&self) -> bool {
+        true
+    }
+}
+
+/// Where attachments are stored, from `ATTACHMENT_ENCRYPTION`: `proxy` (the default) goes through the SaaS Shield
+/// S3 proxy, `app` encrypts in the server and stores in any S3 compatible store at `S3_ENDPOINT_URL`.
+pub async fn from_env(sdk: Arc<dyn Encryptor>) -> Result<Arc<dyn ObjectStore>> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let mode = var("ATTACHMENT_ENCRYPTION").unwrap_or_else(|| "proxy".to_string());
+    let default_endpoint = match mode.as_str() {
+        "proxy" => "http://localhost:8080",
+        "app" => "http://localhost:9000",
+        other => return Err(anyhow!("Unknown ATTACHMENT_ENCRYPTION `{other}`.")),
+    };
+    // This is the way to do path style with the aws sdk in rust
+    let shared_config = aws_config::from_env()
+        .endpoint_url(var("S3_ENDPOINT_URL").unwrap_or_else(|| default_endpoint.to_string()))
+        .load()
+        .await;
+    let s3_config_builder: s3::config::Builder = (&shared_config).into();
+    let final_config = s3_config_builder.force_path_style(true).build();
+    let s3 = Arc::new(S3ObjectStore::new(
+        s3::Client::from_conf(final_config),
+        ATTACHMENT_BUCKET,
+    ));
+    if mode == "app" {
+        let public_url =
+            var("SERVER_PUBLIC_URL").unwrap_or_else(|| "http://localhost:7654".to_string());
+        Ok(Arc::new(EncryptingObjectStore::new(s3, sdk, &public_url)?))
+    } else {
+        Ok(s3)
+    }
+}
+
+#[cfg(test)]
+pub use memory::InMemoryObjectStore;
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Objects kept in memory. Presigned URLs can't actually be used, so tests upload with `put` instead.
+    #[derive(Debug, Default)]
+    pub struct InMemoryObjectStore {
+        objects: Mutex<HashMap<String, (Vec<u8>, ObjectInfo)>>,
+    }
+
+    #[async_trait]
+    impl ObjectStore for InMemoryObjectStore {
+        async fn presign_get(&self, key: &str, _content_type: &str) -> Result<String> {
+            Ok(format!("memory://{key}"))
+        }
+
+        async fn presign_put(
+            &self,
+            key: &str,
+            _content_length: i64,
+            _content_type: &str,
+        ) -> Result<String> {
+            Ok(format!("memory://{key}"))
+        }
+
+        async fn head(&self, key: &str) -> Result<Option<ObjectInfo>> {
+            Ok(self
+                .objects
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|(_, info)| info.clone()))
+        }
+
+        async fn put(
+            &self,
+            key: &str,
+            bytes: Vec<u8>,
+            content_type: &str,
+            metadata: HashMap<String, String>,
+        ) -> Result<()> {
+            let info = ObjectInfo {
+                size: bytes.len() as i64,
+                content_type: Some(content_type.to_string()),
+                metadata,
+            };
+            self.objects
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), (bytes, info));
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self
+                .objects
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|(bytes, _)| bytes.clone()))
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(edek: &[u8], content: &[u8]) -> EncryptedDocument {
+        EncryptedDocument {
+            edek: EdekWithKeyIdHeader(EncryptedBytes(edek.to_vec())),
+            document: [(
+                FieldId(CONTENT_FIELD.to_string()),
+                EncryptedBytes(content.to_vec()),
+            )]
+            .into(),
+        }
+    }
+
+    fn parts(document: EncryptedDocument) -> (Vec<u8>, Vec<u8>) {
+        let content = document.document.into_values().next().unwrap();
+        (document.edek.0 .0, content.0)
+    }
+
+    #[test]
+    fn sealed_objects_unseal_to_the_same_document() {
+        for (edek, content) in [
+            (&b"edek"[..], &b"ciphertext"[..]),
+            (b"edek", b""),
+            (b"", b"ciphertext"),
+        ] {
+            let sealed = EncryptingObjectStore::seal(document(edek, content)).unwrap();
+            assert_eq!(sealed.len(), 4 + edek.len() + content.len());
+            let unsealed = EncryptingObjectStore::unseal(sealed).unwrap();
+            assert_eq!(parts(unsealed), (edek.to_vec(), content.to_vec()));
+        }
+    }
+
+    #[test]
+    fn truncated_objects_are_not_unsealed() {
+        let sealed = EncryptingObjectStore::seal(document(b"edek", b"")).unwrap();
+        for length in 0..4 {
+            assert!(
+                EncryptingObjectStore::unseal(sealed[..length].to_vec()).is_err(),
+                "a {length} byte length prefix"
+            );
+        }
+        for length in 4..sealed.len() {
+            assert!(
+                EncryptingObjectStore::unseal(sealed[..length].to_vec()).is_err(),
+                "an EDEK cut off after {} bytes",
+                length - 4
+            );
+        }
+        assert!(EncryptingObjectStore::unseal(b"plain text".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,322 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt::Display;
+
+use crate::{
+    db::{self, PromptTemplateTable},
+    db_pool::DbPool,
+    embeddings::Prompts,
+    error::handle_err,
+    AppState, CurrentOrganization,
+};
+
+/// Longest each part of a template can be, in characters.
+const MAX_TEMPLATE_LENGTH: usize = 8000;
+
+/// How an organization's chat prompt is put together. Each part can refer to values with `{placeholder}`s:
+///
+/// - `system` is the system prompt. It has to include `{notes}`, and can use `{question}`, `{history}`, and
+///   `{answer_style}`. If `{history}` is used the earlier messages are written into the prompt instead of being
+///   sent as separate messages.
+/// - `note_format` is how each source note is written into `{notes}`. It has to include `{number}` and `{text}`,
+///   and can use `{title}`. The answer cites notes by `[number]`, so the number should be written that way.
+/// - `answer_style` is free text describing how to answer. It's put in place of `{answer_style}`, or after the
+///   system prompt if that isn't used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptTemplate {
+    pub system: String,
+    pub note_format: String,
+    #[serde(default)]
+    pub answer_style: String,
+}
+
+const SYSTEM_PLACEHOLDERS: [&str; 4] = ["notes", "question", "history", "answer_style"];
+const NOTE_FORMAT_PLACEHOLDERS: [&str; 3] = ["number", "title", "text"];
+
+/// A template that can't be used. Reported back to the caller as a 422 with a message.
+#[derive(Debug)]
+pub enum InvalidPromptTemplate {
+    TooLong(&'static str),
+    UnknownPlaceholder {
+        part: &'static str,
+        name: String,
+    },
+    MissingPlaceholder {
+        part: &'static str,
+        name: &'static str,
+    },
+}
+
+impl Display for InvalidPromptTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidPromptTemplate::TooLong(part) => write!(
+                f,
+                "`{part}` can be at most {MAX_TEMPLATE_LENGTH} characters."
+            ),
+            InvalidPromptTemplate::UnknownPlaceholder { part, name } => {
+                write!(f, "`{part}` uses the unknown placeholder `{{{name}}}`.")
+            }
+            InvalidPromptTemplate::MissingPlaceholder { part, name } => {
+                write!(f, "`{part}` has to include the `{{{name}}}` placeholder.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidPromptTemplate {}
+
+impl IntoResponse for InvalidPromptTemplate {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": self.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+/// The names of the `{placeholder}`s in a template, in order.
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template.split('{').skip(1).filter_map(|rest| {
+        let (name, _) = rest.split_once('}')?;
+        is_placeholder_name(name).then_some(name)
+    })
+}
+
+fn is_placeholder_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+}
+
+/// Fills in a template's placeholders in a single pass, so placeholder-like text in the values (a note that
+/// mentions `{question}`, say) is left alone.
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let value = after.split_once('}').and_then(|(name, _)| {
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (name.len(), value))
+        });
+        match value {
+            Some((name_length, value)) => {
+                rendered.push_str(value);
+                rest = &after[name_length + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn check_part(
+    part: &'static str,
+    template: &str,
+    allowed: &[&str],
+    required: &[&'static str],
+) -> Result<(), InvalidPromptTemplate> {
+    if template.chars().count() > MAX_TEMPLATE_LENGTH {
+        return Err(InvalidPromptTemplate::TooLong(part));
+    }
+    if let Some(name) = placeholders(template).find(|name| !allowed.contains(name)) {
+        return Err(InvalidPromptTemplate::UnknownPlaceholder {
+            part,
+            name: name.to_string(),
+        });
+    }
+    match required
+        .iter()
+        .find(|name| !placeholders(template).any(|used| used == **name))
+    {
+        Some(name) => Err(InvalidPromptTemplate::MissingPlaceholder { part, name }),
+        None => Ok(()),
+    }
+}
+
+impl PromptTemplate {
+    /// The template used until an organization saves its own, built from the server's chat system prompt.
+    pub fn default_for(prompts: &Prompts) -> PromptTemplate {
+        PromptTemplate {
+            system: format!("{}\n\nnotes:\n{{notes}}", prompts.chat),
+            note_format: "[{number}] {title}: {text}".to_string(),
+            answer_style: String::new(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), InvalidPromptTemplate> {
+        check_part("system", &self.system, &SYSTEM_PLACEHOLDERS, &["notes"])?;
+        check_part(
+            "note_format",
+            &self.note_format,
+            &NOTE_FORMAT_PLACEHOLDERS,
+            &["number", "text"],
+        )?;
+        check_part("answer_style", &self.answer_style, &[], &[])
+    }
+
+    /// Whether the conversation so far is written into the system prompt rather than sent as messages.
+    pub fn includes_history(&self) -> bool {
+        placeholders(&self.system).any(|name| name == "history")
+    }
+
+    pub fn render_note(&self, number: usize, title: &str, text: &str) -> String {
+        render(
+            &self.note_format,
+            &[
+                ("number", &number.to_string()),
+                ("title", title),
+                ("text", text),
+            ],
+        )
+    }
+
+    pub fn render_system(&self, notes: &str, question: &str, history: &str) -> String {
+        let uses_answer_style = placeholders(&self.system).any(|name| name == "answer_style");
+        let system = render(
+            &self.system,
+            &[
+                ("notes", notes),
+                ("question", question),
+                ("history", history),
+                ("answer_style", &self.answer_style),
+            ],
+        );
+        if uses_answer_style || self.answer_style.trim().is_empty() {
+            system
+        } else {
+            format!("{system}\n\n{}", self.answer_style)
+        }
+    }
+}
+
+impl From<PromptTemplateTable> for PromptTemplate {
+    fn from(table: PromptTemplateTable) -> Self {
+        PromptTemplate {
+            system: table.system,
+            note_format: table.note_format,
+            answer_style: table.answer_style,
+        }
+    }
+}
+
+/// The template the organization's chat should use right now.
+pub async fn for_org(
+    db: &DbPool,
+    org: &CurrentOrganization,
+    prompts: &Prompts,
+) -> anyhow::Result<PromptTemplate> {
+    Ok(db::get_prompt_template(db, org)
+        .await?
+        .map(PromptTemplate::from)
+        .unwrap_or_else(|| PromptTemplate::default_for(prompts)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct PromptTemplateResponse {
+    /// `None` when the organization is using the server's default template.
+    version: Option<u32>,
+    created: Option<String>,
+    #[serde(flatten)]
+    template: PromptTemplate,
+}
+
+impl From<PromptTemplateTable> for PromptTemplateResponse {
+    fn from(table: PromptTemplateTable) -> Self {
+        PromptTemplateResponse {
+            version: Some(table.version),
+            created: Some(table.created.clone()),
+            template: table.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PromptTemplateListResponse {
+    result: Vec<PromptTemplateResponse>,
+}
+
+pub async fn get(
+    State(AppState { db, prompts, .. }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+) -> Result<impl IntoResponse, Response> {
+    let current = db::get_prompt_template(&db, &org)
+        .await
+        .map_err(handle_err)?;
+    Ok(Json(match current {
+        Some(current) => current.into(),
+        None => PromptTemplateResponse {
+            version: None,
+            created: None,
+            template: PromptTemplate::default_for(&prompts),
+        },
+    }))
+}
+
+/// Versions saved at the same time can both be numbered the same, in which case only the first is saved and the
+/// other is reported as a 409 to be tried again.
+fn handle_save_err(e: anyhow::Error) -> Response {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db_error)) if db_error.is_unique_violation() => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Another version of the template was saved at the same time." })),
+        )
+            .into_response(),
+        _ => handle_err(e),
+    }
+}
+
+/// Saves a new version of the template, which is used from then on.
+pub async fn put(
+    State(AppState { db, .. }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+    Json(input): Json<PromptTemplate>,
+) -> Result<impl IntoResponse, Response> {
+    input.validate().map_err(IntoResponse::into_response)?;
+    let saved = db::put_prompt_template(&db, &org, &input)
+        .await
+        .map_err(handle_save_err)?;
+    Ok(Json(PromptTemplateResponse::from(saved)))
+}
+
+pub async fn list_versions(
+    State(AppState { db, .. }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+) -> Result<impl IntoResponse, Response> {
+    let result = db::list_prompt_templates(&db, &org)
+        .await
+        .map_err(handle_err)?
+        .into_iter()
+        .map(PromptTemplateResponse::from)
+        .collect();
+    Ok(Json(PromptTemplateListResponse { result }))
+}
+
+/// Goes back to an earlier version by saving a copy of it as the newest version, so the history is kept.
+pub async fn restore(
+    Path(version): Path<u32>,
+    State(AppState { db, .. }): State<AppState>,
+    Extension(org): Extension<CurrentOrganization>,
+) -> Result<impl IntoResponse, Response> {
+    let earlier = db::get_prompt_template_version(&db, &org, version)
+        .await
+        .map_err(handle_err)?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    let saved = db::put_prompt_template(&db, &org, &earlier.into())
+        .await
+        .map_err(handle_save_err)?;
+    Ok(Json(PromptTemplateResponse::from(saved)))
+}
@@ -0,0 +1,724 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use itertools::Itertools;
+
+use crate::{
+    attachments::{AttachmentPolicy, PolicyViolation},
+    db::{
+        current_timestamp, AttachmentPolicyTable, AttachmentTable,
+        DeterministicallyEncryptedString, EncryptedNote, EncryptedString, NoteIndexFields,
+        NoteTable, OnlyCategory, OnlyEdek,
+    },
+    db_pool::{with_pool, DbPool},
+};
+
+/// Where notes are stored. Everything sensitive arrives already encrypted and is handed back the same way, so a
+/// repository never needs the keys; `note_service` does the encrypting and decrypting.
+#[async_trait]
+pub trait NoteRepository: Send + Sync {
+    /// Stores a new note and puts the attachments on it. Fails if any of the attachments don't exist in the
+    /// organization.
+    async fn create(
+        &self,
+        org_id: u32,
+        note: EncryptedNote,
+        attachment_ids: &[u32],
+    ) -> Result<(NoteTable, Vec<AttachmentTable>)>;
+
+    /// Replaces the note's content and attachments, and clears its summary. Fails if any of the attachments don't
+    /// exist in the organization.
+    async fn update(
+        &self,
+        id: u32,
+        org_id: u32,
+        note: EncryptedNote,
+        attachment_ids: &[u32],
+    ) -> Result<(NoteTable, Vec<AttachmentTable>)>;
+
+    async fn get(&self, id: u32, org_id: u32) -> Result<Option<NoteTable>>;
+
+    /// The organization's notes, or only those in the (deterministically encrypted) category.
+    async fn list(
+        &self,
+        org_id: u32,
+        category: Option<DeterministicallyEncryptedString>,
+    ) -> Result<Vec<NoteTable>>;
+
+    /// The notes with these IDs that belong to the organization, in no particular order.
+    async fn get_many(&self, org_id: u32, ids: &[u32]) -> Result<Vec<NoteTable>>;
+
+    async fn get_edek(&self, id: u32, org_id: u32) -> Result<Option<OnlyEdek>>;
+
+    /// Returns how many notes were updated.
+    async fn put_edek(&self, id: u32, org_id: u32, edek: EncryptedString) -> Result<u32>;
+
+    async fn put_summary(&self, id: u32, org_id: u32, summary: EncryptedString) -> Result<()>;
+
+    async fn get_index_fields(&self, id: u32, org_id: u32) -> Result<NoteIndexFields>;
+
+    /// The fingerprint of the content the note's search document was last built from, see `FingerprintKey`.
+    async fn get_content_fingerprint(&self, id: u32, org_id: u32) -> Result<Option<String>>;
+
+    /// Records the fingerprint of the content a note was just indexed with.
+    async fn put_content_fingerprint(
+        &self,
+        id: u32,
+        org_id: u32,
+        fingerprint: String,
+    ) -> Result<()>;
+
+    /// Every distinct category the organization's notes are in.
+    async fn list_categories(&self, org_id: u32) -> Result<Vec<DeterministicallyEncryptedString>>;
+}
+
+/// Where attachment records and the policies they're held to are stored. The attachments' content is in the
+/// `ObjectStore`.
+#[async_trait]
+pub trait AttachmentRepository: Send + Sync {
+    /// Records an attachment that's about to be uploaded. It isn't confirmed until `confirm` is called.
+    async fn create(
+        &self,
+        org_id: u32,
+        filename: String,
+        content_type: &str,
+        size: i64,
+    ) -> Result<AttachmentTable>;
+
+    async fn get(&self, id: u32, org_id: u32) -> Result<Option<AttachmentTable>>;
+
+    async fn list_for_note(&self, note_id: u32) -> Result<Vec<AttachmentTable>>;
+
+    /// Marks the attachment as uploaded, with the size it turned out to be.
+    async fn confirm(&self, id: u32, size: i64) -> Result<()>;
+
+    async fn delete(&self, id: u32) -> Result<()>;
+
+    /// The organization's attachment policy, or the default if it doesn't have one.
+    async fn get_policy(&self, org_id: u32) -> Result<AttachmentPolicy>;
+}
+
+/// Both repositories, in whichever database the pool is for.
+pub struct SqlRepository {
+    db: DbPool,
+}
+
+impl SqlRepository {
+    pub fn new(db: DbPool) -> SqlRepository {
+        SqlRepository { db }
+    }
+}
+
+#[async_trait]
+impl NoteRepository for SqlRepository {
+    async fn create(
+        &self,
+        org_id: u32,
+        note: EncryptedNote,
+        attachment_ids: &[u32],
+    ) -> Result<(NoteTable, Vec<AttachmentTable>)> {
+        Ok(with_pool!(&self.db, |pool| {
+            let mut trx = pool.begin().await?;
+            let res = sqlx::query_as::<_, NoteTable>(
+                "INSERT INTO note (org_id, title, body, category, edek) VALUES ($1, $2, $3, $4, $5) RETURNING *",
+            )
+            .bind(i64::from(org_id))
+            .bind(note.title)
+            .bind(note.body)
+            .bind(note.category)
+            .bind(note.edek)
+            .fetch_one(&mut *trx)
+            .await?;
+
+            let mut updated_attachments: Vec<AttachmentTable> =
+                Vec::with_capacity(attachment_ids.len());
+            for attachment_id in attachment_ids.iter() {
+                let updated = sqlx::query_as::<_, AttachmentTable>(
+                    "UPDATE attachment SET note_id = $1 WHERE id = $2 AND org_id = $3 RETURNING *",
+                )
+                .bind(i64::from(res.id))
+                .bind(i64::from(*attachment_id))
+                .bind(i64::from(org_id))
+                .fetch_optional(&mut *trx)
+                .await?;
+
+                match updated {
+                    Some(attachment) => updated_attachments.push(attachment),
+                    None => Err(PolicyViolation::UnknownAttachment(*attachment_id))?,
+                }
+            }
+            trx.commit().await?;
+            (res, updated_attachments)
+        }))
+    }
+
+    async fn update(
+        &self,
+        id: u32,
+        org_id: u32,
+        note: EncryptedNote,
+        attachment_ids: &[u32],
+    ) -> Result<(NoteTable, Vec<AttachmentTable>)> {
+        Ok(with_pool!(&self.db, |pool| {
+            let mut trx = pool.begin().await?;
+            let res = sqlx::query_as::<_, NoteTable>(
+                "UPDATE note SET title = $1, body = $2, category = $3, edek = $4, summary = NULL, updated = $5 WHERE id = $6 AND org_id = $7 RETURNING *",
+            )
+            .bind(note.title)
+            .bind(note.body)
+            .bind(note.category)
+            .bind(note.edek)
+            .bind(current_timestamp())
+            .bind(i64::from(id))
+            .bind(i64::from(org_id))
+            .fetch_one(&mut *trx)
+            .await?;
+
+            // clear all the existing attachments
+            sqlx::query("UPDATE attachment SET note_id = NULL WHERE note_id=$1")
+                .bind(i64::from(res.id))
+                .execute(&mut *trx)
+                .await?;
+
+            let mut updated_attachments: Vec<AttachmentTable> =
+                Vec::with_capacity(attachment_ids.len());
+            for attachment_id in attachment_ids.iter() {
+                let updated = sqlx::query_as::<_, AttachmentTable>(
+                    "UPDATE attachment SET note_id = $1 WHERE id = $2 AND org_id = $3 RETURNING *",
+                )
+                .bind(i64::from(res.id))
+                .bind(i64::from(*attachment_id))
+                .bind(i64::from(org_id))
+                .fetch_optional(&mut *trx)
+                .await?;
+
+                match updated {
+                    Some(attachment) => updated_attachments.push(attachment),
+                    None => Err(PolicyViolation::UnknownAttachment(*attachment_id))?,
+                }
+            }
+
+            trx.commit().await?;
+            (res, updated_attachments)
+        }))
+    }
+
+    async fn get(&self, id: u32, org_id: u32) -> Result<Option<NoteTable>> {
+        Ok(with_pool!(&self.db, |pool| {
+            sqlx::query_as::<_, NoteTable>("SELECT * FROM note WHERE id = $1 AND org_id = $2")
+                .bind(i64::from(id))
+                .bind(i64::from(org_id))
+                .fetch_optional(pool)
+                .await?
+        }))
+    }
+
+    async fn list(
+        &self,
+        org_id: u32,
+        category: Option<DeterministicallyEncryptedString>,
+    ) -> Result<Vec<NoteTable>> {
+        Ok(with_pool!(&self.db, |pool| {
+            let query = match category {
+                Some(cat) => sqlx::query_as::<_, NoteTable>(
+                    "SELECT * FROM note WHERE note.org_id=$1 AND note.category IS NOT NULL AND note.category=$2",
+                )
+                .bind(i64::from(org_id))
+                .bind(cat.0),
+                None => sqlx::query_as::<_, NoteTable>("SELECT * FROM note WHERE note.org_id=$1")
+                    .bind(i64::from(org_id)),
+            };
+            query.fetch_all(pool).await?
+        }))
+    }
+
+    async fn get_many(&self, org_id: u32, ids: &[u32]) -> Result<Vec<NoteTable>> {
+        // Postgres doesn't allow an empty `IN ()`
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        // numbered placeholders work on both backends, the organization is $1
+        let parameters = (2..ids.len() + 2).map(|n| format!("${n}")).join(", ");
+        let sql = format!(
+            "SELECT * FROM note WHERE note.org_id=$1 AND note.id IN ({})",
+            parameters
+        );
+        Ok(with_pool!(&self.db, |pool| {
+            let mut query = sqlx::query_as::<_, NoteTable>(&sql).bind(i64::from(org_id));
+            for id in ids {
+                query = query.bind(i64::from(*id));
+            }
+            query.fetch_all(pool).await?
+        }))
+    }
+
+    async fn get_edek(&self, id: u32, org_id: u32) -> Result<Option<OnlyEdek>> {
+        Ok(with_pool!(&self.db, |pool| {
+            sqlx::query_as::<_, OnlyEdek>("SELECT edek FROM note WHERE id = $1 AND org_id = $2")
+                .bind(i64::from(id))
+                .bind(i64::from(org_id))
+                .fetch_optional(pool)
+                .await?
+        }))
+    }
+
+    async fn put_edek(&self, id: u32, org_id: u32, edek: EncryptedString) -> Result<u32> {
+        let rows_affected = with_pool!(&self.db, |pool| {
+            sqlx::query("UPDATE note SET edek=$1 WHERE id = $2 AND org_id = $3")
+                .bind(edek.0)
+                .bind(i64::from(id))
+                .bind(i64::from(org_id))
+                .execute(pool)
+                .await?
+                .rows_affected()
+        });
+        Ok(rows_affected as u32)
+    }
+
+    async fn put_summary(&self, id: u32, org_id: u32, summary: EncryptedString) -> Result<()> {
+        with_pool!(&self.db, |pool| {
+            sqlx::query("UPDATE note SET summary = $1 WHERE id = $2 AND org_id = $3")
+                .bind(summary)
+                .bind(i64::from(id))
+                .bind(i64::from(org_id))
+                .execute(pool)
+                .await?;
+        });
+        Ok(())
+    }
+
+    async fn get_index_fields(&self, id: u32, org_id: u32) -> Result<NoteIndexFields> {
+        Ok(with_pool!(&self.db, |pool| {
+            sqlx::query_as::<_, NoteIndexFields>(
+                "SELECT category, created, updated FROM note WHERE id = $1 AND org_id = $2",
+            )
+            .bind(i64::from(id))
+            .bind(i64::from(org_id))
+            .fetch_one(pool)
+            .await?
+        }))
+    }
+
+    async fn get_content_fingerprint(&self, id: u32, org_id: u32) -> Result<Option<String>> {
+        Ok(with_pool!(&self.db, |pool| {
+            sqlx::query_scalar::<_, Option<String>>(
+                "SELECT content_fingerprint FROM note WHERE id = $1 AND org_id = $2",
+            )
+            .bind(i64::from(id))
+            .bind(i64::from(org_id))
+            .fetch_optional(pool)
+            .await?
+        })
+        .flatten())
+    }
+
+    async fn put_content_fingerprint(
+        &self,
+        id: u32,
+        org_id: u32,
+        fingerprint: String,
+    ) -> Result<()> {
+        with_pool!(&self.db, |pool| {
+            sqlx::query("UPDATE note SET content_fingerprint = $1 WHERE id = $2 AND org_id = $3")
+                .bind(fingerprint)
+                .bind(i64::from(id))
+                .bind(i64::from(org_id))
+                .execute(pool)
+                .await?;
+        });
+        Ok(())
+    }
+
+    async fn list_categories(&self, org_id: u32) -> Result<Vec<DeterministicallyEncryptedString>> {
+        let result = with_pool!(&self.db, |pool| {
+            sqlx::query_as::<_, OnlyCategory>(
+                "SELECT DISTINCT n.category FROM organization AS o JOIN note AS n ON n.org_id = o.id WHERE o.id=$1 AND n.category IS NOT NULL",
+            )
+            .bind(i64::from(org_id))
+            .fetch_all(pool)
+            .await?
+        });
+        Ok(result.into_iter().map(|c| c.category).collect())
+    }
+}
+
+#[async_trait]
+impl AttachmentRepository for SqlRepository {
+    async fn create(
+        &self,
+        org_id: u32,
+        filename: String,
+        content_type: &str,
+        size: i64,
+    ) -> Result<AttachmentTable> {
+        Ok(with_pool!(&self.db, |pool| {
+            sqlx::query_as::<_, AttachmentTable>(
+                "INSERT INTO attachment (filename, org_id, content_type, size) VALUES ($1, $2, $3, $4) RETURNING *",
+            )
+            .bind(filename)
+            .bind(i64::from(org_id))
+            .bind(content_type)
+            .bind(size)
+            .fetch_one(pool)
+            .await?
+        }))
+    }
+
+    async fn get(&self, id: u32, org_id: u32) -> Result<Option<AttachmentTable>> {
+        Ok(with_pool!(&self.db, |pool| {
+            sqlx::query_as::<_, AttachmentTable>(
+                "SELECT * FROM attachment WHERE id = $1 AND org_id = $2",
+            )
+            .bind(i64::from(id))
+            .bind(i64::from(org_id))
+            .fetch_optional(pool)
+            .await?
+        }))
+    }
+
+    async fn list_for_note(&self, note_id: u32) -> Result<Vec<AttachmentTable>> {
+        Ok(with_pool!(&self.db, |pool| {
+            sqlx::query_as::<_, AttachmentTable>("SELECT * FROM attachment WHERE note_id = $1")
+                .bind(i64::from(note_id))
+                .fetch_all(pool)
+                .await?
+        }))
+    }
+
+    async fn confirm(&self, id: u32, size: i64) -> Result<()> {
+        with_pool!(&self.db, |pool| {
+            sqlx::query("UPDATE attachment SET confirmed = TRUE, size = $1 WHERE id = $2")
+                .bind(size)
+                .bind(i64::from(id))
+                .execute(pool)
+                .await?;
+        });
+        Ok(())
+    }
+
+    async fn delete(&self, id: u32) -> Result<()> {
+        with_pool!(&self.db, |pool| {
+            sqlx::query("DELETE FROM attachment WHERE id = $1")
+                .bind(i64::from(id))
+                .execute(pool)
+                .await?;
+        });
+        Ok(())
+    }
+
+    async fn get_policy(&self, org_id: u32) -> Result<AttachmentPolicy> {
+        let policy = with_pool!(&self.db, |pool| {
+            sqlx::query_as::<_, AttachmentPolicyTable>(
+                "SELECT * FROM attachment_policy WHERE org_id = $1",
+            )
+            .bind(i64::from(org_id))
+            .fetch_optional(pool)
+            .await?
+        });
+        Ok(policy.map(AttachmentPolicy::from).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+pub use memory::InMemoryRepository;
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use anyhow::anyhow;
+    use std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        sync::Mutex,
+    };
+
+    #[derive(Debug, Default)]
+    struct Tables {
+        notes: BTreeMap<u32, NoteTable>,
+        fingerprints: HashMap<u32, String>,
+        attachments: BTreeMap<u32, AttachmentTable>,
+        next_id: u32,
+    }
+
+    impl Tables {
+        fn next_id(&mut self) -> u32 {
+            self.next_id += 1;
+            self.next_id
+        }
+
+        fn note(&self, id: u32, org_id: u32) -> Option<&NoteTable> {
+            self.notes.get(&id).filter(|note| note.org_id == org_id)
+        }
+
+        fn note_mut(&mut self, id: u32, org_id: u32) -> Option<&mut NoteTable> {
+            self.notes.get_mut(&id).filter(|note| note.org_id == org_id)
+        }
+
+        /// Fails, like the SQL repository, if any of the attachments aren't the organization's.
+        fn check_attachments(&self, org_id: u32, attachment_ids: &[u32]) -> Result<()> {
+            match attachment_ids
+                .iter()
+                .find(|id| self.attachments.get(id).and_then(|a| a.org_id) != Some(org_id.into()))
+            {
+                Some(missing) => Err(PolicyViolation::UnknownAttachment(*missing))?,
+                None => Ok(()),
+            }
+        }
+
+        fn attach(&mut self, note_id: u32, attachment_ids: &[u32]) -> Vec<AttachmentTable> {
+            attachment_ids
+                .iter()
+                .filter_map(|id| {
+                    let attachment = self.attachments.get_mut(id)?;
+                    attachment.note_id = Some(note_id.into());
+                    Some(attachment.clone())
+                })
+                .collect()
+        }
+    }
+
+    /// Rows kept in memory, so `note_service` can be tested without a database. Every organization gets the
+    /// default attachment policy.
+    #[derive(Debug, Default)]
+    pub struct InMemoryRepository {
+        tables: Mutex<Tables>,
+    }
+
+    impl InMemoryRepository {
+        /// The note as it's stored, encrypted.
+        pub fn stored_note(&self, id: u32) -> Option<NoteTable> {
+            self.tables.lock().unwrap().notes.get(&id).cloned()
+        }
+    }
+
+    #[async_trait]
+    impl NoteRepository for InMemoryRepository {
+        async fn create(
+            &self,
+            org_id: u32,
+            note: EncryptedNote,
+            attachment_ids: &[u32],
+        ) -> Result<(NoteTable, Vec<AttachmentTable>)> {
+            let mut tables = self.tables.lock().unwrap();
+            tables.check_attachments(org_id, attachment_ids)?;
+            let id = tables.next_id();
+            let row = NoteTable {
+                id,
+                org_id,
+                category: note.category,
+                enc_title: note.title,
+                enc_body: note.body,
+                enc_summary: None,
+                edek: note.edek,
+                created: current_timestamp(),
+                updated: current_timestamp(),
+            };
+            tables.notes.insert(id, row.clone());
+            let attachments = tables.attach(id, attachment_ids);
+            Ok((row, attachments))
+        }
+
+        async fn update(
+            &self,
+            id: u32,
+            org_id: u32,
+            note: EncryptedNote,
+            attachment_ids: &[u32],
+        ) -> Result<(NoteTable, Vec<AttachmentTable>)> {
+            let mut tables = self.tables.lock().unwrap();
+            tables.check_attachments(org_id, attachment_ids)?;
+            let row = tables
+                .note_mut(id, org_id)
+                .ok_or_else(|| anyhow!("Note {id} doesn't exist"))?;
+            row.category = note.category;
+            row.enc_title = note.title;
+            row.enc_body = note.body;
+            row.enc_summary = None;
+            row.edek = note.edek;
+            row.updated = current_timestamp();
+            let row = row.clone();
+            for attachment in tables.attachments.values_mut() {
+                if attachment.note_id == Some(id.into()) {
+                    attachment.note_id = None;
+                }
+            }
+            let attachments = tables.attach(id, attachment_ids);
+            Ok((row, attachments))
+        }
+
+        async fn get(&self, id: u32, org_id: u32) -> Result<Option<NoteTable>> {
+            Ok(self.tables.lock().unwrap().note(id, org_id).cloned())
+        }
+
+        async fn list(
+            &self,
+            org_id: u32,
+            category: Option<DeterministicallyEncryptedString>,
+        ) -> Result<Vec<NoteTable>> {
+            Ok(self
+                .tables
+                .lock()
+                .unwrap()
+                .notes
+                .values()
+                .filter(|note| note.org_id == org_id)
+                .filter(|note| match (&category, &note.category) {
+                    (None, _) => true,
+                    (Some(wanted), Some(category)) => wanted.0 == category.0,
+                    (Some(_), None) => false,
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn get_many(&self, org_id: u32, ids: &[u32]) -> Result<Vec<NoteTable>> {
+            let tables = self.tables.lock().unwrap();
+            Ok(ids
+                .iter()
+                .filter_map(|id| tables.note(*id, org_id).cloned())
+                .collect())
+        }
+
+        async fn get_edek(&self, id: u32, org_id: u32) -> Result<Option<OnlyEdek>> {
+            Ok(self
+                .tables
+                .lock()
+                .unwrap()
+                .note(id, org_id)
+                .map(|note| OnlyEdek {
+                    edek: EncryptedString(note.edek.clone()),
+                }))
+        }
+
+        async fn put_edek(&self, id: u32, org_id: u32, edek: EncryptedString) -> Result<u32> {
+            let mut tables = self.tables.lock().unwrap();
+            Ok(match tables.note_mut(id, org_id) {
+                Some(note) => {
+                    note.edek = edek.0;
+                    1
+                }
+                None => 0,
+            })
+        }
+
+        async fn put_summary(&self, id: u32, org_id: u32, summary: EncryptedString) -> Result<()> {
+            if let Some(note) = self.tables.lock().unwrap().note_mut(id, org_id) {
+                note.enc_summary = Some(summary);
+            }
+            Ok(())
+        }
+
+        async fn get_index_fields(&self, id: u32, org_id: u32) -> Result<NoteIndexFields> {
+            let tables = self.tables.lock().unwrap();
+            let note = tables
+                .note(id, org_id)
+                .ok_or_else(|| anyhow!("Note {id} doesn't exist"))?;
+            Ok(NoteIndexFields {
+                category: note.category.clone(),
+                created: note.created.clone(),
+                updated: note.updated.clone(),
+            })
+        }
+
+        async fn get_content_fingerprint(&self, id: u32, org_id: u32) -> Result<Option<String>> {
+            let tables = self.tables.lock().unwrap();
+            Ok(tables
+                .note(id, org_id)
+                .and_then(|_| tables.fingerprints.get(&id).cloned()))
+        }
+
+        async fn put_content_fingerprint(
+            &self,
+            id: u32,
+            org_id: u32,
+            fingerprint: String,
+        ) -> Result<()> {
+            let mut tables = self.tables.lock().unwrap();
+            if tables.note(id, org_id).is_some() {
+                tables.fingerprints.insert(id, fingerprint);
+            }
+            Ok(())
+        }
+
+        async fn list_categories(
+            &self,
+            org_id: u32,
+        ) -> Result<Vec<DeterministicallyEncryptedString>> {
+            let tables = self.tables.lock().unwrap();
+            let categories: BTreeSet<_> = tables
+                .notes
+                .values()
+                .filter(|note| note.org_id == org_id)
+                .filter_map(|note| note.category.as_ref().map(|category| category.0.clone()))
+                .collect();
+            Ok(categories
+                .into_iter()
+                .map(DeterministicallyEncryptedString)
+                .collect())
+        }
+    }
+
+    #[async_trait]
+    impl AttachmentRepository for InMemoryRepository {
+        async fn create(
+            &self,
+            org_id: u32,
+            filename: String,
+            content_type: &str,
+            size: i64,
+        ) -> Result<AttachmentTable> {
+            let mut tables = self.tables.lock().unwrap();
+            let id = tables.next_id();
+            let row = AttachmentTable {
+                id,
+                note_id: None,
+                filename,
+                created: current_timestamp(),
+                org_id: Some(org_id.into()),
+                content_type: Some(content_type.to_string()),
+                size: Some(size),
+                confirmed: false,
+            };
+            tables.attachments.insert(id, row.clone());
+            Ok(row)
+        }
+
+        async fn get(&self, id: u32, org_id: u32) -> Result<Option<AttachmentTable>> {
+            Ok(self
+                .tables
+                .lock()
+                .unwrap()
+                .attachments
+                .get(&id)
+                .filter(|attachment| attachment.org_id == Some(org_id.into()))
+                .cloned())
+        }
+
+        async fn list_for_note(&self, note_id: u32) -> Result<Vec<AttachmentTable>> {
+            Ok(self
+                .tables
+                .lock()
+                .unwrap()
+                .attachments
+                .values()
+                .filter(|attachment| attachment.note_id == Some(note_id.into()))
+                .cloned()
+                .collect())
+        }
+
+        async fn confirm(&self, id: u32, size: i64) -> Result<()> {
+            if let Some(attachment) = self.tables.lock().unwrap().attachments.get_mut(&id) {
+                attachment.confirmed = true;
+                attachment.size = Some(size);
+            }
+            Ok(())
+        }
+
+        async fn delete(&self, id: u32) -> Result<()> {
+            self.tables.lock().unwrap().attachments.remove(&id);
+            Ok(())
+        }
+
+        async fn get_policy(&self, _org_id: u32) -> Result<AttachmentPolicy> {
+            Ok(AttachmentPolicy::default())
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use elasticsearch::{
+    http::StatusCode, Elasticsearch, GetParts, IndexParts, SearchParts, UpdateParts,
+};
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::{
+    embeddings::Passage,
+    search_service::{PassageHit, PASSAGES_PATH},
+};
+
+/// One page of hits from a search, best first, with how many documents matched in total.
+#[derive(Debug, Clone, Default)]
+pub struct SearchResults {
+    pub total: u64,
+    pub hits: Vec<PassageHit>,
+}
+
+/// The index of notes' search documents. Implemented by Elasticsearch behind Cloaked Search, and in tests by an
+/// in-memory index that understands the queries `search_service` builds.
+#[async_trait]
+pub trait SearchIndex: Send + Sync {
+    /// Adds a document, replacing any with the same ID.
+    async fn index(&self, id: u32, document: Value) -> Result<()>;
+
+    /// Overwrites some of a document's fields, leaving the rest as they are. Returns `false` if there's no document
+    /// with that ID.
+    async fn update(&self, id: u32, fields: Value) -> Result<bool>;
+
+    /// The given fields of a document, or `None` if there's no document with that ID.
+    async fn get(&self, id: u32, fields: &[&str]) -> Result<Option<Value>>;
+
+    /// Runs a search written in the Elasticsearch query DSL.
+    async fn search(&self, query: Value) -> Result<SearchResults>;
+}
+
+/// An index in Elasticsearch.
+pub struct ElasticsearchIndex {
+    client: Elasticsearch,
+    index: String,
+}
+
+impl ElasticsearchIndex {
+    pub fn new(client: Elasticsearch, index: &str) -> ElasticsearchIndex {
+        ElasticsearchIndex {
+            client,
+            index: index.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryResponse {
+    hits: HitsObject,
+}
+impl QueryResponse {
+    fn into_results(self) -> SearchResults {
+        let hits = self
+            .hits
+            .hits
+            .into_iter()
+            .flat_map(|hit| {
+                let note_id = hit.id.as_str().and_then(|u| u.parse::<u32>().ok())?;
+                let passage = hit
+                    .inner_hits
+                    .and_then(|mut inner_hits| inner_hits.remove(PASSAGES_PATH))
+                    .and_then(|passages| passages.hits.hits.into_iter().next())
+                    .and_then(|passage| passage.get_passage());
+                Some(PassageHit {
+                    note_id,
+                    passage,
+                    score: hit.score.unwrap_or_default(),
+                })
+            })
+            .collect_vec();
+        SearchResults {
+            total: self
+                .hits
+                .total
+                .map_or(hits.len() as u64, |total| total.value),
+            hits,
+        }
+    }
+}
+#[derive(Debug, Deserialize)]
+struct HitsObject {
+    total: Option<TotalHits>,
+    hits: Vec<NoteId>,
+}
+#[derive(Debug, Deserialize)]
+struct TotalHits {
+    value: u64,
+}
+#[derive(Debug, Deserialize)]
+struct NoteId {
+    #[serde(rename = "_id")]
+    id: Value,
+    #[serde(rename = "_score")]
+    score: Option<f32>,
+    inner_hits: Option<HashMap<String, InnerHitsObject>>,
+}
+#[derive(Debug, Deserialize)]
+struct InnerHitsObject {
+    hits: InnerHitsHits,
+}
+#[derive(Debug, Deserialize)]
+struct InnerHitsHits {
+    hits: Vec<PassageFields>,
+}
+#[derive(Debug, Deserialize)]
+struct PassageFields {
+    fields: HashMap<String, Vec<usize>>,
+}
+impl PassageFields {
+    fn get_passage(mut self) -> Option<Passage> {
+        let start = self.fields.remove(&format!("{PASSAGES_PATH}.start"))?;
+        let end = self.fields.remove(&format!("{PASSAGES_PATH}.end"))?;
+        Some(Passage {
+            start: *start.first()?,
+            end: *end.first()?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GetResponse {
+    #[serde(rename = "_source")]
+    source: Option<Value>,
+}
+
+#[async_trait]
+impl SearchIndex for ElasticsearchIndex {
+    async fn index(&self, id: u32, document: Value) -> Result<()> {
+        self.client
+            .index(IndexParts::IndexId(&self.index, &id.to_string()))
+            .body(document)
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+
+    async fn update(&self, id: u32, fields: Value) -> Result<bool> {
+        let response = self
+            .client
+            .update(UpdateParts::IndexId(&self.index, &id.to_string()))
+            .body(json!({ "doc": fields }))
+            .send()
+            .await?;
+        if response.status_code() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status_code()?;
+        Ok(true)
+    }
+
+    async fn get(&self, id: u32, fields: &[&str]) -> Result<Option<Value>> {
+        let response = self
+            .client
+            .get(GetParts::IndexId(&self.index, &id.to_string()))
+            ._source_includes(fields)
+            .send()
+            .await?;
+        if response.status_code() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(response
+            .error_for_status_code()?
+            .json::<GetResponse>()
+            .await?
+            .source)
+    }
+
+    async fn search(&self, query: Value) -> Result<SearchResults> {
+        Ok(self
+            .client
+            .search(SearchParts::Index(&[&self.index]))
+            .body(query)
+            .send()
+            .await?
+            .error_for_status_code()?
+            .json::<QueryResponse>()
+            .await?
+            .into_results())
+    }
+}
+
+#[cfg(test)]
+pub use memory::InMemorySearchIndex;
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    /// Documents kept in memory, searched by interpreting the subset of the query DSL that `search_service`
+    /// produces: `term`, `range`, `ids`, and `bool` filters, `match` on text fields scored by how many of the
+    /// query's words appear, and `knn` (optionally `nested` in the passages or attachment passages, and with its own `filter`) scored by
+    /// cosine similarity the way Elasticsearch does.
+    #[derive(Debug, Default)]
+    pub struct InMemorySearchIndex {
+        documents: Mutex<BTreeMap<u32, Value>>,
+    }
+
+    impl InMemorySearchIndex {
+        /// Drops a document, like one lost when the index was recreated.
+        pub fn delete(&self, id: u32) {
+            self.documents.lock().unwrap().remove(&id);
+        }
+    }
+
+    struct Hit {
+        id: u32,
+        score: f32,
+        passage: Option<Passage>,
+    }
+
+    fn words(text: &str) -> Vec<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    }
+
+    fn vector(value: &Value) -> Option<Vec<f32>> {
+        value
+            .as_array()?
+            .iter()
+            .map(|x| x.as_f64().map(|x| x as f32))
+            .collect()
+    }
+
+    fn cosine(a: &[f32], b: &[f32]) -> f32 {
+        let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        dot / (norm(a) * norm(b)).max(f32::EPSILON)
+    }
+
+    /// Dates are compared as text, which works for SQLite's timestamps once ISO 8601's `T` is swapped out.
+    fn date(value: &Value) -> Option<String> {
+        value.as_str().map(|date| date.replace('T', " "))
+    }
+
+    fn matches_filter(filter: &Value, id: u32, document: &Value) -> bool {
+        if let Some(term) = filter.get("term").and_then(Value::as_object) {
+            term.iter().all(|(field, value)| {
+                document.get(field.trim_end_matches(".keyword")) == Some(value)
+            })
+        } else if let Some(range) = filter.get("range").and_then(Value::as_object) {
+            range.iter().all(|(field, bounds)| {
+                let Some(value) = document.get(field).and_then(date) else {
+                    return false;
+                };
+                bounds
+                    .get("gte")
+                    .and_then(date)
+                    .is_none_or(|gte| value >= gte)
+                    && bounds
+                        .get("lte")
+                        .and_then(date)
+                        .is_none_or(|lte| value <= lte)
+            })
+        } else if let Some(ids) = filter.pointer("/ids/values").and_then(Value::as_array) {
+            ids.iter()
+                .any(|value| value.as_str() == Some(&id.to_string()))
+        } else if let Some(bool) = filter.get("bool") {
+            let clauses = |name: &str| bool.get(name).and_then(Value::as_array).cloned();
+            clauses("filter")
+                .unwrap_or_default()
+                .iter()
+                .all(|clause| matches_filter(clause, id, document))
+                && !clauses("must_not")
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|clause| matches_filter(clause, id, document))
+        } else {
+            false
+        }
+    }
+
+    /// Whether a document, or a nested document of the one with this ID, matches all of a kNN search's `filter`.
+    fn matches_knn_filter(knn: &Value, id: u32, document: &Value) -> bool {
+        knn.get("filter")
+            .and_then(Value::as_array)
+            .is_none_or(|filter| {
+                filter
+                    .iter()
+                    .all(|clause| matches_filter(clause, id, document))
+            })
+    }
+
+    /// The `k` documents closest to the query, given how close each one is out of what matches the `filter`.
+    fn knn_hits(
+        knn: &Value,
+        candidates: &[(u32, &Value)],
+        closest: impl Fn(u32, &Value, &[f32]) -> Option<(f32, Option<Passage>)>,
+    ) -> Vec<Hit> {
+        let Some(query_vector) = knn.get("query_vector").and_then(vector) else {
+            return vec![];
+        };
+        let k = knn.get("k").and_then(Value::as_u64).unwrap_or(10) as usize;
+        let boost = knn.get("boost").and_then(Value::as_f64).unwrap_or(1.0) as f32;
+        let min_similarity = knn.get("similarity").and_then(Value::as_f64);
+        candidates
+            .iter()
+            .filter_map(|(id, document)| {
+                let (similarity, passage) = closest(*id, document, &query_vector)?;
+                Some((*id, similarity, passage))
+            })
+            .filter(|(_, similarity, _)| min_similarity.is_none_or(|min| *similarity as f64 >= min))
+            .sorted_by(|(_, a, _), (_, b, _)| b.total_cmp(a))
+            .take(k)
+            .map(|(id, similarity, passage)| Hit {
+                id,
+                score: boost * (1.0 + similarity) / 2.0,
+                passage,
+            })
+            .collect()
+    }
+
+    fn clause_hits(clause: &Value, candidates: &[(u32, &Value)]) -> Vec<Hit> {
+        if let Some(fields) = clause.get("match").and_then(Value::as_object) {
+            candidates
+                .iter()
+                .filter_map(|(id, document)| {
+                    let score = fields
+                        .iter()
+                        .map(|(field, query)| {
+                            let text = words(document.get(field)?.as_str()?);
+                            let matched = words(query.as_str()?)
+                                .iter()
+                                .filter(|word| text.contains(word))
+                                .count();
+                            Some(matched as f32)
+                        })
+                        .map(Option::unwrap_or_default)
+                        .sum::<f32>();
+                    (score > 0.0).then_some(Hit {
+                        id: *id,
+                        score,
+                        passage: None,
+                    })
+                })
+                .collect()
+        } else if let Some(knn) = clause.get("knn") {
+            let field = knn.get("field").and_then(Value::as_str).unwrap_or_default();
+            knn_hits(knn, candidates, |id, document, query_vector| {
+                if !matches_knn_filter(knn, id, document) {
+                    return None;
+                }
+                let similarity = cosine(&vector(document.get(field)?)?, query_vector);
+                Some((similarity, None))
+            })
+        } else if let Some(knn) = clause.pointer("/nested/query/knn") {
+            let path = clause
+                .pointer("/nested/path")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let field = knn.get("field").and_then(Value::as_str).unwrap_or_default();
+            let field = field.strip_prefix(&format!("{path}.")).unwrap_or(field);
+            // like in Elasticsearch, a nested kNN's filter is on the nested documents' own fields
+            knn_hits(knn, candidates, |id, document, query_vector| {
+                document
+                    .get(path)?
+                    .as_array()?
+                    .iter()
+                    .filter(|passage| matches_knn_filter(knn, id, passage))
+                    .filter_map(|passage| {
+                        let similarity = cosine(&vector(passage.get(field)?)?, query_vector);
+                        let location = (path == PASSAGES_PATH).then(|| Passage {
+                            start: passage["start"].as_u64().unwrap_or_default() as usize,
+                            end: passage["end"].as_u64().unwrap_or_default() as usize,
+                        });
+                        Some((similarity, location))
+                    })
+                    .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            })
+        } else {
+            vec![]
+        }
+    }
+
+    fn bool_hits(query: &Value, candidates: Vec<(u32, &Value)>) -> Vec<Hit> {
+        let bool = &query["bool"];
+        let clauses = |name: &str| {
+            bool.get(name)
+                .and_then(Value::as_array)
+                .cloned()
+                .unwrap_or_default()
+        };
+        let (filter, must_not, should) =
+            (clauses("filter"), clauses("must_not"), clauses("should"));
+        let documents = candidates.iter().copied().collect::<BTreeMap<_, _>>();
+        let hits = if let Some(must) = bool.get("must") {
+            bool_hits(must, candidates)
+        } else if should.is_empty() {
+            candidates
+                .into_iter()
+                .map(|(id, _)| Hit {
+                    id,
+                    score: 0.0,
+                    passage: None,
+                })
+                .collect()
+        } else {
+            // a bool with only `should` clauses matches documents that match any of them, scored by the sum
+            let mut hits: BTreeMap<u32, Hit> = BTreeMap::new();
+            for hit in should
+                .iter()
+                .flat_map(|clause| clause_hits(clause, &candidates))
+            {
+                let merged = hits.entry(hit.id).or_insert(Hit {
+                    id: hit.id,
+                    score: 0.0,
+                    passage: None,
+                });
+                merged.score += hit.score;
+                merged.passage = merged.passage.or(hit.passage);
+            }
+            hits.into_values().collect()
+        };
+        // like in Elasticsearch, filters only remove what the other clauses matched, so a `knn` clause's `k` nearest
+        // are picked before they're filtered
+        hits.into_iter()
+            .filter(|hit| {
+                let document = documents[&hit.id];
+                filter
+                    .iter()
+                    .all(|clause| matches_filter(clause, hit.id, document))
+                    && !must_not
+                        .iter()
+                        .any(|clause| matches_filter(clause, hit.id, document))
+            })
+            .collect()
+    }
+
+    #[async_trait]
+    impl SearchIndex for InMemorySearchIndex {
+        async fn index(&self, id: u32, document: Value) -> Result<()> {
+            self.documents.lock().unwrap().insert(id, document);
+            Ok(())
+        }
+
+        async fn update(&self, id: u32, fields: Value) -> Result<bool> {
+            let mut documents = self.documents.lock().unwrap();
+            let Some(document) = documents.get_mut(&id).and_then(Value::as_object_mut) else {
+                return Ok(false);
+            };
+            if let Value::Object(fields) = fields {
+                document.extend(fields);
+            }
+            Ok(true)
+        }
+
+        async fn get(&self, id: u32, fields: &[&str]) -> Result<Option<Value>> {
+            Ok(self.documents.lock().unwrap().get(&id).map(|document| {
+                fields
+                    .iter()
+                    .filter_map(|field| Some((field.to_string(), document.get(*field)?.clone())))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into()
+            }))
+        }
+
+        async fn search(&self, query: Value) -> Result<SearchResults> {
+            let documents = self.documents.lock().unwrap();
+            let candidates = documents
+                .iter()
+                .map(|(id, document)| (*id, document))
+                .collect_vec();
+            let hits = bool_hits(&query["query"], candidates)
+                .into_iter()
+                .sorted_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)))
+                .collect_vec();
+            let from = query["from"].as_u64().unwrap_or(0) as usize;
+            let size = query["size"].as_u64().unwrap_or(10) as usize;
+            Ok(SearchResults {
+                total: hits.len() as u64,
+                hits: hits
+                    .into_iter()
+                    .skip(from)
+                    .take(size)
+                    .map(|hit| PassageHit {
+                        note_id: hit.id,
+                        passage: hit.passage,
+                        score: hit.score,
+                    })
+                    .collect(),
+            })
+        }
+    }
+}
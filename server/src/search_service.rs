@@ -1,14 +1,33 @@
 use crate::{
-    embeddings::EncryptedEmbeddings,
-    notes::{CreateNoteRequest, UpdateNoteRequest},
-    CurrentOrganization, INDEX_NAME,
+    db::{DeterministicallyEncryptedString, NoteIndexFields},
+    embeddings::{EncryptedEmbeddings, KnnOptions, Passage},
+    notes::CreateNoteRequest,
+    search_index::{SearchIndex, SearchResults},
+    CurrentOrganization,
 };
 use anyhow::Result;
-use elasticsearch::{Elasticsearch, IndexParts, SearchParts};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::json;
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+/// Nested documents holding the embedding of each passage of a note's body.
+pub const PASSAGES_PATH: &str = "passages";
+pub const PASSAGE_VECTOR_FIELD: &str = "passages.vector";
+/// The embeddings of each passage of a note's attachments' text, in nested documents of their own.
+pub const ATTACHMENT_PASSAGE_VECTOR_FIELD: &str = "attachment_passages.vector";
+/// Damps the difference between the top few ranks when fusing result lists. 60 is the value from the original
+/// reciprocal rank fusion paper and what Elasticsearch uses by default.
+const RRF_RANK_CONSTANT: f32 = 60.0;
+/// Elasticsearch's default `index.max_result_window`, the furthest into the results `from + size` can reach.
+pub const MAX_RESULT_WINDOW: u32 = 10_000;
+/// The most candidates a kNN search can consider per shard.
+pub const MAX_NUM_CANDIDATES: u32 = 10_000;
 
 #[derive(Debug, Serialize)]
 pub struct SearchServiceNote {
@@ -17,36 +36,204 @@ pub struct SearchServiceNote {
     pub body: String,
     pub title_vector: Vec<f32>,
     pub body_vector: Vec<f32>,
+    /// The IDs of the attachments `attachment_text` was extracted from, in order.
+    pub attachment_ids: Vec<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment_text: Option<String>,
+    pub passages: Vec<SearchServicePassage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachment_passages: Vec<SearchServiceAttachmentPassage>,
+    /// The deterministically encrypted category, so it can be filtered on without being decrypted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<DeterministicallyEncryptedString>,
+    pub created: String,
+    pub updated: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct QueryResponse {
-    hits: HitsObject,
+/// Only the passage's location in the body is stored, the text itself is read from the decrypted note.
+#[derive(Debug, Serialize)]
+pub struct SearchServicePassage {
+    pub start: usize,
+    pub end: usize,
+    pub vector: Vec<f32>,
 }
-impl QueryResponse {
-    fn get_ids(self) -> Result<Vec<u32>> {
-        Ok(self
-            .hits
-            .hits
+
+/// Attachments' text isn't kept anywhere it could be read back from, so only the vector is stored.
+#[derive(Debug, Serialize)]
+pub struct SearchServiceAttachmentPassage {
+    pub vector: Vec<f32>,
+}
+
+/// One page of search results, best first, with how many notes matched in total.
+#[derive(Debug, Clone, Default)]
+pub struct ScoredHits {
+    pub total: u64,
+    pub hits: Vec<(u32, f32)>,
+}
+
+/// Which page of results to return.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Page {
+    pub from: u32,
+    pub size: u32,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page { from: 0, size: 10 }
+    }
+}
+
+impl Page {
+    /// How many results it takes to fill everything up to the end of the page.
+    pub fn end(&self) -> Result<u32, InvalidSearch> {
+        self.from
+            .checked_add(self.size)
+            .filter(|end| *end <= MAX_RESULT_WINDOW)
+            .ok_or(InvalidSearch::PageTooDeep)
+    }
+}
+
+/// Search options Elasticsearch would refuse. Reported back to the caller as a 400 with a message.
+#[derive(Debug)]
+pub enum InvalidSearch {
+    PageTooDeep,
+    TooManyCandidates,
+    KOutOfRange { max: u32 },
+}
+
+impl Display for InvalidSearch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidSearch::PageTooDeep => write!(
+                f,
+                "`from` and `size` can add up to at most {MAX_RESULT_WINDOW}."
+            ),
+            InvalidSearch::TooManyCandidates => {
+                write!(f, "`num_candidates` can be at most {MAX_NUM_CANDIDATES}.")
+            }
+            InvalidSearch::KOutOfRange { max } => {
+                write!(f, "`k` has to be between 1 and {max}.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidSearch {}
+
+impl IntoResponse for InvalidSearch {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": self.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+/// A note found by a search, along with the passage of its body that matched best if passages were searched.
+#[derive(Debug, Clone)]
+pub struct PassageHit {
+    pub note_id: u32,
+    pub passage: Option<Passage>,
+    pub score: f32,
+}
+
+/// Narrows a search down beyond the current organization.
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilters {
+    pub category: Option<DeterministicallyEncryptedString>,
+    pub created: Option<DateRange>,
+    pub updated: Option<DateRange>,
+    pub exclude_note_ids: Vec<u32>,
+}
+
+impl SearchFilters {
+    fn into_filters(self, org_id: String) -> Vec<Filter> {
+        let term = |field: &str, value: String| Filter::Term([(field.to_string(), value)].into());
+        let range =
+            |field: &str, range: DateRange| Filter::Range([(field.to_string(), range)].into());
+        [term("org_id.keyword", org_id)]
             .into_iter()
-            .flat_map(|hit| hit.id.as_str().and_then(|u| u.parse::<u32>().ok()))
-            .collect_vec())
+            .chain(self.category.map(|category| term("category", category.0)))
+            .chain(self.created.map(|created| range("created", created)))
+            .chain(self.updated.map(|updated| range("updated", updated)))
+            .collect()
+    }
+
+    /// The same filters for a kNN search's own `filter`, so they narrow down the notes the nearest `k` are picked
+    /// from. Filters around a kNN search only apply to the `k` it already found, which could leave nothing.
+    fn into_knn_filter(self, org_id: String) -> Vec<Filter> {
+        let exclusions = self.exclusions();
+        let mut filter = self.into_filters(org_id);
+        if !exclusions.is_empty() {
+            filter.push(Filter::Bool(Box::new(Bool {
+                filter: vec![],
+                must: None,
+                must_not: exclusions,
+                should: vec![],
+            })));
+        }
+        filter
+    }
+
+    fn exclusions(&self) -> Vec<Filter> {
+        if self.exclude_note_ids.is_empty() {
+            vec![]
+        } else {
+            vec![Filter::Ids(Ids {
+                values: self.exclude_note_ids.iter().map(u32::to_string).collect(),
+            })]
+        }
     }
 }
-#[derive(Debug, Deserialize)]
-struct HitsObject {
-    hits: Vec<NoteId>,
+
+/// An inclusive range of dates or timestamps, either end of which can be left open.
+/// Accepts `2024-01-31` or `2024-01-31 13:45:00` as well as the ISO 8601 formats Elasticsearch understands.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DateRange {
+    #[serde(rename(serialize = "gte"), skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(rename(serialize = "lte"), skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
 }
+
+/// The encrypted vectors a note was indexed with.
 #[derive(Debug, Deserialize)]
-struct NoteId {
-    #[serde(rename = "_id")]
-    id: Value,
+pub struct StoredNoteVectors {
+    org_id: String,
+    title_vector: Vec<f32>,
+    body_vector: Vec<f32>,
+}
+
+impl StoredNoteVectors {
+    /// A kNN query for notes similar to this one. The stored vectors are already encrypted for the organization,
+    /// so they can be searched with as they are.
+    pub fn related_query(self, options: KnnOptions) -> QueryType {
+        let knn = |field: &str, query_vector| Knn {
+            field: field.to_string(),
+            query_vector,
+            num_candidates: options.num_candidates,
+            k: options.k,
+            boost: options.boost,
+            similarity: options.similarity,
+            filter: vec![],
+        };
+        QueryType::Knn {
+            embeddings: vec![
+                knn("title_vector", self.title_vector),
+                knn("body_vector", self.body_vector),
+            ],
+        }
+    }
 }
 
 pub enum QueryType {
     Keyword {
         title: Option<String>,
         body: Option<String>,
+        attachment_text: Option<String>,
     },
     Knn {
         embeddings: Vec<Knn>,
@@ -54,34 +241,55 @@ pub enum QueryType {
 }
 
 impl QueryType {
-    pub fn make_query(self, org_id: String) -> OuterQuery {
+    pub fn make_query(self, org_id: String, filters: SearchFilters, page: Page) -> OuterQuery {
         let should = match self {
-            QueryType::Keyword { title, body } => title
+            QueryType::Keyword {
+                title,
+                body,
+                attachment_text,
+            } => title
                 .map(|title| ("title".to_string(), title))
                 .into_iter()
                 .chain(body.map(|body| ("body".to_string(), body)))
+                .chain(attachment_text.map(|text| ("attachment_text".to_string(), text)))
                 .map(|(key, value)| Should {
                     r#match: Some([(key, value)].into()),
                     knn: None,
+                    nested: None,
                 })
                 .collect_vec(),
             QueryType::Knn { embeddings } => embeddings
                 .into_iter()
-                .map(|knn| Should {
-                    r#match: None,
-                    knn: Some(knn),
+                .map(|knn| match knn.field.split_once('.') {
+                    // a nested kNN's filter is on the nested documents, which don't have the note's fields, so
+                    // the note's filters are left to the outer query
+                    Some((path, _)) => Should {
+                        r#match: None,
+                        knn: None,
+                        nested: Some(Nested::closest(path.to_string(), knn)),
+                    },
+                    None => Should {
+                        r#match: None,
+                        knn: Some(Knn {
+                            filter: filters.clone().into_knn_filter(org_id.clone()),
+                            ..knn
+                        }),
+                        nested: None,
+                    },
                 })
                 .collect_vec(),
         };
         OuterQuery {
+            from: page.from,
+            size: page.size,
             query: Query {
                 bool: Bool {
-                    filter: Some(Filter {
-                        term: Term { org_id },
-                    }),
+                    must_not: filters.exclusions(),
+                    filter: filters.into_filters(org_id),
                     must: Some(Box::new(Query {
                         bool: Bool {
-                            filter: None,
+                            must_not: vec![],
+                            filter: vec![],
                             must: None,
                             should,
                         },
@@ -96,69 +304,188 @@ impl QueryType {
 pub async fn index_note(
     note_id: u32,
     request: CreateNoteRequest,
+    attachment_text: Option<String>,
+    index_fields: NoteIndexFields,
     organization: &CurrentOrganization,
-    search_client: Elasticsearch,
+    search_index: Arc<dyn SearchIndex>,
     embeddings: EncryptedEmbeddings,
 ) -> Result<()> {
+    let attachment_ids = sorted_attachment_ids(&request);
     let search_service_note = SearchServiceNote {
         org_id: organization.0.login.clone(),
         title: request.title,
         body: request.body,
         title_vector: embeddings.enc_title.encrypted_vector,
         body_vector: embeddings.enc_body.encrypted_vector,
+        attachment_ids,
+        attachment_text,
+        attachment_passages: embeddings
+            .enc_attachment_passages
+            .into_iter()
+            .map(|vector| SearchServiceAttachmentPassage {
+                vector: vector.encrypted_vector,
+            })
+            .collect(),
+        passages: embeddings
+            .enc_passages
+            .into_iter()
+            .map(|(passage, vector)| SearchServicePassage {
+                start: passage.start,
+                end: passage.end,
+                vector: vector.encrypted_vector,
+            })
+            .collect(),
+        category: index_fields.category,
+        created: index_fields.created,
+        updated: index_fields.updated,
     };
-    let note_id_str = note_id.to_string();
-    search_client
-        .index(IndexParts::IndexId(INDEX_NAME, &note_id_str))
-        .body(search_service_note)
-        .send()
-        .await?
-        .error_for_status_code()?;
-    Ok(())
+    search_index
+        .index(note_id, serde_json::to_value(search_service_note)?)
+        .await
 }
 
-/// Deletes the existing note then inserts the new one with the same ID.
-/// This doesn't do any checking that the provided ID is in the given org,
-/// so this is intended to be called after the database function which does check.
-pub async fn update_note(
+/// Updates just the filterable fields of an indexed note, for edits that didn't change what it was embedded from.
+/// Returns `false` if the note isn't in the index, in which case it needs to be indexed in full.
+pub async fn update_note_fields(
     note_id: u32,
-    request: UpdateNoteRequest,
+    index_fields: NoteIndexFields,
+    search_index: Arc<dyn SearchIndex>,
+) -> Result<bool> {
+    search_index
+        .update(
+            note_id,
+            json!({
+                "category": index_fields.category,
+                "created": index_fields.created,
+                "updated": index_fields.updated,
+            }),
+        )
+        .await
+}
+
+async fn run_query(
     organization: &CurrentOrganization,
-    search_client: Elasticsearch,
-    embeddings: EncryptedEmbeddings,
-) -> Result<()> {
-    let note_id_str = note_id.to_string();
-    search_client
-        .delete(elasticsearch::DeleteParts::IndexId(
-            INDEX_NAME,
-            &note_id_str,
-        ))
-        .send()
+    search_index: Arc<dyn SearchIndex>,
+    query_type: QueryType,
+    filters: SearchFilters,
+    page: Page,
+) -> Result<SearchResults> {
+    let query = query_type.make_query(organization.0.login.clone(), filters, page);
+    search_index.search(serde_json::to_value(query)?).await
+}
+
+fn sorted_attachment_ids(note: &CreateNoteRequest) -> Vec<u32> {
+    note.attachments.iter().copied().sorted().dedup().collect()
+}
+
+/// What a note was last indexed with from its attachments.
+#[derive(Debug, Deserialize)]
+pub struct IndexedAttachments {
+    org_id: String,
+    #[serde(default)]
+    attachment_ids: Vec<u32>,
+    pub attachment_text: Option<String>,
+}
+
+impl IndexedAttachments {
+    /// Whether the text was extracted from the same attachments the note has now.
+    pub fn are_of(&self, note: &CreateNoteRequest) -> bool {
+        self.attachment_ids == sorted_attachment_ids(note)
+    }
+}
+
+/// Looks up the attachment text a note was indexed with, so it doesn't have to be extracted again if the note's
+/// attachments haven't changed. Returns `None` if the note isn't in the index for this organization.
+pub async fn get_indexed_attachments(
+    organization: &CurrentOrganization,
+    search_index: Arc<dyn SearchIndex>,
+    note_id: u32,
+) -> Result<Option<IndexedAttachments>> {
+    let Some(source) = search_index
+        .get(note_id, &["org_id", "attachment_ids", "attachment_text"])
         .await?
-        .error_for_status_code()?;
-    index_note(note_id, request, organization, search_client, embeddings).await?;
-    Ok(())
+    else {
+        return Ok(None);
+    };
+    let indexed: IndexedAttachments = serde_json::from_value(source)?;
+    Ok(Some(indexed).filter(|indexed| indexed.org_id == organization.0.login))
+}
+
+/// Looks up the vectors a note was indexed with. Returns `None` if the note isn't in the index for this organization.
+pub async fn get_note_vectors(
+    organization: &CurrentOrganization,
+    search_index: Arc<dyn SearchIndex>,
+    note_id: u32,
+) -> Result<Option<StoredNoteVectors>> {
+    let Some(source) = search_index
+        .get(note_id, &["org_id", "title_vector", "body_vector"])
+        .await?
+    else {
+        return Ok(None);
+    };
+    let vectors: StoredNoteVectors = serde_json::from_value(source)?;
+    Ok(Some(vectors).filter(|vectors| vectors.org_id == organization.0.login))
 }
 
+/// Returns the IDs of a page of matching notes with their relevance scores.
 pub async fn query_notes(
     organization: &CurrentOrganization,
-    search_client: Elasticsearch,
+    search_index: Arc<dyn SearchIndex>,
     query_type: QueryType,
-) -> Result<Vec<u32>> {
-    let query = query_type.make_query(organization.0.login.clone());
-    let search_res = search_client
-        .search(SearchParts::Index(&[INDEX_NAME]))
-        .body(query)
-        .send()
-        .await?
-        .error_for_status_code()?
-        .json::<QueryResponse>()
-        .await?;
-    search_res.get_ids()
+    filters: SearchFilters,
+    page: Page,
+) -> Result<ScoredHits> {
+    let results = run_query(organization, search_index, query_type, filters, page).await?;
+    Ok(ScoredHits {
+        total: results.total,
+        hits: results
+            .hits
+            .into_iter()
+            .map(|hit| (hit.note_id, hit.score))
+            .collect(),
+    })
+}
+
+/// Like `query_notes`, but also returns which passage of each note matched a passage search.
+pub async fn query_note_passages(
+    organization: &CurrentOrganization,
+    search_index: Arc<dyn SearchIndex>,
+    query_type: QueryType,
+    filters: SearchFilters,
+    page: Page,
+) -> Result<Vec<PassageHit>> {
+    Ok(
+        run_query(organization, search_index, query_type, filters, page)
+            .await?
+            .hits,
+    )
+}
+
+/// Merges ranked lists of note IDs with weighted reciprocal rank fusion. Each list adds `weight / (60 + rank)`
+/// to the score of every note in it, so notes ranked well by several lists rise to the top. Returns the fused
+/// scores, best first.
+pub fn reciprocal_rank_fusion(ranked_lists: &[(f32, Vec<u32>)]) -> Vec<(u32, f32)> {
+    let mut scores: HashMap<u32, f32> = HashMap::new();
+    for (weight, ids) in ranked_lists {
+        for (rank, id) in ids.iter().enumerate() {
+            *scores.entry(*id).or_default() += weight / (RRF_RANK_CONSTANT + rank as f32 + 1.0);
+        }
+    }
+    // ties keep the order the IDs were first seen in, which favors the earlier lists
+    ranked_lists
+        .iter()
+        .flat_map(|(_, ids)| ids.iter().copied())
+        .unique()
+        .map(|id| (id, scores[&id]))
+        .filter(|(_, score)| *score > 0.0)
+        .sorted_by(|(_, a), (_, b)| b.total_cmp(a))
+        .collect()
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct OuterQuery {
+    from: u32,
+    size: u32,
     query: Query,
 }
 #[derive(Clone, Debug, Serialize)]
@@ -167,21 +494,26 @@ pub struct Query {
 }
 #[derive(Clone, Debug, Serialize)]
 pub struct Bool {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    filter: Option<Filter>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    filter: Vec<Filter>,
     #[serde(skip_serializing_if = "Option::is_none")]
     must: Option<Box<Query>>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
+    must_not: Vec<Filter>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     should: Vec<Should>,
 }
 #[derive(Clone, Debug, Serialize)]
-pub struct Filter {
-    term: Term,
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Term(HashMap<String, String>),
+    Range(HashMap<String, DateRange>),
+    Ids(Ids),
+    Bool(Box<Bool>),
 }
 #[derive(Clone, Debug, Serialize)]
-pub struct Term {
-    #[serde(rename = "org_id.keyword")]
-    org_id: String,
+pub struct Ids {
+    values: Vec<String>,
 }
 #[derive(Clone, Debug, Serialize)]
 pub struct Should {
@@ -189,7 +521,48 @@ pub struct Should {
     r#match: Option<HashMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     knn: Option<Knn>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nested: Option<Nested>,
+}
+#[derive(Clone, Debug, Serialize)]
+pub struct Nested {
+    path: String,
+    query: NestedQuery,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inner_hits: Option<InnerHits>,
+}
+impl Nested {
+    /// Searches the nested documents at `path` of each note. For the body's passages, the location of the closest
+    /// one is returned too.
+    fn closest(path: String, knn: Knn) -> Nested {
+        let inner_hits = (path == PASSAGES_PATH).then(|| InnerHits {
+            size: 1,
+            source: false,
+            docvalue_fields: vec![
+                format!("{PASSAGES_PATH}.start"),
+                format!("{PASSAGES_PATH}.end"),
+            ],
+        });
+        Nested {
+            path,
+            query: NestedQuery { knn },
+            inner_hits,
+        }
+    }
+}
+#[derive(Clone, Debug, Serialize)]
+pub struct NestedQuery {
+    knn: Knn,
+}
+#[derive(Clone, Debug, Serialize)]
+pub struct InnerHits {
+    size: u32,
+    #[serde(rename = "_source")]
+    source: bool,
+    docvalue_fields: Vec<String>,
 }
+/// A kNN search of a vector field. If the field is inside nested documents, like the passages, it's run as a nested
+/// query.
 #[derive(Clone, Debug, Serialize)]
 pub struct Knn {
     pub field: String,
@@ -197,4 +570,101 @@ pub struct Knn {
     pub num_candidates: u32,
     pub k: u32,
     pub boost: f32,
+    /// Least similar a vector can be and still match. Cosine similarity, so from -1 to 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f32>,
+    /// Only notes matching all of these are considered. Filled in from the search's filters when the query is made,
+    /// except for nested fields.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filter: Vec<Filter>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search_index::InMemorySearchIndex;
+
+    fn knn(field: &str, query_vector: Vec<f32>) -> Knn {
+        Knn {
+            field: field.to_string(),
+            query_vector,
+            num_candidates: 10,
+            k: 10,
+            boost: 1.0,
+            similarity: None,
+            filter: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn filtered_passage_searches_find_notes() {
+        let index = InMemorySearchIndex::default();
+        for (id, category, vector) in [(1, "work", [1.0, 0.0]), (2, "home", [1.0, 0.1])] {
+            let document = json!({
+                "org_id": "acme",
+                "category": category,
+                "created": "2024-05-01 12:00:00",
+                "updated": "2024-05-01 12:00:00",
+                "passages": [{ "start": 0, "end": 4, "vector": vector }],
+            });
+            index.index(id, document).await.unwrap();
+        }
+        let filters = SearchFilters {
+            category: Some(DeterministicallyEncryptedString("work".to_string())),
+            created: Some(DateRange {
+                from: Some("2024-01-01".to_string()),
+                to: None,
+            }),
+            ..SearchFilters::default()
+        };
+        let query = QueryType::Knn {
+            embeddings: vec![knn(PASSAGE_VECTOR_FIELD, vec![1.0, 0.0])],
+        }
+        .make_query("acme".to_string(), filters, Page::default());
+
+        let results = index
+            .search(serde_json::to_value(query).unwrap())
+            .await
+            .unwrap();
+        let hits = results
+            .hits
+            .iter()
+            .map(|hit| (hit.note_id, hit.passage))
+            .collect_vec();
+        assert_eq!(hits, [(1, Some(Passage { start: 0, end: 4 }))]);
+    }
+
+    fn fused_ids(ranked_lists: &[(f32, Vec<u32>)]) -> Vec<u32> {
+        reciprocal_rank_fusion(ranked_lists)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    #[test]
+    fn fusion_favors_notes_ranked_by_several_lists() {
+        assert_eq!(
+            fused_ids(&[(1.0, vec![1, 2]), (1.0, vec![3, 2])]),
+            [2, 1, 3]
+        );
+    }
+
+    #[test]
+    fn fusion_ties_keep_the_order_notes_were_first_seen_in() {
+        let fused = reciprocal_rank_fusion(&[(1.0, vec![1, 2]), (1.0, vec![2, 1])]);
+        assert_eq!(fused[0].1, fused[1].1);
+        assert_eq!(fused.iter().map(|(id, _)| *id).collect_vec(), [1, 2]);
+        assert_eq!(fused_ids(&[(1.0, vec![4]), (1.0, vec![3])]), [4, 3]);
+        assert_eq!(fused_ids(&[(1.0, vec![3]), (1.0, vec![4])]), [3, 4]);
+    }
+
+    #[test]
+    fn fusion_weights_break_ties() {
+        assert_eq!(fused_ids(&[(1.0, vec![4]), (2.0, vec![3])]), [3, 4]);
+        assert_eq!(
+            fused_ids(&[(1.0, vec![1]), (0.0, vec![2])]),
+            [1],
+            "notes only in a list weighted 0 are left out"
+        );
+    }
 }